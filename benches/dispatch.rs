@@ -0,0 +1,91 @@
+//! Compares the reference interpreter with the threaded engine on loop- and call-heavy
+//! programs. Run with `cargo bench`.
+
+use kut::value::*;
+use kut::vm::*;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ROUNDS: u32 = 5;
+
+fn literals<'template>() -> Vec<KutValue<'template>> {
+    vec![KutValue::Integer(0), KutValue::Integer(1), KutValue::Integer(2), KutValue::String(std::rc::Rc::new("fib".to_owned()))]
+}
+
+fn templates() -> Vec<KutFunctionTemplate> {
+    use KutInstruction::*;
+    // Sums the integers below the argument with a counting loop.
+    let sum_loop = vec![
+        GetLiteralR { reg: 1, literal: 0 },
+        GetLiteralR { reg: 2, literal: 0 },
+        GetLiteralR { reg: 3, literal: 1 },
+        CompareLssR { reg: 4, lhs: 1, rhs: 0 },
+        JumpIfFalse { cond: 4, target: 8 },
+        AddNumbersR { reg: 2, lhs: 2, rhs: 1 },
+        AddNumbersR { reg: 1, lhs: 1, rhs: 3 },
+        JumpAlwaysT { target: 3 },
+        RetfMethodR { value: 2 },
+    ];
+    // Sums the integers below the argument over a range iterator.
+    let sum_range = vec![
+        GetLiteralR { reg: 2, literal: 0 },
+        GetLiteralR { reg: 3, literal: 1 },
+        IterRangeRg { reg: 1, start: 2, end: 0, step: 3 },
+        IterNextReg { reg: 4, iter: 1, target: 6 },
+        AddNumbersR { reg: 2, lhs: 2, rhs: 4 },
+        JumpAlwaysT { target: 3 },
+        RetfMethodR { value: 2 },
+    ];
+    // The naive recursive fibonacci, calling itself through the global `fib`.
+    let fib = vec![
+        GetLiteralR { reg: 1, literal: 2 },
+        CompareLssR { reg: 2, lhs: 0, rhs: 1 },
+        JumpIfFalse { cond: 2, target: 4 },
+        RetfMethodR { value: 0 },
+        GetGlobalRg { reg: 3, name: 3 },
+        GetLiteralR { reg: 6, literal: 1 },
+        SubNumbersR { reg: 4, lhs: 0, rhs: 6 },
+        PushValue1R { val1: 4 },
+        CallMethodR { ret_position: 5, arg_count: 1, subject: 3 },
+        SubNumbersR { reg: 4, lhs: 0, rhs: 1 },
+        PushValue1R { val1: 4 },
+        CallMethodR { ret_position: 4, arg_count: 1, subject: 3 },
+        AddNumbersR { reg: 5, lhs: 5, rhs: 4 },
+        RetfMethodR { value: 5 },
+    ];
+    let define_fib = vec![CaptureFunc { reg: 0, template: 2 }, SetGlobalRg { reg: 0, name: 3 }];
+    vec![
+        KutFunctionTemplate::new(sum_loop, vec![], 5).with_name("sum_loop"),
+        KutFunctionTemplate::new(sum_range, vec![], 5).with_name("sum_range"),
+        KutFunctionTemplate::new(fib, vec![], 7).with_name("fib"),
+        KutFunctionTemplate::new(define_fib, vec![], 1).with_name("define_fib"),
+    ]
+}
+
+/// Runs `name` with `arg` on a fresh VM using `engine`, returning the result and the
+/// best time of `ROUNDS` runs.
+fn measure(engine: KutEngine, name: &str, arg: i64) -> (i64, Duration) {
+    let mut vm = KutVm::new(literals(), templates());
+    vm.engine = engine;
+    let vm = &vm;
+    vm.call::<KutValue>("define_fib", ()).expect("fib is defined");
+    let mut result = 0;
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        result = vm.call(name, (black_box(arg),)).expect("benchmark runs");
+        best = best.min(start.elapsed());
+    }
+    (result, best)
+}
+
+fn main() {
+    println!("{:<10} {:>12} {:>12} {:>8}", "program", "reference", "threaded", "speedup");
+    for (name, arg) in [("sum_loop", 3_000_000), ("sum_range", 3_000_000), ("fib", 25)] {
+        let (expected, reference) = measure(KutEngine::Reference, name, arg);
+        let (result, threaded) = measure(KutEngine::Threaded, name, arg);
+        assert_eq!(expected, result, "{name} differs between the engines");
+        let speedup = reference.as_secs_f64() / threaded.as_secs_f64();
+        println!("{name:<10} {:>10.2}ms {:>10.2}ms {speedup:>7.2}x", reference.as_secs_f64() * 1000.0, threaded.as_secs_f64() * 1000.0);
+    }
+}
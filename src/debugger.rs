@@ -0,0 +1,409 @@
+use crate::hook::*;
+use crate::value::*;
+use crate::vm::*;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+/// Writes a line to the output of the debugger, which has nowhere to report a failed write.
+macro_rules! output {
+    ($debugger:expr, $($arg:tt)*) => {{
+        let _ = writeln!($debugger.output, $($arg)*);
+    }};
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KutWatch {
+    Register(u8),
+    Capture(u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum KutStepMode {
+    Into,
+    Over(usize),
+    Out(usize),
+    Continue,
+}
+
+/// Interactive command-line debugger, driven by the `KutHook` events. It reads its
+/// commands from stdin and writes to stdout, unless created with `with_io`.
+pub struct KutDebugger {
+    breakpoints: HashSet<(usize, usize)>,
+    watches: Vec<(KutWatch, usize, Option<String>)>,
+    frames: Vec<(Option<usize>, usize)>,
+    mode: KutStepMode,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+}
+
+impl fmt::Debug for KutDebugger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KutDebugger")
+            .field("breakpoints", &self.breakpoints)
+            .field("watches", &self.watches)
+            .field("frames", &self.frames)
+            .field("mode", &self.mode)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for KutDebugger {
+    fn default() -> Self {
+        KutDebugger::new()
+    }
+}
+
+impl KutDebugger {
+    pub fn new() -> KutDebugger {
+        KutDebugger::with_io(Box::new(io::stdin().lock()), Box::new(io::stdout()))
+    }
+
+    /// Creates a debugger that reads its commands from `input` and writes to `output`.
+    pub fn with_io(input: Box<dyn BufRead>, output: Box<dyn Write>) -> KutDebugger {
+        KutDebugger { breakpoints: HashSet::new(), watches: vec![], frames: vec![], mode: KutStepMode::Into, input, output }
+    }
+
+    pub fn add_breakpoint(&mut self, template: usize, offset: usize) {
+        self.breakpoints.insert((template, offset));
+    }
+
+    /// Watches a register or capture of the function at call depth `depth`,
+    /// where the outermost function has depth 1.
+    pub fn add_watch(&mut self, watch: KutWatch, depth: usize) {
+        if !self.watches.iter().any(|(w, d, _)| *w == watch && *d == depth) {
+            self.watches.push((watch, depth, None));
+        }
+    }
+
+    fn template_index(vm: &KutVm, frame: &KutFunction) -> Option<usize> {
+        vm.templates.iter().position(|template| std::ptr::eq(template, frame.closure.template))
+    }
+
+    fn format_template(template: Option<usize>) -> String {
+        match template {
+            Some(index) => format!("template {index}"),
+            None => "template ?".to_owned(),
+        }
+    }
+
+    fn format_value(value: &KutValue) -> String {
+        value.to_repr_string()
+    }
+
+    fn read_watch(frame: &KutFunction, watch: KutWatch) -> String {
+        let value = match watch {
+            KutWatch::Register(reg) => frame.registers.get(reg as usize),
+            KutWatch::Capture(cap) => frame.closure.captures.get(cap as usize),
+        };
+        value.map_or_else(|| "<out of range>".to_owned(), KutDebugger::format_value)
+    }
+
+    fn parse_watch(arg: &str) -> Option<KutWatch> {
+        if let Some(reg) = arg.strip_prefix('r') {
+            reg.parse().ok().map(KutWatch::Register)
+        } else if let Some(cap) = arg.strip_prefix('c') {
+            cap.parse().ok().map(KutWatch::Capture)
+        } else {
+            None
+        }
+    }
+
+    fn update_watches(&mut self, frame: &KutFunction) -> bool {
+        let mut changed = false;
+        let depth = self.frames.len();
+        for (watch, _, last) in self.watches.iter_mut().filter(|(_, d, _)| *d == depth) {
+            let current = KutDebugger::read_watch(frame, *watch);
+            if last.as_ref().is_some_and(|last| *last != current) {
+                output!(self, "watch {watch:?} changed: {} -> {current}", last.as_deref().unwrap_or(""));
+                changed = true;
+            }
+            *last = Some(current);
+        }
+        changed
+    }
+
+    /// Matches the frames to the `depth` frames the VM runs, since no event tells when
+    /// a function stops running because it failed or yielded. A function that runs
+    /// again without entering, like a resumed coroutine, gets its frame back on its
+    /// next instruction, and the frames between are unknown.
+    fn sync_frames(&mut self, depth: usize) {
+        self.frames.resize(depth, (None, 0));
+    }
+
+    fn should_stop(&self, location: (Option<usize>, usize)) -> bool {
+        let depth = self.frames.len();
+        let at_breakpoint = location.0.is_some_and(|template| self.breakpoints.contains(&(template, location.1)));
+        at_breakpoint || match self.mode {
+            KutStepMode::Into => true,
+            KutStepMode::Over(level) => depth <= level,
+            KutStepMode::Out(level) => depth < level,
+            KutStepMode::Continue => false,
+        }
+    }
+
+    fn print_help(&mut self) {
+        output!(self, "break <template> <offset>   stop before the instruction at offset in template");
+        output!(self, "delete <template> <offset>  remove a breakpoint");
+        output!(self, "step | s                    run one instruction, entering calls");
+        output!(self, "next | n                    run one instruction, stepping over calls");
+        output!(self, "finish | f                  run until the current function returns");
+        output!(self, "continue | c                run until a breakpoint or watch change");
+        output!(self, "regs | r                    print the registers of the current function");
+        output!(self, "caps                        print the captures of the current function");
+        output!(self, "globals | g                 print the defined global variables");
+        output!(self, "stack                       print the value stack of the current function");
+        output!(self, "backtrace | bt              print the active functions");
+        output!(self, "list | l                    print the instructions of the current function");
+        output!(self, "watch r<N> | c<N>           stop when a register or capture changes");
+        output!(self, "unwatch r<N> | c<N>         remove a watch");
+        output!(self, "abort | q                   abort the program");
+    }
+
+    fn prompt(&mut self, vm: &KutVm, frame: &KutFunction, pc: usize) -> Result<(), KutError> {
+        loop {
+            let _ = write!(self.output, "(kutdb) ");
+            let _ = self.output.flush();
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    self.mode = KutStepMode::Continue;
+                    return Ok(());
+                },
+                Ok(_) => {},
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => continue,
+                ["step" | "s"] => {
+                    self.mode = KutStepMode::Into;
+                    return Ok(());
+                },
+                ["next" | "n"] => {
+                    self.mode = KutStepMode::Over(self.frames.len());
+                    return Ok(());
+                },
+                ["finish" | "f"] => {
+                    self.mode = KutStepMode::Out(self.frames.len());
+                    return Ok(());
+                },
+                ["continue" | "c"] => {
+                    self.mode = KutStepMode::Continue;
+                    return Ok(());
+                },
+                ["abort" | "q"] => return Err(KutError::Aborted { reason: "debugger".to_owned() }),
+                ["break" | "b", template, offset] => match (template.parse(), offset.parse()) {
+                    (Ok(template), Ok(offset)) => self.add_breakpoint(template, offset),
+                    _ => output!(self, "expected a template index and an instruction offset"),
+                },
+                ["delete" | "d", template, offset] => match (template.parse(), offset.parse()) {
+                    (Ok(template), Ok(offset)) => {
+                        self.breakpoints.remove(&(template, offset));
+                    },
+                    _ => output!(self, "expected a template index and an instruction offset"),
+                },
+                ["regs" | "r"] => {
+                    for (reg, value) in frame.registers.iter().enumerate() {
+                        output!(self, "r{reg} = {}", KutDebugger::format_value(value));
+                    }
+                },
+                ["caps"] => {
+                    for (cap, value) in frame.closure.captures.iter().enumerate() {
+                        output!(self, "c{cap} = {}", KutDebugger::format_value(value));
+                    }
+                },
+                ["globals" | "g"] => {
+                    for (name, value) in vm.globals.borrow().iter() {
+                        output!(self, "{name} = {}", KutDebugger::format_value(value));
+                    }
+                },
+                ["stack"] => {
+                    for (index, value) in frame.call_stack.iter().enumerate().rev() {
+                        output!(self, "[{index}] {}", KutDebugger::format_value(value));
+                    }
+                },
+                ["backtrace" | "bt"] => {
+                    for (depth, (template, offset)) in self.frames.iter().enumerate().rev() {
+                        output!(self, "#{depth} {} at {offset}", KutDebugger::format_template(*template));
+                    }
+                },
+                ["list" | "l"] => {
+                    for (offset, instruction) in frame.closure.template.instructions.iter().enumerate() {
+                        let marker = if offset == pc { "=>" } else { "  " };
+                        output!(self, "{marker} {offset:4} {instruction:?}");
+                    }
+                },
+                ["watch" | "w", target] => match KutDebugger::parse_watch(target) {
+                    Some(watch) => {
+                        self.add_watch(watch, self.frames.len());
+                        self.update_watches(frame);
+                    },
+                    None => output!(self, "expected r<N> or c<N>"),
+                },
+                ["unwatch", target] => match KutDebugger::parse_watch(target) {
+                    Some(watch) => {
+                        let depth = self.frames.len();
+                        self.watches.retain(|(w, d, _)| *w != watch || *d != depth);
+                    },
+                    None => output!(self, "expected r<N> or c<N>"),
+                },
+                ["help" | "h"] => self.print_help(),
+                _ => output!(self, "unknown command, try help"),
+            }
+        }
+    }
+}
+
+impl KutHook for KutDebugger {
+    fn enter_function(&mut self, vm: &KutVm, frame: &KutFunction) -> Result<(), KutError> {
+        self.sync_frames(vm.call_depth() - 1);
+        self.frames.push((KutDebugger::template_index(vm, frame), frame.pc));
+        Ok(())
+    }
+
+    fn exit_function(&mut self, vm: &KutVm, _frame: &KutFunction, value: Option<&KutValue>) -> Result<(), KutError> {
+        self.sync_frames(vm.call_depth());
+        if let Some((template, _)) = self.frames.pop() {
+            let value = value.map_or_else(|| "nil".to_owned(), KutDebugger::format_value);
+            if !matches!(self.mode, KutStepMode::Continue) {
+                output!(self, "{} returned {value}", KutDebugger::format_template(template));
+            }
+        }
+        Ok(())
+    }
+
+    fn before_instruction(&mut self, vm: &KutVm, frame: &KutFunction, pc: usize, instruction: &KutInstruction) -> Result<(), KutError> {
+        self.sync_frames(vm.call_depth());
+        let template = match self.frames.last_mut() {
+            Some(top) => {
+                top.0 = top.0.or_else(|| KutDebugger::template_index(vm, frame));
+                top.1 = pc;
+                top.0
+            },
+            None => None,
+        };
+        let watch_changed = self.update_watches(frame);
+        if watch_changed || self.should_stop((template, pc)) {
+            output!(self, "{} at {pc}: {instruction:?}", KutDebugger::format_template(template));
+            self.prompt(vm, frame, pc)?;
+        }
+        Ok(())
+    }
+
+    fn instruction_error(&mut self, vm: &KutVm, frame: &KutFunction, pc: usize, instruction: &KutInstruction, error: &KutError) {
+        self.sync_frames(vm.call_depth());
+        let template = self.frames.last().and_then(|top| top.0);
+        output!(self, "{} at {pc}: {instruction:?} failed with {error:?}", KutDebugger::format_template(template));
+        let _ = self.update_watches(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use KutInstruction::*;
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// `main` returns `double(1) + 1`, `fail` calls a function that adds nil.
+    fn debugged_vm<'template>(commands: &str) -> (KutVm<'template>, SharedOutput) {
+        let main = vec![
+            GetLiteralR { reg: 0, literal: 0 },
+            CaptureFunc { reg: 1, template: 1 },
+            PushValue1R { val1: 0 },
+            CallMethodR { ret_position: 2, arg_count: 1, subject: 1 },
+            AddNumbersR { reg: 3, lhs: 2, rhs: 0 },
+            RetfMethodR { value: 3 },
+        ];
+        let double = || vec![AddNumbersR { reg: 1, lhs: 0, rhs: 0 }, RetfMethodR { value: 1 }];
+        let fail = vec![
+            CaptureFunc { reg: 1, template: 3 },
+            CallMethodR { ret_position: 0, arg_count: 0, subject: 1 },
+            RetfMethodR { value: 0 },
+        ];
+        let vm = KutVm::new(vec![KutValue::Integer(1)], vec![
+            KutFunctionTemplate::new(main, vec![], 4).with_name("main"),
+            KutFunctionTemplate::new(double(), vec![], 2),
+            KutFunctionTemplate::new(fail, vec![], 2).with_name("fail"),
+            KutFunctionTemplate::new(double(), vec![], 2),
+        ]);
+        let output = SharedOutput::default();
+        let input = io::Cursor::new(commands.as_bytes().to_vec());
+        vm.set_hook(Box::new(KutDebugger::with_io(Box::new(input), Box::new(output.clone()))));
+        (vm, output)
+    }
+
+    fn printed(output: &SharedOutput) -> String {
+        String::from_utf8(output.0.borrow().clone()).unwrap()
+    }
+
+    #[test]
+    fn breakpoint_stops_before_its_instruction() {
+        let (vm, output) = debugged_vm("break 1 0\ncontinue\nregs\ncontinue\n");
+        let vm = &vm;
+        assert_eq!(vm.call::<i64>("main", ()).unwrap(), 3);
+        let output = printed(&output);
+        assert!(output.contains("template 0 at 0: GetLiteralR"), "{output}");
+        assert!(output.contains("template 1 at 0: AddNumbersR"), "{output}");
+        assert!(output.contains("r0 = 1\n"), "{output}");
+        assert!(!output.contains("template 0 at 1"), "{output}");
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let (vm, output) = debugged_vm("next\nnext\nnext\nnext\ncontinue\n");
+        let vm = &vm;
+        assert_eq!(vm.call::<i64>("main", ()).unwrap(), 3);
+        let output = printed(&output);
+        assert!(output.contains("template 0 at 3: CallMethodR"), "{output}");
+        assert!(output.contains("template 0 at 4: AddNumbersR"), "{output}");
+        assert!(!output.contains("template 1 at 0"), "{output}");
+    }
+
+    #[test]
+    fn step_enters_calls_and_finish_leaves_them() {
+        let (vm, output) = debugged_vm("break 0 3\ncontinue\nstep\nbt\nfinish\nbt\ncontinue\n");
+        let vm = &vm;
+        assert_eq!(vm.call::<i64>("main", ()).unwrap(), 3);
+        let output = printed(&output);
+        let entered = output.find("template 1 at 0: AddNumbersR").expect(&output);
+        let returned = output.find("template 1 returned 2").expect(&output);
+        let left = output.find("template 0 at 4: AddNumbersR").expect(&output);
+        assert!(entered < returned && returned < left, "{output}");
+        assert!(output.contains("#1 template 1 at 0\n#0 template 0 at 3\n"), "{output}");
+        assert!(output.contains("(kutdb) #0 template 0 at 4\n(kutdb) "), "{output}");
+    }
+
+    #[test]
+    fn watch_stops_when_the_register_changes() {
+        let (vm, output) = debugged_vm("watch r2\ncontinue\ncontinue\n");
+        let vm = &vm;
+        assert_eq!(vm.call::<i64>("main", ()).unwrap(), 3);
+        let output = printed(&output);
+        assert!(output.contains("watch Register(2) changed: nil -> 2\ntemplate 0 at 4"), "{output}");
+    }
+
+    #[test]
+    fn frames_follow_failed_calls() {
+        let (vm, output) = debugged_vm("break 0 4\ncontinue\nbt\ncontinue\n");
+        let vm = &vm;
+        assert!(vm.call::<i64>("fail", ()).is_err());
+        assert_eq!(vm.call::<i64>("main", ()).unwrap(), 3);
+        let output = printed(&output);
+        assert!(output.contains("template 3 at 0: AddNumbersR { reg: 1, lhs: 0, rhs: 0 } failed with InvalidOperand"), "{output}");
+        assert!(output.contains("(kutdb) #0 template 0 at 4\n(kutdb) "), "{output}");
+    }
+}
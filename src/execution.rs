@@ -0,0 +1,337 @@
+use crate::value::*;
+use crate::value::convert::*;
+use crate::value::native::*;
+use crate::vm::*;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// Builds the result of a pending native inside the VM once its future completed.
+/// Like natives it is generic over the VM lifetime, so it can outlive any borrow of it.
+pub type KutCompletion = Box<dyn for<'template> FnOnce(&'template KutVm<'template>) -> Result<KutValue<'template>, KutError>>;
+
+/// The future a native waits for after returning `KutError::Pending` through `KutVm::pending`.
+pub struct KutPending {
+    future: Pin<Box<dyn Future<Output = KutCompletion>>>,
+}
+
+impl KutPending {
+    pub fn new<T>(future: impl Future<Output = Result<T, KutError>> + 'static) -> KutPending
+    where
+        T: for<'template> IntoKut<'template> + 'static,
+    {
+        fn completion<F>(complete: F) -> KutCompletion
+        where
+            F: for<'template> FnOnce(&'template KutVm<'template>) -> Result<KutValue<'template>, KutError> + 'static,
+        {
+            Box::new(complete)
+        }
+        KutPending {
+            future: Box::pin(async move {
+                let result = future.await;
+                completion(move |vm| {
+                    let value = result?.into_kut();
+                    vm.memory.track(&value)?;
+                    Ok(value)
+                })
+            }),
+        }
+    }
+
+    pub fn poll_completion(&mut self, cx: &mut Context<'_>) -> Poll<KutCompletion> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+impl fmt::Debug for KutPending {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KutPending")
+    }
+}
+
+/// A call started by `KutVm::start_call`. It keeps the frame of the function when the
+/// call fails, so that the host can `resume` it, e.g. after refilling the fuel on
+/// `KutError::OutOfFuel` or after `KutError::Interrupted`.
+#[derive(Debug)]
+pub struct KutCallHandle<'template, R> {
+    vm: &'template KutVm<'template>,
+    state: KutCallState<'template>,
+    result: PhantomData<fn() -> R>,
+}
+
+#[derive(Debug)]
+enum KutCallState<'template> {
+    Frame(KutFunction<'template>),
+    Native(Rc<KutNativeFunction>, Vec<KutValue<'template>>),
+    Finished,
+}
+
+impl<'template, R: FromKut<'template>> KutCallHandle<'template, R> {
+    pub(crate) fn new(vm: &'template KutVm<'template>, callee: KutCallee<'template>, args: Vec<KutValue<'template>>) -> Result<KutCallHandle<'template, R>, KutError> {
+        let state = match callee {
+            KutCallee::Func(closure) => KutCallState::Frame(closure.start_with(vm, args)?),
+            KutCallee::Native(native) => KutCallState::Native(native, args),
+        };
+        Ok(KutCallHandle { vm, state, result: PhantomData })
+    }
+
+    /// Runs the call until it returns or fails, continuing where it failed before. A
+    /// native runs again from the start. Once the call returned, resuming fails with
+    /// `KutError::InvalidResume`.
+    pub fn resume(&mut self) -> Result<R, KutError> {
+        let value = match &mut self.state {
+            KutCallState::Frame(frame) => frame.run(self.vm)?.unwrap_or(KutValue::Nil),
+            KutCallState::Native(native, args) => native.call(self.vm, args.clone())?,
+            KutCallState::Finished => return Err(KutError::InvalidResume { status: "finished".to_owned() }),
+        };
+        self.state = KutCallState::Finished;
+        R::from_kut(value)
+    }
+
+    /// The frame of the function, while the call did not return.
+    pub fn frame(&mut self) -> Option<&mut KutFunction<'template>> {
+        match &mut self.state {
+            KutCallState::Frame(frame) => Some(frame),
+            _ => None,
+        }
+    }
+}
+
+/// A call started by `KutVm::call_async`, which runs the script whenever it is polled
+/// and waits on the future of a pending native in between. It works with any executor,
+/// since it only uses the waker of the `Context` it is polled with.
+#[derive(Debug)]
+pub struct KutExecution<'template, R> {
+    vm: &'template KutVm<'template>,
+    frame: Option<KutFunction<'template>>,
+    pending: Option<KutPending>,
+    ready: Option<KutValue<'template>>,
+    result: PhantomData<fn() -> R>,
+}
+
+impl<'template, R: FromKut<'template>> KutExecution<'template, R> {
+    pub(crate) fn new(vm: &'template KutVm<'template>, frame: Option<KutFunction<'template>>, pending: Option<KutPending>, ready: Option<KutValue<'template>>) -> KutExecution<'template, R> {
+        KutExecution { vm, frame, pending, ready, result: PhantomData }
+    }
+}
+
+impl<'template, R: FromKut<'template>> Future for KutExecution<'template, R> {
+    type Output = Result<R, KutError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let execution = &mut *self;
+        loop {
+            if let Some(value) = execution.ready.take() {
+                return Poll::Ready(R::from_kut(value));
+            }
+            if let Some(pending) = execution.pending.as_mut() {
+                let Poll::Ready(complete) = pending.poll_completion(cx) else {
+                    return Poll::Pending;
+                };
+                execution.pending = None;
+                let value = match complete(execution.vm) {
+                    Ok(value) => value,
+                    Err(error) => return Poll::Ready(Err(error)),
+                };
+                match execution.frame.as_mut() {
+                    Some(frame) => {
+                        if let Err(error) = frame.resume_with(value) {
+                            return Poll::Ready(Err(error));
+                        }
+                    },
+                    None => return Poll::Ready(R::from_kut(value)),
+                }
+            }
+            let Some(frame) = execution.frame.as_mut() else {
+                return Poll::Ready(Err(KutError::Pending));
+            };
+            match frame.run(execution.vm) {
+                Err(KutError::Pending) => match execution.vm.take_pending() {
+                    Some(pending) => execution.pending = Some(pending),
+                    None => return Poll::Ready(Err(KutError::Pending)),
+                },
+                result => return Poll::Ready(result.and_then(|value| R::from_kut(value.unwrap_or(KutValue::Nil)))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+    use KutInstruction::*;
+
+    fn noop_raw_waker() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw_waker()
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+
+    /// Polls `future` until it is ready, returning its output and how often it was polled.
+    fn block_on<F: Future>(future: F) -> (F::Output, usize) {
+        // SAFETY: the vtable functions ignore the data pointer and do nothing.
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return (output, polls);
+            }
+        }
+    }
+
+    /// A future that is pending on its first poll and ready with `result` on the next.
+    struct Delayed<T> {
+        result: Option<Result<T, KutError>>,
+        polled: bool,
+    }
+
+    impl<T: Unpin> Future for Delayed<T> {
+        type Output = Result<T, KutError>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            if !self.polled {
+                self.polled = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(self.result.take().expect("polled after completion"))
+        }
+    }
+
+    /// A VM whose global `fetch(n)` waits for a `Delayed` future of `n * 2`, or fails
+    /// once the future completed when `n` is negative.
+    fn async_vm<'template>(templates: Vec<KutFunctionTemplate>) -> KutVm<'template> {
+        let vm = KutVm::new(vec![KutValue::String(Rc::new("fetch".to_owned())), KutValue::Integer(5), KutValue::Integer(-1)], templates);
+        vm.set_global("fetch", KutValue::Native(Rc::new(KutNativeFunction::new("fetch", |vm, args| {
+            let result = match args.first() {
+                Some(KutValue::Integer(n)) if *n >= 0 => Ok(n * 2),
+                _ => Err(KutError::Io { operation: "fetch".to_owned(), message: "no value".to_owned() }),
+            };
+            vm.pending(Delayed { result: Some(result), polled: false })
+        }))));
+        vm
+    }
+
+    #[test]
+    fn pending_natives_resume_into_registers_and_the_stack() {
+        let main = vec![
+            GetGlobalRg { reg: 0, name: 0 },
+            GetLiteralR { reg: 1, literal: 1 },
+            PushValue1R { val1: 1 },
+            CallMethodR { ret_position: 2, arg_count: 1, subject: 0 },
+            PushValue1R { val1: 2 },
+            CallMethodS { arg_count: 1, subject: 0 },
+            RetfMethodS,
+        ];
+        let vm = &async_vm(vec![KutFunctionTemplate::new(main, vec![], 3).with_name("main")]);
+        let (result, polls) = block_on(vm.call_async::<i64>("main", ()).unwrap());
+        assert_eq!(result.unwrap(), 20);
+        assert_eq!(polls, 3);
+        assert!(matches!(vm.call::<i64>("main", ()), Err(KutError::Pending)));
+        let (result, polls) = block_on(vm.call_async::<i64>("fetch", (21,)).unwrap());
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(polls, 2);
+    }
+
+    #[test]
+    fn pending_native_inside_a_coroutine_resumes_it() {
+        // Yields fetch(n), then returns fetch(n) plus the value it is resumed with.
+        let body = vec![
+            GetGlobalRg { reg: 1, name: 0 },
+            PushValue1R { val1: 0 },
+            CallMethodR { ret_position: 2, arg_count: 1, subject: 1 },
+            CoroYieldRg { reg: 3, value: 2 },
+            AddNumbersR { reg: 3, lhs: 2, rhs: 3 },
+            RetfMethodR { value: 3 },
+        ];
+        let main = vec![
+            CaptureFunc { reg: 0, template: 1 },
+            CoroutineRg { reg: 1, func: 0 },
+            GetLiteralR { reg: 2, literal: 1 },
+            CoroResumeR { reg: 3, coroutine: 1, value: 2 },
+            CoroResumeR { reg: 4, coroutine: 1, value: 3 },
+            AddNumbersR { reg: 4, lhs: 3, rhs: 4 },
+            RetfMethodR { value: 4 },
+        ];
+        let vm = &async_vm(vec![
+            KutFunctionTemplate::new(main, vec![], 5).with_name("main"),
+            KutFunctionTemplate::new(body, vec![], 4),
+        ]);
+        let (result, polls) = block_on(vm.call_async::<i64>("main", ()).unwrap());
+        assert_eq!(result.unwrap(), 30);
+        assert_eq!(polls, 2);
+    }
+
+    #[test]
+    fn failed_completion_is_surfaced() {
+        let main = vec![
+            GetGlobalRg { reg: 0, name: 0 },
+            GetLiteralR { reg: 1, literal: 2 },
+            PushValue1R { val1: 1 },
+            CallMethodR { ret_position: 2, arg_count: 1, subject: 0 },
+            RetfMethodR { value: 2 },
+        ];
+        let vm = &async_vm(vec![KutFunctionTemplate::new(main, vec![], 3).with_name("main")]);
+        let (result, _) = block_on(vm.call_async::<i64>("main", ()).unwrap());
+        assert!(matches!(result, Err(KutError::Io { operation, .. }) if operation == "fetch"));
+        let (result, _) = block_on(vm.call_async::<i64>("fetch", (-1,)).unwrap());
+        assert!(matches!(result, Err(KutError::Io { .. })));
+    }
+
+    /// `sum(n)` adds the integers below `n` in a loop.
+    fn sum_vm<'template>() -> KutVm<'template> {
+        let sum = vec![
+            GetLiteralR { reg: 1, literal: 0 },
+            GetLiteralR { reg: 2, literal: 0 },
+            GetLiteralR { reg: 3, literal: 1 },
+            CompareLssR { reg: 4, lhs: 1, rhs: 0 },
+            JumpIfFalse { cond: 4, target: 8 },
+            AddNumbersR { reg: 2, lhs: 2, rhs: 1 },
+            AddNumbersR { reg: 1, lhs: 1, rhs: 3 },
+            JumpAlwaysT { target: 3 },
+            RetfMethodR { value: 2 },
+        ];
+        KutVm::new(vec![KutValue::Integer(0), KutValue::Integer(1)], vec![KutFunctionTemplate::new(sum, vec![], 5).with_name("sum")])
+    }
+
+    #[test]
+    fn call_handle_resumes_after_running_out_of_fuel() {
+        let vm = &sum_vm();
+        vm.set_fuel(Some(50));
+        let mut call = vm.start_call::<i64>("sum", (100,)).unwrap();
+        let mut refills = 0;
+        let result = loop {
+            match call.resume() {
+                Err(KutError::OutOfFuel { .. }) => {
+                    refills += 1;
+                    vm.add_fuel(50);
+                },
+                result => break result,
+            }
+        };
+        assert_eq!(result.unwrap(), 4950);
+        assert!(refills > 5);
+        assert!(call.frame().is_none());
+        assert!(matches!(call.resume(), Err(KutError::InvalidResume { .. })));
+    }
+
+    #[test]
+    fn call_handle_resumes_after_an_interrupt() {
+        let vm = &sum_vm();
+        let mut call = vm.start_call::<i64>("sum", (10,)).unwrap();
+        vm.interrupt_handle().interrupt();
+        assert!(matches!(call.resume(), Err(KutError::Interrupted)));
+        assert_eq!(call.frame().map(|frame| frame.pc), Some(0));
+        assert_eq!(call.resume().unwrap(), 45);
+    }
+}
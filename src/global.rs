@@ -0,0 +1,123 @@
+use crate::value::*;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Global variables of a `KutVm`. Every name is interned once into a slot, so
+/// instructions can cache the slot of a literal name and skip the hash lookup. A
+/// slot stays allocated after its name is interned, even before it is defined.
+#[derive(Debug, Default)]
+pub struct KutGlobals<'template> {
+    slots: HashMap<Rc<str>, usize>,
+    values: Vec<(Rc<str>, Option<KutValue<'template>>)>,
+}
+
+impl<'template> KutGlobals<'template> {
+    pub fn new() -> KutGlobals<'template> {
+        KutGlobals::default()
+    }
+
+    /// Returns the slot of `name`, allocating an undefined one if it is new.
+    pub fn intern(&mut self, name: &str) -> usize {
+        if let Some(slot) = self.slots.get(name) {
+            return *slot;
+        }
+        let name: Rc<str> = Rc::from(name);
+        self.slots.insert(Rc::clone(&name), self.values.len());
+        self.values.push((name, None));
+        self.values.len() - 1
+    }
+
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    pub fn name(&self, slot: usize) -> Option<&str> {
+        self.values.get(slot).map(|(name, _)| &**name)
+    }
+
+    pub fn get(&self, slot: usize) -> Option<&KutValue<'template>> {
+        self.values.get(slot).and_then(|(_, value)| value.as_ref())
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<&KutValue<'template>> {
+        self.slot(name).and_then(|slot| self.get(slot))
+    }
+
+    /// Defines or overwrites the global in an interned `slot`.
+    pub fn set(&mut self, slot: usize, value: KutValue<'template>) {
+        if let Some((_, old)) = self.values.get_mut(slot) {
+            *old = Some(value);
+        }
+    }
+
+    pub fn set_by_name(&mut self, name: &str, value: KutValue<'template>) {
+        let slot = self.intern(name);
+        self.set(slot, value);
+    }
+
+    /// Iterates over the defined globals in the order their names were interned.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &KutValue<'template>)> {
+        self.values.iter().filter_map(|(name, value)| value.as_ref().map(|value| (&**name, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::*;
+    use KutInstruction::*;
+
+    #[test]
+    fn names_are_interned_into_stable_slots() {
+        let mut globals = KutGlobals::new();
+        let x = globals.intern("x");
+        let y = globals.intern("y");
+        assert_ne!(x, y);
+        assert_eq!(globals.intern("x"), x);
+        assert_eq!(globals.slot("y"), Some(y));
+        assert_eq!(globals.slot("z"), None);
+        assert_eq!(globals.name(x), Some("x"));
+        assert!(globals.get(x).is_none());
+        globals.set_by_name("y", KutValue::Integer(2));
+        globals.set(x, KutValue::Integer(1));
+        globals.set_by_name("x", KutValue::Integer(3));
+        assert_eq!(globals.intern("x"), x);
+        assert!(matches!(globals.get_by_name("x"), Some(KutValue::Integer(3))));
+        globals.intern("undefined");
+        globals.set(99, KutValue::Nil);
+        let defined: Vec<_> = globals.iter().map(|(name, _)| name).collect();
+        assert_eq!(defined, ["x", "y"]);
+    }
+
+    fn vm<'template>() -> KutVm<'template> {
+        let literals = vec![KutValue::String(Rc::new("answer".to_owned())), KutValue::String(Rc::new("answer".to_owned())), KutValue::Integer(42)];
+        KutVm::new(literals, vec![KutFunctionTemplate::new(vec![], vec![], 2)])
+    }
+
+    #[test]
+    fn global_slot_interns_each_literal_once() {
+        let vm = vm();
+        let template = &vm.templates[0];
+        let slot = vm.global_slot(template, 0).unwrap();
+        assert_eq!(vm.globals.borrow().slot("answer"), Some(slot));
+        assert_eq!(vm.global_slot(template, 1).unwrap(), slot);
+        let globals = vm.globals.borrow_mut();
+        assert_eq!(vm.global_slot(template, 0).unwrap(), slot);
+        drop(globals);
+        assert!(matches!(vm.global_slot(template, 2), Err(KutError::InvalidOperand { .. })));
+        assert!(matches!(vm.global_slot(template, 3), Err(KutError::OutOfRangeLiteral { literal: 3, .. })));
+    }
+
+    #[test]
+    fn reading_an_undefined_global_fails() {
+        let vm = &vm();
+        let mut frame = Rc::new(KutClosure { template: &vm.templates[0], captures: vec![] }).start_with(vm, vec![KutValue::Nil, KutValue::Integer(42)]).unwrap();
+        let error = GetGlobalRg { reg: 0, name: 0 }.run(&mut frame, vm).unwrap_err();
+        assert!(matches!(&error, KutError::UndefinedGlobal { name } if name == "answer"), "{error:?}");
+        assert!(matches!(frame.registers[0], KutValue::Nil));
+        SetGlobalRg { reg: 1, name: 1 }.run(&mut frame, vm).unwrap();
+        GetGlobalRg { reg: 0, name: 0 }.run(&mut frame, vm).unwrap();
+        assert!(matches!(frame.registers[0], KutValue::Integer(42)));
+        assert!(matches!(vm.globals.borrow().get_by_name("answer"), Some(KutValue::Integer(42))));
+    }
+}
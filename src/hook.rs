@@ -0,0 +1,29 @@
+use crate::value::*;
+use crate::vm::*;
+use std::fmt;
+
+/// Observer of the interpreter, installed on a `KutVm` with `KutVm::set_hook`.
+///
+/// Every method has an empty default, so a hook only implements the events it
+/// needs. An error returned from a hook aborts execution with that error.
+pub trait KutHook {
+    fn enter_function(&mut self, _vm: &KutVm, _frame: &KutFunction) -> Result<(), KutError> {
+        Ok(())
+    }
+
+    fn exit_function(&mut self, _vm: &KutVm, _frame: &KutFunction, _value: Option<&KutValue>) -> Result<(), KutError> {
+        Ok(())
+    }
+
+    fn before_instruction(&mut self, _vm: &KutVm, _frame: &KutFunction, _pc: usize, _instruction: &KutInstruction) -> Result<(), KutError> {
+        Ok(())
+    }
+
+    fn instruction_error(&mut self, _vm: &KutVm, _frame: &KutFunction, _pc: usize, _instruction: &KutInstruction, _error: &KutError) {}
+}
+
+impl fmt::Debug for dyn KutHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KutHook")
+    }
+}
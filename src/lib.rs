@@ -1,3 +1,9 @@
+pub mod execution;
+pub mod global;
+pub mod hook;
+pub mod library;
+pub mod memory;
+pub mod module;
 pub mod value;
 pub mod vm;
 pub mod list;
@@ -0,0 +1,243 @@
+use crate::value::*;
+use crate::value::convert::*;
+use crate::value::native::*;
+use crate::vm::*;
+use std::io::Write;
+use std::rc::Rc;
+
+fn io_error(operation: &str, path: &str, error: std::io::Error) -> KutError {
+    KutError::Io { operation: format!("io.{operation}"), message: format!("{path}: {error}") }
+}
+
+fn write_console<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>, stderr: bool, newline: bool) -> Result<KutValue<'template>, KutError> {
+    vm.capabilities.check_stdout()?;
+    let mut text = args.iter().map(KutValue::to_display_string).collect::<Vec<_>>().join(" ");
+    if newline {
+        text.push('\n');
+    }
+    let result = if stderr { std::io::stderr().write_all(text.as_bytes()) } else { std::io::stdout().write_all(text.as_bytes()) };
+    result.map_err(|error| io_error("print", if stderr { "stderr" } else { "stdout" }, error))?;
+    Ok(KutValue::Nil)
+}
+
+fn print<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    write_console(vm, args, false, false)
+}
+
+fn println<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    write_console(vm, args, false, true)
+}
+
+fn eprint<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    write_console(vm, args, true, false)
+}
+
+fn eprintln<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    write_console(vm, args, true, true)
+}
+
+fn read_file<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    let (path,) = <(String,)>::from_kut(args.into_kut())?;
+    let resolved = vm.capabilities.check_read(&path)?;
+    let value = std::fs::read_to_string(resolved).map_err(|error| io_error("read_file", &path, error))?.into_kut();
+    vm.memory.track(&value)?;
+    Ok(value)
+}
+
+fn write<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>, append: bool) -> Result<KutValue<'template>, KutError> {
+    let (path, contents) = <(String, Rc<String>)>::from_kut(args.into_kut())?;
+    let resolved = vm.capabilities.check_write(&path)?;
+    let operation = if append { "append_file" } else { "write_file" };
+    let mut file = std::fs::OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(resolved).map_err(|error| io_error(operation, &path, error))?;
+    file.write_all(contents.as_bytes()).map_err(|error| io_error(operation, &path, error))?;
+    Ok(KutValue::Nil)
+}
+
+fn write_file<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    write(vm, args, false)
+}
+
+fn append_file<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    write(vm, args, true)
+}
+
+fn list_dir<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    let (path,) = <(String,)>::from_kut(args.into_kut())?;
+    let resolved = vm.capabilities.check_read(&path)?;
+    let mut names = vec![];
+    for entry in std::fs::read_dir(resolved).map_err(|error| io_error("list_dir", &path, error))? {
+        let entry = entry.map_err(|error| io_error("list_dir", &path, error))?;
+        names.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+    let names: Vec<KutValue> = names.into_iter().map(String::into_kut).collect();
+    for name in names.iter() {
+        vm.memory.track(name)?;
+    }
+    let value = names.into_kut();
+    vm.memory.track(&value)?;
+    Ok(value)
+}
+
+fn exists<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    let (path,) = <(String,)>::from_kut(args.into_kut())?;
+    let resolved = vm.capabilities.check_read(&path)?;
+    Ok(KutValue::Bool(resolved.exists()))
+}
+
+fn is_dir<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    let (path,) = <(String,)>::from_kut(args.into_kut())?;
+    let resolved = vm.capabilities.check_read(&path)?;
+    Ok(KutValue::Bool(resolved.is_dir()))
+}
+
+/// Exports of the `io` module. Every function checks its capability on the
+/// `KutVm` first and fails with `KutError::PermissionDenied` when it is missing.
+/// The print functions join their arguments with spaces.
+pub fn exports<'template>() -> Vec<(&'static str, KutValue<'template>)> {
+    let functions: [(&'static str, KutNativeFnPointer); 10] = [
+        ("print", print),
+        ("println", println),
+        ("eprint", eprint),
+        ("eprintln", eprintln),
+        ("read_file", read_file),
+        ("write_file", write_file),
+        ("append_file", append_file),
+        ("list_dir", list_dir),
+        ("exists", exists),
+        ("is_dir", is_dir),
+    ];
+    functions.into_iter().map(|(name, function)| (name, KutValue::Native(Rc::new(KutNativeFunction::new(&format!("io.{name}"), function))))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    /// A scratch directory with `allowed/file.txt`, `allowed2/secret.txt` and
+    /// `outside/secret.txt`, removed when dropped.
+    struct Sandbox {
+        root: PathBuf,
+    }
+
+    impl Sandbox {
+        fn new(name: &str) -> Sandbox {
+            let root = std::env::temp_dir().join(format!("kut-io-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            for (directory, file) in [("allowed", "file.txt"), ("allowed2", "secret.txt"), ("outside", "secret.txt")] {
+                std::fs::create_dir_all(root.join(directory)).unwrap();
+                std::fs::write(root.join(directory).join(file), directory).unwrap();
+            }
+            Sandbox { root }
+        }
+
+        fn path(&self, path: &str) -> String {
+            self.root.join(path).to_string_lossy().into_owned()
+        }
+
+        fn vm<'template>(&self, read: bool, write: bool) -> KutVm<'template> {
+            let mut vm = KutVm::new(vec![], vec![]);
+            let allowed = vec![self.root.join("allowed")];
+            vm.capabilities = KutCapabilities {
+                stdout: false,
+                read_paths: if read { allowed.clone() } else { vec![] },
+                write_paths: if write { allowed } else { vec![] },
+            };
+            vm
+        }
+    }
+
+    impl Drop for Sandbox {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    fn call<'template>(vm: &'template KutVm<'template>, name: &str, args: Vec<&str>) -> Result<KutValue<'template>, KutError> {
+        let Some((_, KutValue::Native(function))) = exports().into_iter().find(|(export, _)| *export == name) else {
+            panic!("io.{name} is not exported");
+        };
+        function.call(vm, args.into_iter().map(|arg| arg.to_owned().into_kut()).collect())
+    }
+
+    fn is_denied(result: Result<KutValue, KutError>, expected: &str) -> bool {
+        matches!(result, Err(KutError::PermissionDenied { capability, .. }) if capability == expected)
+    }
+
+    #[test]
+    fn allowed_paths_are_read() {
+        let sandbox = Sandbox::new("allowed");
+        let vm = &sandbox.vm(true, false);
+        assert!(matches!(call(vm, "read_file", vec![&sandbox.path("allowed/file.txt")]), Ok(KutValue::String(text)) if *text == "allowed"));
+        assert!(call(vm, "read_file", vec![&sandbox.path("allowed/./file.txt")]).is_ok());
+    }
+
+    #[test]
+    fn paths_outside_the_allowlist_are_denied() {
+        let sandbox = Sandbox::new("outside");
+        let vm = &sandbox.vm(true, true);
+        assert!(is_denied(call(vm, "read_file", vec![&sandbox.path("outside/secret.txt")]), "read"));
+        assert!(is_denied(call(vm, "write_file", vec![&sandbox.path("outside/secret.txt"), "x"]), "write"));
+        assert_eq!(std::fs::read_to_string(sandbox.root.join("outside/secret.txt")).unwrap(), "outside");
+    }
+
+    #[test]
+    fn parent_components_do_not_escape() {
+        let sandbox = Sandbox::new("parent");
+        let vm = &sandbox.vm(true, true);
+        assert!(is_denied(call(vm, "read_file", vec![&sandbox.path("allowed/../outside/secret.txt")]), "read"));
+        assert!(is_denied(call(vm, "write_file", vec![&sandbox.path("allowed/missing/../../outside/new.txt"), "x"]), "write"));
+        assert!(!sandbox.root.join("outside/new.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symbolic_links_out_of_an_allowed_directory_are_denied() {
+        let sandbox = Sandbox::new("symlink");
+        std::os::unix::fs::symlink(sandbox.root.join("outside"), sandbox.root.join("allowed/link")).unwrap();
+        let vm = &sandbox.vm(true, true);
+        assert!(is_denied(call(vm, "read_file", vec![&sandbox.path("allowed/link/secret.txt")]), "read"));
+        assert!(is_denied(call(vm, "write_file", vec![&sandbox.path("allowed/link/new.txt"), "x"]), "write"));
+        assert!(is_denied(call(vm, "list_dir", vec![&sandbox.path("allowed/link")]), "read"));
+    }
+
+    #[test]
+    fn new_files_are_written_under_an_allowed_directory() {
+        let sandbox = Sandbox::new("write");
+        let vm = &sandbox.vm(false, true);
+        let path = sandbox.path("allowed/new.txt");
+        assert!(matches!(call(vm, "write_file", vec![&path, "a"]), Ok(KutValue::Nil)));
+        assert!(matches!(call(vm, "append_file", vec![&path, "b"]), Ok(KutValue::Nil)));
+        assert_eq!(std::fs::read_to_string(Path::new(&path)).unwrap(), "ab");
+        assert!(is_denied(call(vm, "read_file", vec![&path]), "read"));
+    }
+
+    #[test]
+    fn sibling_prefixes_are_not_allowed() {
+        let sandbox = Sandbox::new("sibling");
+        let vm = &sandbox.vm(true, true);
+        assert!(is_denied(call(vm, "read_file", vec![&sandbox.path("allowed2/secret.txt")]), "read"));
+        assert!(is_denied(call(vm, "write_file", vec![&sandbox.path("allowed2/new.txt"), "x"]), "write"));
+    }
+
+    #[test]
+    fn console_output_needs_stdout() {
+        let vm = &KutVm::new(vec![], vec![]);
+        for name in ["print", "println", "eprint", "eprintln"] {
+            assert!(is_denied(call(vm, name, vec!["text"]), "stdout"), "io.{name}");
+        }
+    }
+
+    #[test]
+    fn queries_need_read() {
+        let sandbox = Sandbox::new("queries");
+        let vm = &sandbox.vm(false, true);
+        for name in ["exists", "is_dir", "list_dir"] {
+            assert!(is_denied(call(vm, name, vec![&sandbox.path("allowed")]), "read"), "io.{name}");
+        }
+        let vm = &sandbox.vm(true, false);
+        assert!(matches!(call(vm, "exists", vec![&sandbox.path("allowed/missing.txt")]), Ok(KutValue::Bool(false))));
+        assert!(matches!(call(vm, "is_dir", vec![&sandbox.path("allowed")]), Ok(KutValue::Bool(true))));
+    }
+}
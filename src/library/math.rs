@@ -0,0 +1,208 @@
+use crate::value::*;
+use crate::value::native::*;
+use crate::vm::*;
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+fn number(name: &str, value: &KutValue) -> Result<(), KutError> {
+    match value {
+        KutValue::Integer(_) | KutValue::Number(_) => Ok(()),
+        other => Err(KutError::InvalidOperand { operation: format!("math.{name}"), value_type: other.get_type_string() }),
+    }
+}
+
+fn is_nan(value: &KutValue) -> bool {
+    matches!(value, KutValue::Number(num) if num.is_nan())
+}
+
+/// Picks the argument that `wanted` prefers, keeping its type, or NaN if any argument is NaN.
+fn extreme<'template>(name: &str, args: Vec<KutValue<'template>>, wanted: Ordering) -> Result<KutValue<'template>, KutError> {
+    let mut args = args.into_iter();
+    let mut best = args.next().ok_or_else(|| KutError::WrongArgumentCount { function: format!("math.{name}"), min: 1, max: None, given: 0 })?;
+    number(name, &best)?;
+    for arg in args {
+        number(name, &arg)?;
+        if is_nan(&arg) || (!is_nan(&best) && arg.partial_cmp(&best) == Some(wanted)) {
+            best = arg;
+        }
+    }
+    Ok(best)
+}
+
+fn min<'template>(_vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    extreme("min", args, Ordering::Less)
+}
+
+fn max<'template>(_vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    extreme("max", args, Ordering::Greater)
+}
+
+fn clamp<'template>(_vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    let [value, low, high]: [KutValue; 3] = args.try_into().map_err(|args: Vec<KutValue>| KutError::WrongArgumentCount { function: "math.clamp".to_owned(), min: 3, max: Some(3), given: args.len() })?;
+    for arg in [&value, &low, &high] {
+        number("clamp", arg)?;
+    }
+    if low > high || is_nan(&low) || is_nan(&high) {
+        return Err(KutError::InvalidArgument { function: "math.clamp".to_owned(), message: format!("its bounds {low} and {high} are not ordered") });
+    }
+    Ok(if value < low { low } else if value > high { high } else { value })
+}
+
+fn abs<'template>(_vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    match args.as_slice() {
+        [KutValue::Integer(int)] => int.checked_abs().map(KutValue::Integer).ok_or_else(|| KutError::IntegerOverflow { operation: "math.abs".to_owned() }),
+        [KutValue::Number(num)] => Ok(KutValue::Number(num.abs())),
+        [other] => Err(KutError::InvalidOperand { operation: "math.abs".to_owned(), value_type: other.get_type_string() }),
+        _ => Err(KutError::WrongArgumentCount { function: "math.abs".to_owned(), min: 1, max: Some(1), given: args.len() }),
+    }
+}
+
+/// Like `unary`, but an `Integer` is already rounded and is returned unchanged.
+fn rounding<'template>(name: &'static str, function: fn(f64) -> f64) -> (&'static str, KutValue<'template>) {
+    let native = KutNativeFunction::new(&format!("math.{name}"), move |_, args| match args.as_slice() {
+        [KutValue::Integer(int)] => Ok(KutValue::Integer(*int)),
+        [KutValue::Number(num)] => Ok(KutValue::Number(function(*num))),
+        [other] => Err(KutError::InvalidOperand { operation: format!("math.{name}"), value_type: other.get_type_string() }),
+        _ => Err(KutError::WrongArgumentCount { function: format!("math.{name}"), min: 1, max: Some(1), given: args.len() }),
+    });
+    (name, KutValue::Native(Rc::new(native)))
+}
+
+fn unary<'template>(name: &'static str, function: fn(f64) -> f64) -> (&'static str, KutValue<'template>) {
+    (name, KutValue::Native(Rc::new(KutNativeFunction::typed(&format!("math.{name}"), move |x: f64| Ok(function(x))))))
+}
+
+fn binary<'template>(name: &'static str, function: fn(f64, f64) -> f64) -> (&'static str, KutValue<'template>) {
+    (name, KutValue::Native(Rc::new(KutNativeFunction::typed(&format!("math.{name}"), move |x: f64, y: f64| Ok(function(x, y))))))
+}
+
+fn predicate<'template>(name: &'static str, function: fn(f64) -> bool) -> (&'static str, KutValue<'template>) {
+    (name, KutValue::Native(Rc::new(KutNativeFunction::typed(&format!("math.{name}"), move |x: f64| Ok(function(x))))))
+}
+
+fn raw<'template>(name: &'static str, function: KutNativeFnPointer) -> (&'static str, KutValue<'template>) {
+    (name, KutValue::Native(Rc::new(KutNativeFunction::new(&format!("math.{name}"), function))))
+}
+
+/// Exports of the `math` module. Functions take `Integer` and `Number` arguments
+/// and return a `Number`, except `abs`, `min`, `max` and `clamp`, which return one
+/// of their arguments unchanged, the rounding functions, which return an `Integer`
+/// unchanged, and the `is_*` checks, which return a `Bool`.
+pub fn exports<'template>() -> Vec<(&'static str, KutValue<'template>)> {
+    vec![
+        ("pi", KutValue::Number(std::f64::consts::PI)),
+        ("tau", KutValue::Number(std::f64::consts::TAU)),
+        ("e", KutValue::Number(std::f64::consts::E)),
+        ("inf", KutValue::Number(f64::INFINITY)),
+        ("nan", KutValue::Number(f64::NAN)),
+        ("max_integer", KutValue::Integer(i64::MAX)),
+        ("min_integer", KutValue::Integer(i64::MIN)),
+        rounding("floor", f64::floor),
+        rounding("ceil", f64::ceil),
+        rounding("round", f64::round),
+        rounding("trunc", f64::trunc),
+        unary("sqrt", f64::sqrt),
+        unary("cbrt", f64::cbrt),
+        unary("exp", f64::exp),
+        unary("ln", f64::ln),
+        unary("log2", f64::log2),
+        unary("log10", f64::log10),
+        unary("sin", f64::sin),
+        unary("cos", f64::cos),
+        unary("tan", f64::tan),
+        unary("asin", f64::asin),
+        unary("acos", f64::acos),
+        unary("atan", f64::atan),
+        unary("sinh", f64::sinh),
+        unary("cosh", f64::cosh),
+        unary("tanh", f64::tanh),
+        unary("sign", f64::signum),
+        binary("pow", f64::powf),
+        binary("log", f64::log),
+        binary("atan2", f64::atan2),
+        binary("hypot", f64::hypot),
+        predicate("is_nan", f64::is_nan),
+        predicate("is_finite", f64::is_finite),
+        predicate("is_inf", f64::is_infinite),
+        raw("abs", abs),
+        raw("min", min),
+        raw("max", max),
+        raw("clamp", clamp),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call<'template>(vm: &'template KutVm<'template>, name: &str, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+        let Some((_, KutValue::Native(function))) = exports().into_iter().find(|(export, _)| *export == name) else {
+            panic!("math.{name} is not exported");
+        };
+        function.call(vm, args)
+    }
+
+    #[test]
+    fn rounding_keeps_integers_unchanged() {
+        let vm = &KutVm::new(vec![], vec![]);
+        for name in ["floor", "ceil", "round", "trunc"] {
+            for int in [0, -3, i64::MAX, i64::MIN] {
+                assert!(matches!(call(vm, name, vec![KutValue::Integer(int)]), Ok(KutValue::Integer(result)) if result == int), "{name}({int})");
+            }
+            let error = call(vm, name, vec![KutValue::Bool(true)]).unwrap_err();
+            assert!(matches!(error, KutError::InvalidOperand { .. }), "{error:?}");
+            let error = call(vm, name, vec![]).unwrap_err();
+            assert!(matches!(error, KutError::WrongArgumentCount { min: 1, max: Some(1), given: 0, .. }), "{error:?}");
+        }
+        let rounded = ["floor", "ceil", "round", "trunc"].map(|name| call(vm, name, vec![KutValue::Number(-2.5)]).unwrap());
+        assert!(matches!(rounded, [KutValue::Number(floor), KutValue::Number(ceil), KutValue::Number(round), KutValue::Number(trunc)]
+            if floor == -3.0 && ceil == -2.0 && round == -3.0 && trunc == -2.0), "{rounded:?}");
+    }
+
+    #[test]
+    fn clamp_keeps_the_type_and_rejects_unordered_bounds() {
+        let vm = &KutVm::new(vec![], vec![]);
+        assert!(matches!(call(vm, "clamp", vec![KutValue::Integer(5), KutValue::Number(0.5), KutValue::Integer(3)]), Ok(KutValue::Integer(3))));
+        assert!(matches!(call(vm, "clamp", vec![KutValue::Integer(-5), KutValue::Number(0.5), KutValue::Integer(3)]), Ok(KutValue::Number(low)) if low == 0.5));
+        assert!(matches!(call(vm, "clamp", vec![KutValue::Integer(2), KutValue::Integer(0), KutValue::Integer(3)]), Ok(KutValue::Integer(2))));
+        for (low, high) in [(KutValue::Integer(3), KutValue::Integer(0)), (KutValue::Number(f64::NAN), KutValue::Integer(3))] {
+            let error = call(vm, "clamp", vec![KutValue::Integer(1), low, high]).unwrap_err();
+            assert!(matches!(&error, KutError::InvalidArgument { function, .. } if function == "math.clamp"), "{error:?}");
+        }
+        let error = call(vm, "clamp", vec![KutValue::Integer(1), KutValue::Integer(0)]).unwrap_err();
+        assert!(matches!(error, KutError::WrongArgumentCount { min: 3, max: Some(3), given: 2, .. }), "{error:?}");
+        let error = call(vm, "clamp", vec![KutValue::Nil, KutValue::Integer(0), KutValue::Integer(3)]).unwrap_err();
+        assert!(matches!(error, KutError::InvalidOperand { .. }), "{error:?}");
+    }
+
+    #[test]
+    fn min_and_max_return_an_argument_or_nan() {
+        let vm = &KutVm::new(vec![], vec![]);
+        assert!(matches!(call(vm, "min", vec![KutValue::Number(2.5), KutValue::Integer(2), KutValue::Integer(7)]), Ok(KutValue::Integer(2))));
+        assert!(matches!(call(vm, "max", vec![KutValue::Number(2.5), KutValue::Integer(2)]), Ok(KutValue::Number(max)) if max == 2.5));
+        assert!(matches!(call(vm, "max", vec![KutValue::Integer(1), KutValue::Number(f64::NAN), KutValue::Integer(3)]), Ok(KutValue::Number(max)) if max.is_nan()));
+        let error = call(vm, "min", vec![]).unwrap_err();
+        assert!(matches!(error, KutError::WrongArgumentCount { min: 1, max: None, given: 0, .. }), "{error:?}");
+    }
+
+    #[test]
+    fn abs_keeps_integers_and_checks_overflow() {
+        let vm = &KutVm::new(vec![], vec![]);
+        assert!(matches!(call(vm, "abs", vec![KutValue::Integer(-4)]), Ok(KutValue::Integer(4))));
+        assert!(matches!(call(vm, "abs", vec![KutValue::Number(-0.5)]), Ok(KutValue::Number(abs)) if abs == 0.5));
+        let error = call(vm, "abs", vec![KutValue::Integer(i64::MIN)]).unwrap_err();
+        assert!(matches!(error, KutError::IntegerOverflow { .. }), "{error:?}");
+    }
+
+    #[test]
+    fn other_functions_take_integers_and_return_numbers() {
+        let vm = &KutVm::new(vec![], vec![]);
+        assert!(matches!(call(vm, "sqrt", vec![KutValue::Integer(9)]), Ok(KutValue::Number(root)) if root == 3.0));
+        assert!(matches!(call(vm, "pow", vec![KutValue::Integer(2), KutValue::Integer(10)]), Ok(KutValue::Number(power)) if power == 1024.0));
+        assert!(matches!(call(vm, "is_nan", vec![KutValue::Integer(1)]), Ok(KutValue::Bool(false))));
+        let error = call(vm, "pow", vec![KutValue::Integer(2)]).unwrap_err();
+        assert!(matches!(error, KutError::WrongArgumentCount { min: 2, max: Some(2), given: 1, .. }), "{error:?}");
+        let error = call(vm, "sqrt", vec![KutValue::String(Rc::new("9".to_owned()))]).unwrap_err();
+        assert!(matches!(error, KutError::InvalidConversion { .. }), "{error:?}");
+    }
+}
@@ -0,0 +1,3 @@
+pub mod io;
+pub mod math;
+pub mod string;
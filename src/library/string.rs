@@ -0,0 +1,318 @@
+use crate::value::*;
+use crate::memory::*;
+use crate::value::convert::*;
+use crate::value::native::*;
+use crate::vm::*;
+use std::rc::Rc;
+
+fn invalid(name: &str, value_type: &str) -> KutError {
+    KutError::InvalidOperand { operation: format!("string.{name}"), value_type: value_type.to_owned() }
+}
+
+/// Converts a character `index`, counted from the end if negative, to a byte offset.
+/// The length itself is a valid index, for the end of a range.
+fn byte_offset(string: &str, index: i64) -> Result<usize, KutError> {
+    let length = string.chars().count();
+    let position = if index < 0 { index + length as i64 } else { index };
+    if position < 0 || position as usize > length {
+        return Err(KutError::OutOfRangeIndex { index, length });
+    }
+    Ok(string.char_indices().nth(position as usize).map_or(string.len(), |(offset, _)| offset))
+}
+
+fn track_result<'template>(vm: &'template KutVm<'template>, value: &KutValue<'template>) -> Result<(), KutError> {
+    if let KutValue::List(items) = value {
+        for item in items.iter() {
+            vm.memory.track(item)?;
+        }
+    }
+    vm.memory.track(value)
+}
+
+fn function<'template, Args>(name: &'static str, function: impl KutTypedNative<Args> + 'static) -> (&'static str, KutValue<'template>) {
+    sized(name, |_| 0, function)
+}
+
+/// Like `function`, for a function whose result takes at least `size(args)` bytes,
+/// which are reserved before it runs.
+fn sized<'template, Args>(name: &'static str, size: fn(&[KutValue]) -> usize, function: impl KutTypedNative<Args> + 'static) -> (&'static str, KutValue<'template>) {
+    let qualified = format!("string.{name}");
+    let native = KutNativeFunction::new(&qualified.clone(), move |vm, args| {
+        vm.memory.reserve(size(&args))?;
+        let value = function.call_typed(&qualified, args)?;
+        track_result(vm, &value)?;
+        Ok(value)
+    });
+    (name, KutValue::Native(Rc::new(native)))
+}
+
+/// The string argument `index`, or an empty string when it is not a string, which
+/// the conversion of the arguments then reports.
+fn text<'a>(args: &'a [KutValue], index: usize) -> &'a str {
+    match args.get(index) {
+        Some(KutValue::String(string)) => string,
+        _ => "",
+    }
+}
+
+/// Size of a list of `count` strings of `bytes` bytes in total.
+fn strings_size(count: usize, bytes: usize) -> usize {
+    KutMemory::list_size(count).saturating_add(count.saturating_mul(KutMemory::string_size(0))).saturating_add(bytes)
+}
+
+fn replace_size(args: &[KutValue]) -> usize {
+    let (string, from, to) = (text(args, 0), text(args, 1), text(args, 2));
+    let mut count = string.matches(from).count();
+    if let Some(KutValue::Integer(limit)) = args.get(3) {
+        count = count.min(usize::try_from(*limit).unwrap_or(0));
+    }
+    KutMemory::string_size((string.len() - count * from.len()).saturating_add(count.saturating_mul(to.len())))
+}
+
+fn split_size(args: &[KutValue]) -> usize {
+    let string = text(args, 0);
+    let count = match args.get(1) {
+        Some(KutValue::String(separator)) if !separator.is_empty() => string.matches(separator.as_str()).count() + 1,
+        _ => string.split_whitespace().count(),
+    };
+    strings_size(count, string.len())
+}
+
+fn join_size(args: &[KutValue]) -> usize {
+    let Some(KutValue::List(items)) = args.first() else {
+        return 0;
+    };
+    let separators = items.len().saturating_sub(1).saturating_mul(text(args, 1).len());
+    KutMemory::string_size(items.iter().map(|item| if let KutValue::String(item) = item { item.len() } else { 0 }).fold(separators, usize::saturating_add))
+}
+
+fn sub(string: Rc<String>, start: i64, end: Option<i64>) -> Result<String, KutError> {
+    let start = byte_offset(&string, start)?;
+    let end = match end {
+        Some(end) => byte_offset(&string, end)?.max(start),
+        None => string.len(),
+    };
+    Ok(string[start..end].to_owned())
+}
+
+fn find(string: Rc<String>, needle: Rc<String>, start: Option<i64>) -> Result<Option<i64>, KutError> {
+    let start = byte_offset(&string, start.unwrap_or(0))?;
+    Ok(string[start..].find(needle.as_str()).map(|found| string[..start + found].chars().count() as i64))
+}
+
+fn replace(string: Rc<String>, from: Rc<String>, to: Rc<String>, count: Option<i64>) -> Result<String, KutError> {
+    match count {
+        None => Ok(string.replace(from.as_str(), &to)),
+        Some(count) if count >= 0 => Ok(string.replacen(from.as_str(), &to, count as usize)),
+        Some(count) => Err(invalid("replace", &format!("negative count {count}"))),
+    }
+}
+
+fn split(string: Rc<String>, separator: Option<Rc<String>>) -> Result<Vec<String>, KutError> {
+    match separator {
+        None => Ok(string.split_whitespace().map(str::to_owned).collect()),
+        Some(separator) if separator.is_empty() => Err(invalid("split", "empty separator")),
+        Some(separator) => Ok(string.split(separator.as_str()).map(str::to_owned).collect()),
+    }
+}
+
+fn join(items: Vec<Rc<String>>, separator: Option<Rc<String>>) -> Result<String, KutError> {
+    let items: Vec<&str> = items.iter().map(|item| item.as_str()).collect();
+    Ok(items.join(separator.as_ref().map_or("", |separator| separator.as_str())))
+}
+
+fn from_codepoints(codepoints: Vec<i64>) -> Result<String, KutError> {
+    codepoints
+        .into_iter()
+        .map(|code| u32::try_from(code).ok().and_then(char::from_u32).ok_or_else(|| invalid("from_codepoints", &format!("codepoint {code}"))))
+        .collect()
+}
+
+fn repeat<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    let (string, count) = <(Rc<String>, i64)>::from_kut(args.into_kut())?;
+    let count = usize::try_from(count).map_err(|_| invalid("repeat", &format!("negative count {count}")))?;
+    let requested = string.len().checked_mul(count).ok_or(KutError::IntegerOverflow { operation: "string.repeat".to_owned() })?;
+    vm.memory.reserve(KutMemory::string_size(requested))?;
+    let value = string.repeat(count).into_kut();
+    vm.memory.track(&value)?;
+    Ok(value)
+}
+
+/// Replaces every `{}` in the first argument with the display form of the next
+/// argument. `{{` and `}}` stand for literal braces. The output is reserved as it
+/// grows, since its size is only known once every argument is displayed.
+fn format<'template>(vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+    let mut args = args.into_iter();
+    let template = Rc::<String>::from_kut(args.next().unwrap_or(KutValue::Nil))?;
+    vm.memory.reserve(KutMemory::string_size(template.len()))?;
+    let mut output = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                output.push(c);
+            },
+            ('{', Some('}')) => {
+                chars.next();
+                let arg = args.next().ok_or_else(|| invalid("format", "template with more {} than arguments"))?;
+                output.push_str(&arg.to_display_string());
+                vm.memory.reserve(KutMemory::string_size(output.len()))?;
+            },
+            ('{' | '}', _) => return Err(invalid("format", "template with an unmatched brace")),
+            _ => output.push(c),
+        }
+    }
+    if args.next().is_some() {
+        return Err(invalid("format", "template with fewer {} than arguments"));
+    }
+    let value = output.into_kut();
+    vm.memory.track(&value)?;
+    Ok(value)
+}
+
+/// Exports of the `string` module. Every index and length counts Unicode scalar
+/// values, except `byte_len`, which counts UTF-8 bytes. Negative indices count from
+/// the end and an index outside of the string is a `KutError::OutOfRangeIndex`.
+pub fn exports<'template>() -> Vec<(&'static str, KutValue<'template>)> {
+    vec![
+        function("len", |string: Rc<String>| Ok(string.chars().count() as i64)),
+        function("byte_len", |string: Rc<String>| Ok(string.len() as i64)),
+        function("sub", sub),
+        function("find", find),
+        function("contains", |string: Rc<String>, needle: Rc<String>| Ok(string.contains(needle.as_str()))),
+        sized("replace", replace_size, replace),
+        sized("split", split_size, split),
+        sized("join", join_size, join),
+        function("trim", |string: Rc<String>| Ok(string.trim().to_owned())),
+        function("trim_start", |string: Rc<String>| Ok(string.trim_start().to_owned())),
+        function("trim_end", |string: Rc<String>| Ok(string.trim_end().to_owned())),
+        sized("upper", |args| KutMemory::string_size(text(args, 0).len()), |string: Rc<String>| Ok(string.to_uppercase())),
+        sized("lower", |args| KutMemory::string_size(text(args, 0).len()), |string: Rc<String>| Ok(string.to_lowercase())),
+        function("starts_with", |string: Rc<String>, prefix: Rc<String>| Ok(string.starts_with(prefix.as_str()))),
+        function("ends_with", |string: Rc<String>, suffix: Rc<String>| Ok(string.ends_with(suffix.as_str()))),
+        sized("chars", |args| strings_size(text(args, 0).chars().count(), text(args, 0).len()), |string: Rc<String>| Ok(string.chars().map(String::from).collect::<Vec<_>>())),
+        sized("codepoints", |args| KutMemory::list_size(text(args, 0).chars().count()), |string: Rc<String>| Ok(string.chars().map(|c| c as i64).collect::<Vec<_>>())),
+        sized("from_codepoints", |args| if let Some(KutValue::List(codepoints)) = args.first() { KutMemory::string_size(codepoints.len()) } else { 0 }, from_codepoints),
+        ("repeat", KutValue::Native(Rc::new(KutNativeFunction::new("string.repeat", repeat)))),
+        ("format", KutValue::Native(Rc::new(KutNativeFunction::new("string.format", format)))),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call<'template>(vm: &'template KutVm<'template>, name: &str, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+        let Some((_, KutValue::Native(function))) = exports().into_iter().find(|(export, _)| *export == name) else {
+            panic!("string.{name} is not exported");
+        };
+        function.call(vm, args)
+    }
+
+    fn text<'template>(text: &str) -> KutValue<'template> {
+        text.to_owned().into_kut()
+    }
+
+    /// A VM with 1000 bytes of headroom left under its limit of 4000.
+    fn limited_vm<'template>() -> (KutVm<'template>, KutValue<'template>) {
+        let vm = KutVm::new(vec![], vec![]);
+        let held = text(&"x".repeat(3000 - KutMemory::string_size(0)));
+        vm.memory.track(&held).unwrap();
+        vm.memory.set_limit(Some(4000));
+        (vm, held)
+    }
+
+    #[test]
+    fn results_are_reserved_against_the_headroom() {
+        let (vm, _held) = limited_vm();
+        let vm = &vm;
+        let long = text(&"a".repeat(1200));
+        assert!(matches!(call(vm, "repeat", vec![text("ab"), KutValue::Integer(600)]), Err(KutError::OutOfMemory { .. })));
+        assert!(matches!(call(vm, "upper", vec![long.clone()]), Err(KutError::OutOfMemory { .. })));
+        assert!(matches!(call(vm, "replace", vec![text(&"a".repeat(100)), text("a"), text("bbbbbbbbbbbb")]), Err(KutError::OutOfMemory { .. })));
+        assert!(matches!(call(vm, "split", vec![text(&"a,".repeat(100)), text(",")]), Err(KutError::OutOfMemory { .. })));
+        assert!(matches!(call(vm, "chars", vec![text(&"a".repeat(100))]), Err(KutError::OutOfMemory { .. })));
+        assert!(matches!(call(vm, "format", vec![text("{} {}"), long.clone(), long]), Err(KutError::OutOfMemory { .. })));
+        assert!(call(vm, "repeat", vec![text("ab"), KutValue::Integer(100)]).is_ok());
+        assert!(vm.memory.peak() <= 4000);
+    }
+
+    fn strings(value: Result<KutValue, KutError>) -> Vec<String> {
+        Vec::<String>::from_kut(value.unwrap()).unwrap()
+    }
+
+    fn is_out_of_range(result: Result<KutValue, KutError>, wanted: i64) -> bool {
+        matches!(result, Err(KutError::OutOfRangeIndex { index, length: 5 }) if index == wanted)
+    }
+
+    #[test]
+    fn sub_counts_characters_from_either_end() {
+        let vm = &KutVm::new(vec![], vec![]);
+        let sub = |start: i64, end: Option<i64>| call(vm, "sub", vec![text("héllø"), KutValue::Integer(start), end.into_kut()]);
+        assert_eq!(String::from_kut(sub(1, Some(4)).unwrap()).unwrap(), "éll");
+        assert_eq!(String::from_kut(sub(-2, None).unwrap()).unwrap(), "lø");
+        assert_eq!(String::from_kut(sub(-5, Some(-4)).unwrap()).unwrap(), "h");
+        assert_eq!(String::from_kut(sub(5, None).unwrap()).unwrap(), "");
+        assert_eq!(String::from_kut(sub(3, Some(1)).unwrap()).unwrap(), "");
+        assert!(is_out_of_range(sub(6, None), 6));
+        assert!(is_out_of_range(sub(-6, None), -6));
+        assert!(is_out_of_range(sub(0, Some(9)), 9));
+        let error = call(vm, "sub", vec![text("héllø")]).unwrap_err();
+        assert!(matches!(error, KutError::WrongArgumentCount { min: 2, max: Some(3), given: 1, .. }), "{error:?}");
+    }
+
+    #[test]
+    fn find_returns_character_indices() {
+        let vm = &KutVm::new(vec![], vec![]);
+        let find = |needle: &str, start: Option<i64>| call(vm, "find", vec![text("héllø"), text(needle), start.into_kut()]);
+        assert!(matches!(find("l", None), Ok(KutValue::Integer(2))));
+        assert!(matches!(find("l", Some(3)), Ok(KutValue::Integer(3))));
+        assert!(matches!(find("ø", Some(-1)), Ok(KutValue::Integer(4))));
+        assert!(matches!(find("é", Some(2)), Ok(KutValue::Nil)));
+        assert!(matches!(find("", Some(5)), Ok(KutValue::Integer(5))));
+        assert!(is_out_of_range(find("l", Some(6)), 6));
+        assert!(is_out_of_range(find("l", Some(-7)), -7));
+    }
+
+    #[test]
+    fn chars_and_codepoints_split_multibyte_characters() {
+        let vm = &KutVm::new(vec![], vec![]);
+        assert_eq!(strings(call(vm, "chars", vec![text("a€😀")])), ["a", "€", "😀"]);
+        assert!(strings(call(vm, "chars", vec![text("")])).is_empty());
+        assert_eq!(Vec::<i64>::from_kut(call(vm, "codepoints", vec![text("a€")]).unwrap()).unwrap(), [97, 8364]);
+        assert!(matches!(call(vm, "len", vec![text("a€😀")]), Ok(KutValue::Integer(3))));
+        assert!(matches!(call(vm, "byte_len", vec![text("a€😀")]), Ok(KutValue::Integer(8))));
+        let error = call(vm, "from_codepoints", vec![vec![0xD800_i64].into_kut()]).unwrap_err();
+        assert!(matches!(error, KutError::InvalidOperand { .. }), "{error:?}");
+    }
+
+    #[test]
+    fn split_rejects_an_empty_separator() {
+        let vm = &KutVm::new(vec![], vec![]);
+        assert_eq!(strings(call(vm, "split", vec![text("a,,b"), text(",")])), ["a", "", "b"]);
+        assert_eq!(strings(call(vm, "split", vec![text("  a \n b ")])), ["a", "b"]);
+        let error = call(vm, "split", vec![text("ab"), text("")]).unwrap_err();
+        assert!(matches!(&error, KutError::InvalidOperand { operation, value_type } if operation == "string.split" && value_type == "empty separator"), "{error:?}");
+    }
+
+    #[test]
+    fn format_checks_its_template_against_the_arguments() {
+        let vm = &KutVm::new(vec![], vec![]);
+        let formatted = call(vm, "format", vec![text("{{{}}} = {}"), text("x"), KutValue::Integer(1)]).unwrap();
+        assert_eq!(String::from_kut(formatted).unwrap(), "{x} = 1");
+        for (template, args, message) in [
+            ("{} {}", vec![KutValue::Integer(1)], "template with more {} than arguments"),
+            ("{}", vec![KutValue::Integer(1), KutValue::Integer(2)], "template with fewer {} than arguments"),
+            ("{", vec![], "template with an unmatched brace"),
+            ("a } b", vec![], "template with an unmatched brace"),
+        ] {
+            let mut args = args;
+            args.insert(0, text(template));
+            let error = call(vm, "format", args).unwrap_err();
+            assert!(matches!(&error, KutError::InvalidOperand { value_type, .. } if value_type == message), "{template}: {error:?}");
+        }
+        let error = call(vm, "format", vec![KutValue::Integer(1)]).unwrap_err();
+        assert!(matches!(error, KutError::InvalidConversion { .. }), "{error:?}");
+    }
+}
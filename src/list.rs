@@ -1,4 +1,370 @@
-pub mod list {
-    use crate::value::*;
-    use std::rc::Rc;
-}
\ No newline at end of file
+use crate::value::*;
+use std::fmt;
+use std::rc::Rc;
+
+const BITS: u32 = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+#[derive(Debug, Clone)]
+pub enum KutVectorNode<'template> {
+    Branch(Vec<Rc<KutVectorNode<'template>>>),
+    Leaf(Vec<KutValue<'template>>),
+}
+
+/// Persistent vector as a bitmapped trie of 32-way nodes, plus a tail leaf that is
+/// not yet in the trie. Every update copies only the O(log n) nodes on the path
+/// to the changed item and shares the rest with the vector it was made from.
+#[derive(Clone)]
+pub struct KutVector<'template> {
+    len: usize,
+    shift: u32,
+    root: Rc<KutVectorNode<'template>>,
+    tail: Rc<KutVectorNode<'template>>,
+}
+
+pub struct KutVectorIter<'vector, 'template> {
+    vector: &'vector KutVector<'template>,
+    index: usize,
+    leaf: &'vector [KutValue<'template>],
+}
+
+impl<'template> KutVectorNode<'template> {
+    pub fn children(&self) -> &[Rc<KutVectorNode<'template>>] {
+        match self {
+            KutVectorNode::Branch(children) => children,
+            KutVectorNode::Leaf(_) => &[],
+        }
+    }
+
+    pub fn values(&self) -> &[KutValue<'template>] {
+        match self {
+            KutVectorNode::Branch(_) => &[],
+            KutVectorNode::Leaf(values) => values,
+        }
+    }
+}
+
+impl<'template> Default for KutVector<'template> {
+    fn default() -> Self {
+        KutVector::new()
+    }
+}
+
+impl<'template> KutVector<'template> {
+    pub fn new() -> KutVector<'template> {
+        KutVector {
+            len: 0,
+            shift: BITS,
+            root: Rc::new(KutVectorNode::Branch(vec![])),
+            tail: Rc::new(KutVectorNode::Leaf(vec![])),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn tail_offset(&self) -> usize {
+        if self.len < WIDTH {
+            0
+        } else {
+            ((self.len - 1) >> BITS) << BITS
+        }
+    }
+
+    fn leaf_for(&self, index: usize) -> &Rc<KutVectorNode<'template>> {
+        if index >= self.tail_offset() {
+            return &self.tail;
+        }
+        let mut node = &self.root;
+        let mut level = self.shift;
+        while level > 0 {
+            node = &node.children()[(index >> level) & MASK];
+            level -= BITS;
+        }
+        node
+    }
+
+    pub fn get(&self, index: usize) -> Option<&KutValue<'template>> {
+        if index < self.len {
+            Some(&self.leaf_for(index).values()[index & MASK])
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> KutVectorIter<'_, 'template> {
+        KutVectorIter { vector: self, index: 0, leaf: &[] }
+    }
+
+    pub fn push(&self, value: KutValue<'template>) -> KutVector<'template> {
+        let mut vector = self.clone();
+        vector.push_mut(value);
+        vector
+    }
+
+    fn push_mut(&mut self, value: KutValue<'template>) {
+        if self.len - self.tail_offset() < WIDTH {
+            if let KutVectorNode::Leaf(values) = Rc::make_mut(&mut self.tail) {
+                values.push(value);
+            }
+        } else {
+            let tail = std::mem::replace(&mut self.tail, Rc::new(KutVectorNode::Leaf(vec![value])));
+            if (self.len >> BITS) > (1 << self.shift) {
+                let path = KutVector::new_path(self.shift, tail);
+                self.root = Rc::new(KutVectorNode::Branch(vec![Rc::clone(&self.root), path]));
+                self.shift += BITS;
+            } else {
+                self.root = self.push_tail(self.shift, &self.root, tail);
+            }
+        }
+        self.len += 1;
+    }
+
+    fn new_path(level: u32, node: Rc<KutVectorNode<'template>>) -> Rc<KutVectorNode<'template>> {
+        if level == 0 {
+            node
+        } else {
+            Rc::new(KutVectorNode::Branch(vec![KutVector::new_path(level - BITS, node)]))
+        }
+    }
+
+    fn push_tail(&self, level: u32, parent: &KutVectorNode<'template>, tail: Rc<KutVectorNode<'template>>) -> Rc<KutVectorNode<'template>> {
+        let position = ((self.len - 1) >> level) & MASK;
+        let mut children = parent.children().to_vec();
+        let node = if level == BITS {
+            tail
+        } else if let Some(child) = parent.children().get(position) {
+            self.push_tail(level - BITS, child, tail)
+        } else {
+            KutVector::new_path(level - BITS, tail)
+        };
+        if position < children.len() {
+            children[position] = node;
+        } else {
+            children.push(node);
+        }
+        Rc::new(KutVectorNode::Branch(children))
+    }
+
+    pub fn set(&self, index: usize, value: KutValue<'template>) -> Option<KutVector<'template>> {
+        if index >= self.len {
+            return None;
+        }
+        let mut vector = self.clone();
+        if index >= self.tail_offset() {
+            let mut values = self.tail.values().to_vec();
+            values[index & MASK] = value;
+            vector.tail = Rc::new(KutVectorNode::Leaf(values));
+        } else {
+            vector.root = KutVector::assoc(self.shift, &self.root, index, value);
+        }
+        Some(vector)
+    }
+
+    fn assoc(level: u32, node: &KutVectorNode<'template>, index: usize, value: KutValue<'template>) -> Rc<KutVectorNode<'template>> {
+        if level == 0 {
+            let mut values = node.values().to_vec();
+            values[index & MASK] = value;
+            Rc::new(KutVectorNode::Leaf(values))
+        } else {
+            let position = (index >> level) & MASK;
+            let mut children = node.children().to_vec();
+            children[position] = KutVector::assoc(level - BITS, &children[position], index, value);
+            Rc::new(KutVectorNode::Branch(children))
+        }
+    }
+
+    /// Returns the vector without its last item, or `None` if it is empty.
+    pub fn pop(&self) -> Option<KutVector<'template>> {
+        match self.len {
+            0 => return None,
+            1 => return Some(KutVector::new()),
+            _ => {},
+        }
+        let mut vector = self.clone();
+        vector.len -= 1;
+        if self.len - self.tail_offset() > 1 {
+            let mut values = self.tail.values().to_vec();
+            values.pop();
+            vector.tail = Rc::new(KutVectorNode::Leaf(values));
+            return Some(vector);
+        }
+        vector.tail = Rc::clone(self.leaf_for(self.len - 2));
+        let mut root = self.pop_tail(self.shift, &self.root).unwrap_or_else(|| Rc::new(KutVectorNode::Branch(vec![])));
+        if self.shift > BITS && root.children().len() == 1 {
+            root = Rc::clone(&root.children()[0]);
+            vector.shift -= BITS;
+        }
+        vector.root = root;
+        Some(vector)
+    }
+
+    fn pop_tail(&self, level: u32, node: &KutVectorNode<'template>) -> Option<Rc<KutVectorNode<'template>>> {
+        let position = ((self.len - 2) >> level) & MASK;
+        let mut children = node.children().to_vec();
+        if level > BITS {
+            match self.pop_tail(level - BITS, &children[position]) {
+                None if position == 0 => return None,
+                None => children.truncate(position),
+                Some(child) => children[position] = child,
+            }
+        } else if position == 0 {
+            return None;
+        } else {
+            children.truncate(position);
+        }
+        Some(Rc::new(KutVectorNode::Branch(children)))
+    }
+
+    /// Calls `visit` on every node that no other vector shares with this one. Right
+    /// after an update, while the vector it was made from is alive, these are
+    /// exactly the nodes the update allocated.
+    pub fn for_each_unshared_node(&self, visit: &mut impl FnMut(&Rc<KutVectorNode<'template>>)) {
+        fn walk<'template>(node: &Rc<KutVectorNode<'template>>, visit: &mut impl FnMut(&Rc<KutVectorNode<'template>>)) {
+            if Rc::strong_count(node) == 1 {
+                visit(node);
+                for child in node.children() {
+                    walk(child, visit);
+                }
+            }
+        }
+        walk(&self.root, visit);
+        walk(&self.tail, visit);
+    }
+}
+
+impl<'template> FromIterator<KutValue<'template>> for KutVector<'template> {
+    fn from_iter<T: IntoIterator<Item = KutValue<'template>>>(iter: T) -> Self {
+        let mut vector = KutVector::new();
+        for value in iter {
+            vector.push_mut(value);
+        }
+        vector
+    }
+}
+
+impl<'vector, 'template> Iterator for KutVectorIter<'vector, 'template> {
+    type Item = &'vector KutValue<'template>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.vector.len {
+            return None;
+        }
+        if self.index & MASK == 0 {
+            self.leaf = self.vector.leaf_for(self.index).values();
+        }
+        let value = &self.leaf[self.index & MASK];
+        self.index += 1;
+        Some(value)
+    }
+}
+
+impl<'template> fmt::Debug for KutVector<'template> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sizes around the limits of the tail and of one, two and three trie levels.
+    const BOUNDARIES: [usize; 19] = [0, 1, 31, 32, 33, 63, 64, 65, 1055, 1056, 1057, 1088, 1089, 32767, 32768, 32769, 32800, 32801, 33000];
+
+    fn integer(value: &KutValue) -> i64 {
+        match value {
+            KutValue::Integer(int) => *int,
+            other => panic!("expected an integer, found {other:?}"),
+        }
+    }
+
+    /// Checks `vector` against `model` through `len`, `get` and `iter`.
+    fn assert_same(vector: &KutVector, model: &[i64]) {
+        assert_eq!(vector.len(), model.len());
+        assert_eq!(vector.is_empty(), model.is_empty());
+        assert_eq!(vector.iter().map(integer).collect::<Vec<_>>(), model);
+        for (index, expected) in model.iter().enumerate() {
+            assert_eq!(vector.get(index).map(integer), Some(*expected), "item {index} of {}", model.len());
+        }
+        assert!(vector.get(model.len()).is_none());
+    }
+
+    /// Pushes up to the largest boundary, keeping the vector at every boundary.
+    fn versions<'template>() -> Vec<(KutVector<'template>, Vec<i64>)> {
+        let mut vector = KutVector::new();
+        let mut model = vec![];
+        let mut versions = vec![];
+        for size in BOUNDARIES {
+            while model.len() < size {
+                vector = vector.push(KutValue::Integer(model.len() as i64));
+                model.push(model.len() as i64);
+            }
+            versions.push((vector.clone(), model.clone()));
+        }
+        versions
+    }
+
+    #[test]
+    fn push_agrees_with_vec() {
+        for (vector, model) in versions() {
+            assert_same(&vector, &model);
+        }
+    }
+
+    #[test]
+    fn set_agrees_with_vec() {
+        for (vector, mut model) in versions() {
+            assert!(vector.set(model.len(), KutValue::Nil).is_none());
+            let mut updated = vector;
+            let len = model.len();
+            for index in [0, 31, 32, 1055, 1056, 32767, 32768, len.saturating_sub(1)].into_iter().filter(|index| *index < len) {
+                updated = updated.set(index, KutValue::Integer(-(index as i64))).unwrap();
+                model[index] = -(index as i64);
+            }
+            assert_same(&updated, &model);
+        }
+    }
+
+    #[test]
+    fn pop_agrees_with_vec() {
+        let (mut vector, mut model) = versions().pop().unwrap();
+        while let Some(popped) = vector.pop() {
+            vector = popped;
+            model.pop();
+            if BOUNDARIES.contains(&model.len()) {
+                assert_same(&vector, &model);
+            }
+        }
+        assert!(model.is_empty());
+        assert_same(&vector.push(KutValue::Integer(0)), &[0]);
+    }
+
+    #[test]
+    fn updates_leave_the_original_unchanged() {
+        for (vector, model) in versions() {
+            let pushed = vector.push(KutValue::Integer(-1));
+            let set = (!model.is_empty()).then(|| vector.set(model.len() / 2, KutValue::Integer(-2)).unwrap());
+            let popped = vector.pop();
+            assert_same(&vector, &model);
+            let mut expected = model.clone();
+            expected.push(-1);
+            assert_same(&pushed, &expected);
+            if let Some(set) = set {
+                let mut expected = model.clone();
+                expected[model.len() / 2] = -2;
+                assert_same(&set, &expected);
+                let popped = popped.unwrap();
+                assert_same(&popped, &model[..model.len() - 1]);
+                assert_same(&popped.push(KutValue::Integer(-3)), &[&model[..model.len() - 1], &[-3]].concat());
+                assert_same(&vector, &model);
+            }
+        }
+    }
+}
@@ -1,10 +1,24 @@
+pub mod debugger;
+pub mod execution;
+pub mod global;
+pub mod hook;
+pub mod library;
+pub mod list;
+pub mod memory;
+pub mod module;
 pub mod value;
 pub mod vm;
 use value::*;
 use vm::*;
+use debugger::*;
+use module::*;
+use std::path::Path;
 use std::rc::Rc;
 
-fn main() -> Result<(), String> {
+const USAGE: &str = "usage: kut [debug] [<module>.kutm]";
+
+/// The program that runs when no module file is given.
+fn sample<'template>() -> KutVm<'template> {
     let instructions = vec![
         KutInstruction::GetLiteralR { reg: 0, literal: 0 },
         KutInstruction::GetLiteralR { reg: 1, literal: 1 },
@@ -21,17 +35,45 @@ fn main() -> Result<(), String> {
         KutValue::Number(10.0),
     ];
     let templates = vec![
-        KutFunctionTemplate::new(instructions, vec![], 4),
+        KutFunctionTemplate::new(instructions, vec![], 4).with_name("main"),
         KutFunctionTemplate::new(inner_instructions, vec![KutCaptureInfo::Register(2)], 2),
     ];
-    let vm = KutVm::new(literals, templates);
-    let capture = vm.templates[0].capture(None)?;
-    let mut func = capture.start();
-    func.run(&vm)?;
-    dbg!(&func);
-    // dbg!(&vm);
-    if let KutValue::String(s) = &vm.literals[1] {
-        println!("{}", Rc::strong_count(s));
+    KutVm::new(literals, templates)
+}
+
+/// Links the module file at `path`, whose imports are searched for next to it.
+fn load<'template>(path: &Path) -> Result<(KutVm<'template>, String), String> {
+    let name = path.file_stem().and_then(|stem| stem.to_str()).ok_or_else(|| format!("{} is not a module file", path.display()))?.to_owned();
+    let text = std::fs::read_to_string(path).map_err(|error| format!("cannot read {}: {error}", path.display()))?;
+    let module = KutFileLoader::decode(&name, &text)?.rebind()?;
+    let mut vm = KutVm::new(vec![], vec![]);
+    let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    vm.set_loader(Box::new(KutFileLoader::new(vec![directory.to_path_buf()])));
+    vm.add_module(module)?;
+    Ok((vm, name))
+}
+
+/// Runs the module file given on the command line, or the sample program without
+/// one: the entry of the module first, then its template named `main`, if any,
+/// whose result is printed. `kut debug` runs the same under `KutDebugger`.
+fn main() -> Result<(), String> {
+    let mut args = std::env::args().skip(1).peekable();
+    let debug = args.next_if(|arg| arg == "debug").is_some();
+    let path = args.next();
+    if let Some(extra) = args.next() {
+        return Err(format!("unexpected argument {extra}, {USAGE}"));
+    }
+    let (vm, name) = match &path {
+        Some(path) => load(Path::new(path))?,
+        None => (sample(), "main".to_owned()),
+    };
+    if debug {
+        vm.set_hook(Box::new(KutDebugger::new()));
+    }
+    vm.module_exports(&name)?;
+    if vm.templates.iter().any(|template| template.name.as_deref() == Some("main")) {
+        let value: KutValue = vm.call("main", ())?;
+        println!("{value:#}");
     }
-    return Ok(());
+    Ok(())
 }
@@ -0,0 +1,250 @@
+use crate::value::*;
+use crate::list::*;
+use std::cell::{Cell, RefCell};
+use std::mem::size_of;
+use std::rc::{Rc, Weak};
+
+const RC_HEADER: usize = 2 * size_of::<usize>();
+
+#[derive(Debug)]
+enum KutAllocation<'template> {
+    String(Weak<String>),
+    List(Weak<Vec<KutValue<'template>>>),
+    Func(Weak<KutClosure<'template>>),
+    Reference(Weak<RefCell<KutValue<'template>>>),
+    Map(Weak<KutMap<'template>>),
+    Coroutine(Weak<KutCoroutine<'template>>),
+    Iterator(Weak<KutIterator<'template>>),
+    Vector(Weak<KutVector<'template>>),
+    VectorNode(Weak<KutVectorNode<'template>>),
+}
+
+impl<'template> KutAllocation<'template> {
+    fn is_live(&self) -> bool {
+        match self {
+            KutAllocation::String(weak) => weak.strong_count() > 0,
+            KutAllocation::List(weak) => weak.strong_count() > 0,
+            KutAllocation::Func(weak) => weak.strong_count() > 0,
+            KutAllocation::Reference(weak) => weak.strong_count() > 0,
+            KutAllocation::Map(weak) => weak.strong_count() > 0,
+            KutAllocation::Coroutine(weak) => weak.strong_count() > 0,
+            KutAllocation::Iterator(weak) => weak.strong_count() > 0,
+            KutAllocation::Vector(weak) => weak.strong_count() > 0,
+            KutAllocation::VectorNode(weak) => weak.strong_count() > 0,
+        }
+    }
+}
+
+/// Accounts the heap values allocated by a `KutVm`.
+///
+/// Every allocation is remembered through a `Weak`, and the bytes of the ones
+/// that have been dropped are given back lazily, when the limit would be
+/// exceeded, when the usage is queried or when the table has doubled.
+#[derive(Debug, Default)]
+pub struct KutMemory<'template> {
+    allocations: RefCell<Vec<(KutAllocation<'template>, usize)>>,
+    swept_count: Cell<usize>,
+    used: Cell<usize>,
+    peak: Cell<usize>,
+    limit: Cell<Option<usize>>,
+}
+
+impl<'template> KutMemory<'template> {
+    pub fn new() -> KutMemory<'template> {
+        KutMemory::default()
+    }
+
+    /// Estimated number of heap bytes owned by `value` itself, not counting the values it holds
+    /// or, for a `Vector`, its trie nodes.
+    pub fn size_of(value: &KutValue) -> usize {
+        match value {
+            KutValue::String(string) => KutMemory::string_size(string.capacity()),
+            KutValue::List(list) => KutMemory::list_size(list.capacity()),
+            KutValue::Func(func) => RC_HEADER + size_of::<KutClosure>() + func.captures.capacity() * size_of::<KutValue>(),
+            KutValue::Reference(_) => RC_HEADER + size_of::<RefCell<KutValue>>(),
+            KutValue::Map(map) => RC_HEADER + size_of::<KutMap>() + map.capacity() * 2 * (size_of::<KutKey>() + size_of::<KutValue>()),
+            KutValue::Vector(_) => RC_HEADER + size_of::<KutVector>(),
+            KutValue::Iterator(_) => RC_HEADER + size_of::<KutIterator>(),
+            KutValue::Coroutine(coroutine) => {
+                RC_HEADER + size_of::<KutCoroutine>() + size_of::<KutFunction>() + coroutine.closure.template.register_count as usize * size_of::<KutValue>()
+            },
+            _ => 0,
+        }
+    }
+
+    /// Estimated size of a `String` value of `bytes` bytes.
+    pub fn string_size(bytes: usize) -> usize {
+        (RC_HEADER + size_of::<String>()).saturating_add(bytes)
+    }
+
+    /// Estimated size of a `List` value of `len` items, not counting the items.
+    pub fn list_size(len: usize) -> usize {
+        (RC_HEADER + size_of::<Vec<KutValue>>()).saturating_add(len.saturating_mul(size_of::<KutValue>()))
+    }
+
+    /// Fails like `track` when `requested` more bytes do not fit under the limit, but
+    /// accounts nothing. Natives call it with the size of a value before building it,
+    /// so that an oversized value fails before it is allocated.
+    pub fn reserve(&self, requested: usize) -> Result<(), KutError> {
+        if let Some(limit) = self.limit.get() {
+            if self.used.get().saturating_add(requested) > limit {
+                self.sweep();
+                if self.used.get().saturating_add(requested) > limit {
+                    return Err(KutError::OutOfMemory { requested, used: self.used.get(), limit });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Accounts a freshly allocated `value`, failing if it does not fit under the limit. A
+    /// `Vector` must be tracked while the vector it was updated from is still alive, so
+    /// that only its new trie nodes are accounted.
+    pub fn track(&self, value: &KutValue<'template>) -> Result<(), KutError> {
+        if let KutValue::Vector(vector) = value {
+            let mut result = Ok(());
+            vector.for_each_unshared_node(&mut |node| {
+                if result.is_ok() {
+                    let size = RC_HEADER + size_of::<KutVectorNode>() + std::mem::size_of_val(node.children()) + std::mem::size_of_val(node.values());
+                    result = self.track_allocation(KutAllocation::VectorNode(Rc::downgrade(node)), size);
+                }
+            });
+            result?;
+        }
+        self.track_bytes(value, KutMemory::size_of(value))
+    }
+
+    /// Accounts the bytes an already tracked `value` has grown by since it was `old_size` bytes.
+    pub fn track_growth(&self, value: &KutValue<'template>, old_size: usize) -> Result<(), KutError> {
+        match KutMemory::size_of(value).checked_sub(old_size) {
+            Some(grown) if grown > 0 => self.track_bytes(value, grown),
+            _ => Ok(()),
+        }
+    }
+
+    fn track_bytes(&self, value: &KutValue<'template>, size: usize) -> Result<(), KutError> {
+        let allocation = match value {
+            KutValue::Vector(vector) => KutAllocation::Vector(Rc::downgrade(vector)),
+            KutValue::String(string) => KutAllocation::String(Rc::downgrade(string)),
+            KutValue::List(list) => KutAllocation::List(Rc::downgrade(list)),
+            KutValue::Func(func) => KutAllocation::Func(Rc::downgrade(func)),
+            KutValue::Reference(r) => KutAllocation::Reference(Rc::downgrade(r)),
+            KutValue::Map(map) => KutAllocation::Map(Rc::downgrade(map)),
+            KutValue::Coroutine(coroutine) => KutAllocation::Coroutine(Rc::downgrade(coroutine)),
+            KutValue::Iterator(iterator) => KutAllocation::Iterator(Rc::downgrade(iterator)),
+            _ => return Ok(()),
+        };
+        self.track_allocation(allocation, size)
+    }
+
+    fn track_allocation(&self, allocation: KutAllocation<'template>, size: usize) -> Result<(), KutError> {
+        self.reserve(size)?;
+        if self.allocations.borrow().len() >= 2 * self.swept_count.get().max(64) {
+            self.sweep();
+        }
+        self.allocations.borrow_mut().push((allocation, size));
+        self.used.set(self.used.get() + size);
+        self.peak.set(self.peak.get().max(self.used.get()));
+        Ok(())
+    }
+
+    pub fn used(&self) -> usize {
+        self.sweep();
+        self.used.get()
+    }
+
+    pub fn peak(&self) -> usize {
+        self.peak.get()
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit.get()
+    }
+
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit.set(limit);
+    }
+
+    fn sweep(&self) {
+        let mut allocations = self.allocations.borrow_mut();
+        let mut freed = 0;
+        allocations.retain(|(allocation, size)| {
+            let live = allocation.is_live();
+            if !live {
+                freed += size;
+            }
+            live
+        });
+        self.used.set(self.used.get() - freed);
+        self.swept_count.set(allocations.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string<'template>(bytes: usize) -> KutValue<'template> {
+        KutValue::String(Rc::new("x".repeat(bytes)))
+    }
+
+    #[test]
+    fn track_fails_over_the_limit_without_accounting() {
+        let memory = KutMemory::new();
+        let (small, large) = (string(100), string(150));
+        memory.set_limit(Some(KutMemory::size_of(&small) + KutMemory::size_of(&large) - 1));
+        memory.track(&small).unwrap();
+        let error = memory.track(&large).unwrap_err();
+        assert!(matches!(error, KutError::OutOfMemory { requested, used, .. } if requested == KutMemory::size_of(&large) && used == KutMemory::size_of(&small)), "{error:?}");
+        assert_eq!(memory.used(), KutMemory::size_of(&small));
+        assert!(memory.peak() <= memory.limit().unwrap());
+    }
+
+    #[test]
+    fn reserve_checks_the_headroom() {
+        let memory = KutMemory::new();
+        let value = string(100);
+        memory.set_limit(Some(2 * KutMemory::size_of(&value)));
+        memory.track(&value).unwrap();
+        memory.reserve(KutMemory::size_of(&value)).unwrap();
+        assert_eq!(memory.used(), KutMemory::size_of(&value));
+        assert!(matches!(memory.reserve(KutMemory::size_of(&value) + 1), Err(KutError::OutOfMemory { .. })));
+        assert!(matches!(memory.reserve(usize::MAX), Err(KutError::OutOfMemory { .. })));
+    }
+
+    #[test]
+    fn dropped_values_are_given_back() {
+        let memory = KutMemory::new();
+        let kept = string(10);
+        memory.track(&kept).unwrap();
+        let dropped: Vec<_> = (0..1000).map(|_| string(10)).collect();
+        for value in &dropped {
+            memory.track(value).unwrap();
+        }
+        let peak = memory.used();
+        assert_eq!(peak, 1001 * KutMemory::size_of(&kept));
+        drop(dropped);
+        assert_eq!(memory.used(), KutMemory::size_of(&kept));
+        assert_eq!(memory.peak(), peak);
+    }
+
+    #[test]
+    fn the_limit_sweeps_before_failing() {
+        let memory = KutMemory::new();
+        let first = string(100);
+        memory.set_limit(Some(KutMemory::size_of(&first) + 10));
+        memory.track(&first).unwrap();
+        let second = string(100);
+        assert!(matches!(memory.track(&second), Err(KutError::OutOfMemory { .. })));
+        drop(first);
+        memory.track(&second).unwrap();
+        assert_eq!(memory.used(), KutMemory::size_of(&second));
+    }
+
+    #[test]
+    fn lists_are_sized_by_capacity() {
+        let list = KutValue::List(Rc::new(Vec::with_capacity(8)));
+        assert_eq!(KutMemory::size_of(&list), KutMemory::list_size(8));
+        assert_eq!(KutMemory::size_of(&string(3)), KutMemory::string_size(3));
+    }
+}
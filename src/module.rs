@@ -0,0 +1,529 @@
+use crate::value::*;
+use crate::value::convert::*;
+use std::cell::{Cell, OnceCell};
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// A unit of code with its own literal pool and template table. Instructions of
+/// its templates index `literals` and `templates` of the same module. `imports`
+/// names the modules that must be linked before this one, and `exports` names
+/// the templates that `GetModuleRg` hands out, which must not need captures.
+/// `entry` names the template that runs the top level of the module, without
+/// arguments, once on its first import and before its exports are handed out.
+#[derive(Debug)]
+pub struct KutModule<'template> {
+    pub name: String,
+    pub literals: Vec<KutValue<'template>>,
+    pub templates: Vec<KutFunctionTemplate>,
+    pub imports: Vec<String>,
+    pub exports: Vec<(String, u16)>,
+    pub entry: Option<u16>,
+}
+
+/// A module after linking, with the ranges its literals and templates occupy in
+/// the pools of the `KutVm` and its exports and entry as indices into
+/// `KutVm::templates`.
+#[derive(Debug)]
+pub struct KutLinkedModule<'template> {
+    pub name: String,
+    pub literals: Range<usize>,
+    pub templates: Range<usize>,
+    pub exports: Vec<(String, usize)>,
+    pub entry: Option<usize>,
+    pub(crate) entered: Cell<bool>,
+    pub(crate) value: OnceCell<KutValue<'template>>,
+}
+
+impl<'template> KutLinkedModule<'template> {
+    pub(crate) fn native(name: &str, exports: Vec<(&str, KutValue<'template>)>) -> KutLinkedModule<'template> {
+        let mut map = KutMap::new();
+        for (name, value) in exports {
+            map.insert(KutKey(name.into_kut()), value);
+        }
+        KutLinkedModule::new(name, 0..0, 0..0, vec![], None, OnceCell::from(KutValue::Map(Rc::new(map))))
+    }
+
+    pub(crate) fn new(name: &str, literals: Range<usize>, templates: Range<usize>, exports: Vec<(String, usize)>, entry: Option<usize>, value: OnceCell<KutValue<'template>>) -> KutLinkedModule<'template> {
+        KutLinkedModule { name: name.to_owned(), literals, templates, exports, entry, entered: Cell::new(false), value }
+    }
+}
+
+/// Finds modules by name while a `KutVm` links its imports.
+pub trait KutModuleLoader {
+    fn load(&mut self, name: &str) -> Result<KutModule<'static>, KutError>;
+}
+
+impl fmt::Debug for dyn KutModuleLoader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KutModuleLoader")
+    }
+}
+
+/// Loads module `name` from `<directory>/<name>.kutm` in the first directory of
+/// the search path that has it. The file is JSON of the form
+///
+/// ```text
+/// {
+///   "literals": [5, "zort"],
+///   "templates": [{"name": "f", "registers": 2, "captures": [["register", 0]], "instructions": [["GetLiteralR", 0, 1]]}],
+///   "imports": ["util"],
+///   "exports": {"f": 0},
+///   "entry": 0
+/// }
+/// ```
+///
+/// where every instruction is its name followed by its operands in declaration order.
+/// JSON numbers are `Number` literals; an `Integer` literal is written as an object
+/// with the single key `"integer"` and the decimal digits as a string, such as
+/// `{"integer": "-12"}`, also inside list and object literals.
+/// A template may also declare `"parameters": {"required": 1, "defaults": [0], "rest": true}`,
+/// where the defaults are literal indices.
+#[derive(Debug, Clone)]
+pub struct KutFileLoader {
+    pub search_path: Vec<PathBuf>,
+}
+
+impl<'template> KutModule<'template> {
+    pub fn new(name: &str, literals: Vec<KutValue<'template>>, templates: Vec<KutFunctionTemplate>) -> KutModule<'template> {
+        KutModule { name: name.to_owned(), literals, templates, imports: vec![], exports: vec![], entry: None }
+    }
+
+    pub fn with_import(mut self, module: &str) -> KutModule<'template> {
+        self.imports.push(module.to_owned());
+        self
+    }
+
+    pub fn with_export(mut self, name: &str, template: u16) -> KutModule<'template> {
+        self.exports.push((name.to_owned(), template));
+        self
+    }
+
+    pub fn with_entry(mut self, template: u16) -> KutModule<'template> {
+        self.entry = Some(template);
+        self
+    }
+}
+
+impl KutModule<'static> {
+    /// Copies a loaded module so that it can be linked into a VM of any lifetime. A
+    /// loader can only produce plain data literals, so a `Func` or `Reference` literal
+    /// is rejected.
+    pub fn rebind<'template>(self) -> Result<KutModule<'template>, KutError> {
+        fn rebind_value<'template>(name: &str, value: &KutValue<'static>) -> Result<KutValue<'template>, KutError> {
+            Ok(match value {
+                KutValue::Nil => KutValue::Nil,
+                KutValue::Undefined => KutValue::Undefined,
+                KutValue::Bool(b) => KutValue::Bool(*b),
+                KutValue::Integer(int) => KutValue::Integer(*int),
+                KutValue::Number(num) => KutValue::Number(*num),
+                KutValue::String(string) => KutValue::String(Rc::clone(string)),
+                KutValue::List(list) => KutValue::List(Rc::new(list.iter().map(|item| rebind_value(name, item)).collect::<Result<_, _>>()?)),
+                KutValue::Vector(vector) => KutValue::Vector(Rc::new(vector.iter().map(|item| rebind_value(name, item)).collect::<Result<_, _>>()?)),
+                KutValue::Map(map) => {
+                    let mut rebound = KutMap::new();
+                    for (key, value) in map.iter() {
+                        rebound.insert(KutKey(rebind_value(name, &key.0)?), rebind_value(name, value)?);
+                    }
+                    KutValue::Map(Rc::new(rebound))
+                },
+                KutValue::Native(native) => KutValue::Native(Rc::clone(native)),
+                KutValue::External(ext) => KutValue::External(Rc::clone(ext)),
+                other => return Err(KutError::InvalidModule { name: name.to_owned(), message: format!("literal of type {} cannot be loaded", other.get_type_string()) }),
+            })
+        }
+        let literals = self.literals.iter().map(|literal| rebind_value(&self.name, literal)).collect::<Result<_, _>>()?;
+        Ok(KutModule { name: self.name, literals, templates: self.templates, imports: self.imports, exports: self.exports, entry: self.entry })
+    }
+}
+
+impl Default for KutFileLoader {
+    fn default() -> Self {
+        KutFileLoader { search_path: vec![PathBuf::from(".")] }
+    }
+}
+
+impl KutFileLoader {
+    pub fn new(search_path: Vec<PathBuf>) -> KutFileLoader {
+        KutFileLoader { search_path }
+    }
+
+    /// Decodes the JSON module format described on `KutFileLoader`.
+    pub fn decode(name: &str, text: &str) -> Result<KutModule<'static>, KutError> {
+        let invalid = |message: String| KutError::InvalidModule { name: name.to_owned(), message };
+        let field = |map: &KutMap<'static>, key: &str| map.get(&KutKey(key.into_kut())).cloned().unwrap_or(KutValue::Nil);
+        let root = match KutValue::from_json(text)? {
+            KutValue::Map(map) => map,
+            other => return Err(invalid(format!("expected an object, found {}", other.get_type_string()))),
+        };
+        let literals = Option::<Vec<KutValue>>::from_kut(field(&root, "literals"))?.unwrap_or_default()
+            .into_iter()
+            .map(|literal| KutFileLoader::decode_literal(literal).map_err(invalid))
+            .collect::<Result<_, _>>()?;
+        let entry = Option::<u16>::from_kut(field(&root, "entry"))?;
+        let imports = Option::<Vec<String>>::from_kut(field(&root, "imports"))?.unwrap_or_default();
+        let mut exports = vec![];
+        if let KutValue::Map(map) = field(&root, "exports") {
+            for (key, template) in map.iter() {
+                exports.push((String::from_kut(key.0.clone())?, u16::from_kut(template.clone())?));
+            }
+        }
+        let mut templates = vec![];
+        for template in Option::<Vec<KutValue>>::from_kut(field(&root, "templates"))?.unwrap_or_default() {
+            let object = match template {
+                KutValue::Map(map) => map,
+                other => return Err(invalid(format!("expected a template object, found {}", other.get_type_string()))),
+            };
+            let mut capture_infos = vec![];
+            for (kind, index) in Option::<Vec<(String, u16)>>::from_kut(field(&object, "captures"))?.unwrap_or_default() {
+                capture_infos.push(match kind.as_str() {
+                    "register" => KutCaptureInfo::Register(u8::try_from(index).map_err(|_| invalid(format!("register {index} is out of range")))?),
+                    "capture" => KutCaptureInfo::Capture(index),
+                    other => return Err(invalid(format!("unknown capture kind {other}"))),
+                });
+            }
+            let mut instructions = vec![];
+            for instruction in Vec::<Vec<KutValue>>::from_kut(field(&object, "instructions"))? {
+                instructions.push(KutFileLoader::decode_instruction(instruction).map_err(invalid)?);
+            }
+            let register_count = u8::from_kut(field(&object, "registers"))?;
+            let mut template = KutFunctionTemplate::new(instructions, capture_infos, register_count);
+            if let Some(name) = Option::<String>::from_kut(field(&object, "name"))? {
+                template = template.with_name(&name);
+            }
+            if let KutValue::Map(parameters) = field(&object, "parameters") {
+                let parameters = KutParameters {
+                    required: Option::<u8>::from_kut(field(&parameters, "required"))?.unwrap_or(0),
+                    defaults: Option::<Vec<u16>>::from_kut(field(&parameters, "defaults"))?.unwrap_or_default(),
+                    rest: Option::<bool>::from_kut(field(&parameters, "rest"))?.unwrap_or(false),
+                };
+                if parameters.register_count() > register_count as usize {
+                    return Err(invalid(format!("parameters need {} registers when there are {register_count}", parameters.register_count())));
+                }
+                template = template.with_parameters(parameters);
+            }
+            templates.push(template);
+        }
+        Ok(KutModule { name: name.to_owned(), literals, templates, imports, exports, entry })
+    }
+
+    /// Turns the `{"integer": "<digits>"}` objects of a decoded literal into integers.
+    fn decode_literal(literal: KutValue<'static>) -> Result<KutValue<'static>, String> {
+        Ok(match literal {
+            KutValue::Map(map) if map.len() == 1 => match map.get(&KutKey("integer".into_kut())) {
+                Some(KutValue::String(digits)) => KutValue::Integer(digits.parse().map_err(|_| format!("{digits} is not an integer literal"))?),
+                Some(other) => return Err(format!("expected the digits of an integer literal, found {}", other.get_type_string())),
+                None => KutValue::Map(Rc::new(KutFileLoader::decode_entries(&map)?)),
+            },
+            KutValue::Map(map) => KutValue::Map(Rc::new(KutFileLoader::decode_entries(&map)?)),
+            KutValue::List(list) => KutValue::List(Rc::new(list.iter().cloned().map(KutFileLoader::decode_literal).collect::<Result<_, _>>()?)),
+            other => other,
+        })
+    }
+
+    fn decode_entries(map: &KutMap<'static>) -> Result<KutMap<'static>, String> {
+        let mut decoded = KutMap::new();
+        for (key, value) in map.iter() {
+            decoded.insert(key.clone(), KutFileLoader::decode_literal(value.clone())?);
+        }
+        Ok(decoded)
+    }
+
+    fn decode_instruction(instruction: Vec<KutValue<'static>>) -> Result<KutInstruction, String> {
+        let mut operands = instruction.into_iter();
+        let name = match operands.next() {
+            Some(KutValue::String(name)) => name,
+            _ => return Err("expected an instruction name".to_owned()),
+        };
+        fn operand<T: TryFrom<i64>>(operands: &mut impl Iterator<Item = KutValue<'static>>, name: &str, field: &str) -> Result<T, String> {
+            match operands.next().map(i64::from_kut) {
+                Some(Ok(value)) => value.try_into().map_err(|_| format!("operand {field} of {name} is out of range")),
+                _ => Err(format!("expected operand {field} of {name}")),
+            }
+        }
+        macro_rules! decode {
+            ($($variant:ident { $($field:ident),* }),* $(,)?) => {
+                match name.as_str() {
+                    $(stringify!($variant) => Ok(KutInstruction::$variant { $($field: operand(&mut operands, &name, stringify!($field))?),* }),)*
+                    other => Err(format!("unknown instruction {other}")),
+                }
+            };
+        }
+        decode! {
+            NoOperation {},
+            MovRegister { destination, source },
+            CallMethodR { ret_position, arg_count, subject },
+            CallMethodS { arg_count, subject },
+            RetfMethodR { value },
+            RetfMethodS {},
+            RetfMultiRg { first, count },
+            RetfMultiSt { count },
+            CallMultiRg { first, count, arg_count, subject },
+            CallMultiSt { count, arg_count, subject },
+            PushValue1R { val1 },
+            PushValue2R { val1, val2 },
+            PushValue3R { val1, val2, val3 },
+            SwapValuesR { reg1, reg2 },
+            GetLiteralR { reg, literal },
+            GetCaptureR { reg, capture },
+            SetCaptureR { reg, capture },
+            CaptureFunc { reg, template },
+            PushLiteral { literal },
+            PushCapture { capture },
+            PushFuncStk { template },
+            PopCaptureS { capture },
+            NewEmptyMap { reg },
+            MapGetValue { reg, map, key },
+            MapSetValue { map, key, value },
+            MapDelValue { map, key },
+            MapContains { reg, map, key },
+            MapGetKeysR { reg, map },
+            MapGetCount { reg, map },
+            CompareEqlR { reg, lhs, rhs },
+            CompareNeqR { reg, lhs, rhs },
+            CompareLssR { reg, lhs, rhs },
+            CompareLeqR { reg, lhs, rhs },
+            CompareOrdR { reg, lhs, rhs },
+            AddNumbersR { reg, lhs, rhs },
+            SubNumbersR { reg, lhs, rhs },
+            MulNumbersR { reg, lhs, rhs },
+            DivNumbersR { reg, lhs, rhs },
+            ModNumbersR { reg, lhs, rhs },
+            NegateValue { reg, value },
+            IntegerCast { reg, value },
+            NumberCastR { reg, value },
+            JumpAlwaysT { target },
+            JumpIfTrueR { cond, target },
+            JumpIfFalse { cond, target },
+            NewListRegs { reg, first, count },
+            NewListStck { reg, count },
+            ListGetItem { reg, list, index },
+            ListSetItem { list, index, value },
+            ListPushVal { list, value },
+            ListPopValR { reg, list },
+            ListSliceRg { reg, list, start, end },
+            ListLengthR { reg, list },
+            NewEmptyVec { reg },
+            VecFromList { reg, list },
+            VecToListRg { reg, vector },
+            VecGetItemR { reg, vector, index },
+            VecSetItemR { reg, vector, index, value },
+            VecPushValR { reg, vector, value },
+            VecPopLastR { reg, vector },
+            VecLengthRg { reg, vector },
+            ToStringVal { reg, value },
+            ToReprValue { reg, value },
+            JsonEncodeR { reg, value },
+            JsonPrettyR { reg, value },
+            JsonDecodeR { reg, string },
+            GetGlobalRg { reg, name },
+            SetGlobalRg { reg, name },
+            GetModuleRg { reg, name },
+            CoroutineRg { reg, func },
+            CoroResumeR { reg, coroutine, value },
+            CoroYieldRg { reg, value },
+            CoroStatusR { reg, coroutine },
+            IterCreateR { reg, value },
+            IterRangeRg { reg, start, end, step },
+            IterNextReg { reg, iter, target },
+        }
+    }
+}
+
+impl KutModuleLoader for KutFileLoader {
+    fn load(&mut self, name: &str) -> Result<KutModule<'static>, KutError> {
+        if name.split(['/', '\\']).any(|part| part.is_empty() || part == "." || part == "..") {
+            return Err(KutError::InvalidModule { name: name.to_owned(), message: "module names must not contain empty, . or .. components".to_owned() });
+        }
+        for directory in self.search_path.iter() {
+            let path = directory.join(format!("{name}.kutm"));
+            if let Ok(text) = std::fs::read_to_string(&path) {
+                return KutFileLoader::decode(name, &text);
+            }
+        }
+        Err(KutError::ModuleNotFound { name: name.to_owned() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::*;
+    use crate::value::native::*;
+    use crate::vm::*;
+    use std::collections::HashMap;
+    use KutInstruction::*;
+
+    /// Decodes modules from JSON texts held in memory.
+    struct TextLoader(HashMap<&'static str, &'static str>);
+
+    impl KutModuleLoader for TextLoader {
+        fn load(&mut self, name: &str) -> Result<KutModule<'static>, KutError> {
+            match self.0.get(name) {
+                Some(text) => KutFileLoader::decode(name, text),
+                None => Err(KutError::ModuleNotFound { name: name.to_owned() }),
+            }
+        }
+    }
+
+    fn loader(modules: &[(&'static str, &'static str)]) -> Box<TextLoader> {
+        Box::new(TextLoader(modules.iter().copied().collect()))
+    }
+
+    /// `counter` calls the global `tick` and sets the global `ready` to its integer
+    /// literal 7 in its entry. Its export `ready` returns that global, `seven` its literal.
+    const COUNTER: &str = r#"{
+        "literals": ["tick", "ready", {"integer": "7"}],
+        "templates": [
+            {"name": "init", "registers": 2, "instructions": [["GetGlobalRg", 0, 0], ["CallMethodR", 1, 0, 0], ["GetLiteralR", 1, 2], ["SetGlobalRg", 1, 1]]},
+            {"name": "ready", "registers": 1, "instructions": [["GetGlobalRg", 0, 1], ["RetfMethodR", 0]]},
+            {"name": "seven", "registers": 1, "instructions": [["GetLiteralR", 0, 2], ["RetfMethodR", 0]]}
+        ],
+        "exports": {"ready": 1, "seven": 2},
+        "entry": 0
+    }"#;
+
+    /// A VM whose `main` imports `counter` twice and adds the results of its exports, and
+    /// whose global `tick` counts the calls.
+    fn counter_vm<'template>(engine: KutEngine, ticks: &Rc<Cell<u32>>) -> KutVm<'template> {
+        let main = vec![
+            GetModuleRg { reg: 0, name: 0 },
+            GetModuleRg { reg: 1, name: 0 },
+            GetLiteralR { reg: 2, literal: 1 },
+            MapGetValue { reg: 2, map: 1, key: 2 },
+            CallMethodR { ret_position: 3, arg_count: 0, subject: 2 },
+            GetLiteralR { reg: 2, literal: 2 },
+            MapGetValue { reg: 2, map: 0, key: 2 },
+            CallMethodR { ret_position: 4, arg_count: 0, subject: 2 },
+            AddNumbersR { reg: 3, lhs: 3, rhs: 4 },
+            RetfMethodR { value: 3 },
+        ];
+        let literals = ["counter", "ready", "seven"].map(|name| name.into_kut()).to_vec();
+        let mut vm = KutVm::new(literals, vec![KutFunctionTemplate::new(main, vec![], 5).with_name("main")]);
+        vm.engine = engine;
+        vm.set_loader(loader(&[("counter", COUNTER)]));
+        vm.load_module("counter").unwrap();
+        let ticks = Rc::clone(ticks);
+        vm.set_global("tick", KutValue::Native(Rc::new(KutNativeFunction::new("tick", move |_, _| {
+            ticks.set(ticks.get() + 1);
+            Ok(KutValue::Nil)
+        }))));
+        vm
+    }
+
+    #[test]
+    fn entry_runs_once_before_the_exports_are_used() {
+        for engine in [KutEngine::Reference, KutEngine::Threaded] {
+            let ticks = Rc::new(Cell::new(0));
+            let vm = &counter_vm(engine, &ticks);
+            assert_eq!(vm.call::<i64>("main", ()).unwrap(), 14);
+            assert_eq!(vm.call::<i64>("main", ()).unwrap(), 14);
+            vm.module_exports("counter").unwrap();
+            assert_eq!(ticks.get(), 1);
+        }
+    }
+
+    #[test]
+    fn host_import_runs_the_entry() {
+        let ticks = Rc::new(Cell::new(0));
+        let vm = &counter_vm(KutEngine::Reference, &ticks);
+        vm.module_exports("counter").unwrap();
+        assert_eq!(ticks.get(), 1);
+        assert_eq!(vm.get_global::<i64>("ready").unwrap(), 7);
+        assert_eq!(vm.call::<i64>("main", ()).unwrap(), 14);
+        assert_eq!(ticks.get(), 1);
+    }
+
+    #[test]
+    fn entry_resumes_after_running_out_of_fuel() {
+        let ticks = Rc::new(Cell::new(0));
+        let vm = &counter_vm(KutEngine::Reference, &ticks);
+        vm.set_fuel(Some(3));
+        let mut call: KutCallHandle<i64> = vm.start_call("main", ()).unwrap();
+        let result = loop {
+            match call.resume() {
+                Err(KutError::OutOfFuel { .. }) => vm.add_fuel(3),
+                result => break result,
+            }
+        };
+        assert_eq!(result.unwrap(), 14);
+        assert_eq!(ticks.get(), 1);
+    }
+
+    #[test]
+    fn modules_keep_their_own_literal_pools() {
+        let ticks = Rc::new(Cell::new(0));
+        let vm = &counter_vm(KutEngine::Reference, &ticks);
+        let counter = vm.module("counter").unwrap();
+        assert_eq!(counter.literals, 3..6);
+        assert!(matches!(&vm.literals[counter.literals.start + 2], KutValue::Integer(7)));
+        assert!(matches!(vm.literal(&vm.templates[0], 2), Ok(KutValue::String(name)) if **name == "seven"));
+        assert!(matches!(vm.literal(&vm.templates[0], 3), Err(KutError::OutOfRangeLiteral { literal: 3, literal_count: 3 })));
+    }
+
+    #[test]
+    fn integer_literals_are_decoded_exactly() {
+        let text = r#"{"literals": [5, {"integer": "9007199254740993"}, [{"integer": "-1"}], {"a": {"integer": "2"}, "b": 1}, {"integer": 3}]}"#;
+        let error = KutFileLoader::decode("numbers", text).unwrap_err();
+        assert!(matches!(error, KutError::InvalidModule { ref message, .. } if message.contains("digits")), "{error:?}");
+        let text = text.replace(r#", {"integer": 3}"#, "");
+        let module = KutFileLoader::decode("numbers", &text).unwrap();
+        assert!(matches!(module.literals[0], KutValue::Number(num) if num == 5.0));
+        assert!(matches!(module.literals[1], KutValue::Integer(9007199254740993)));
+        assert!(matches!(&module.literals[2], KutValue::List(list) if matches!(list[..], [KutValue::Integer(-1)])));
+        let KutValue::Map(map) = &module.literals[3] else {
+            panic!("expected a map literal");
+        };
+        assert!(matches!(map.get(&KutKey("a".into_kut())), Some(KutValue::Integer(2))));
+        assert!(matches!(map.get(&KutKey("b".into_kut())), Some(KutValue::Number(_))));
+        assert!(KutFileLoader::decode("numbers", r#"{"literals": [{"integer": "1.5"}]}"#).is_err());
+    }
+
+    #[test]
+    fn circular_imports_are_reported() {
+        let mut vm = KutVm::new(vec![], vec![]);
+        vm.set_loader(loader(&[("a", r#"{"imports": ["b"]}"#), ("b", r#"{"imports": ["c"]}"#), ("c", r#"{"imports": ["a"]}"#)]));
+        let error = vm.load_module("a").unwrap_err();
+        assert!(matches!(&error, KutError::CircularImport { chain } if chain == &["a", "b", "c", "a"]), "{error:?}");
+        assert!(vm.module("a").is_none());
+    }
+
+    #[test]
+    fn imports_are_linked_first() {
+        let mut vm = KutVm::new(vec![], vec![]);
+        vm.set_loader(loader(&[("app", r#"{"imports": ["util"]}"#), ("util", "{}")]));
+        let app = vm.load_module("app").unwrap();
+        let util = vm.load_module("util").unwrap();
+        assert!(util < app);
+    }
+
+    #[test]
+    fn missing_modules_and_exports_are_reported() {
+        let mut vm = KutVm::new(vec![], vec![]);
+        vm.set_loader(loader(&[
+            ("export", r#"{"templates": [{"registers": 0, "instructions": []}], "exports": {"f": 1}}"#),
+            ("entry", r#"{"entry": 0}"#),
+        ]));
+        assert!(matches!(vm.load_module("missing"), Err(KutError::ModuleNotFound { .. })));
+        assert!(matches!(vm.load_module("export"), Err(KutError::InvalidModule { message, .. }) if message.contains("export f")));
+        assert!(matches!(vm.load_module("entry"), Err(KutError::InvalidModule { message, .. }) if message.contains("entry")));
+        let vm = &vm;
+        assert!(matches!(vm.module_exports("missing"), Err(KutError::UnlinkedModule { .. })));
+    }
+
+    #[test]
+    fn file_loader_searches_its_directories() {
+        let root = std::env::temp_dir().join(format!("kut-modules-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("second")).unwrap();
+        std::fs::write(root.join("second/util.kutm"), r#"{"literals": [{"integer": "4"}]}"#).unwrap();
+        let mut loader = KutFileLoader::new(vec![root.join("first"), root.join("second")]);
+        let module = loader.load("util");
+        let missing = loader.load("other");
+        let escape = loader.load("../second/util");
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(matches!(module.unwrap().literals[..], [KutValue::Integer(4)]));
+        assert!(matches!(missing, Err(KutError::ModuleNotFound { .. })));
+        assert!(matches!(escape, Err(KutError::InvalidModule { .. })));
+    }
+}
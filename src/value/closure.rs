@@ -1,7 +1,45 @@
 use crate::value::*;
+use crate::vm::*;
 
 impl<'template> KutClosure<'template> {
-    pub fn start<'closure>(&'closure self) -> KutFunction<'closure, 'template> {
-        KutFunction { closure: self, registers: vec![KutValue::Nil; self.template.register_count as usize], call_stack: vec![] }
+    pub fn start(self: &Rc<Self>) -> KutFunction<'template> {
+        KutFunction {
+            closure: Rc::clone(self),
+            registers: vec![KutValue::Nil; self.template.register_count as usize],
+            call_stack: vec![],
+            pc: 0,
+            entered: false,
+            callee: None,
+            resume_target: None,
+            multiple_results: false,
+        }
+    }
+
+    /// Starts a frame with `args` bound to its first registers. When the template declares
+    /// its parameters, the argument count is checked, missing defaulted parameters get
+    /// their default and extra arguments are collected into the rest list.
+    pub fn start_with(self: &Rc<Self>, vm: &'template KutVm<'template>, mut args: Vec<KutValue<'template>>) -> Result<KutFunction<'template>, KutError> {
+        let mut frame = self.start();
+        if let Some(parameters) = &self.template.parameters {
+            let required = parameters.required as usize;
+            let positional = required + parameters.defaults.len();
+            if args.len() < required || (!parameters.rest && args.len() > positional) {
+                let function = self.template.name.clone().unwrap_or_else(|| "<function>".to_owned());
+                return Err(KutError::WrongArgumentCount { function, min: required, max: (!parameters.rest).then_some(positional), given: args.len() });
+            }
+            let rest = args.split_off(args.len().min(positional));
+            for literal in &parameters.defaults[args.len() - required..] {
+                args.push(vm.literal(self.template, *literal)?.clone());
+            }
+            if parameters.rest {
+                let rest = KutValue::List(Rc::new(rest));
+                vm.memory.track(&rest)?;
+                args.push(rest);
+            }
+        }
+        for (reg, arg) in args.into_iter().enumerate() {
+            KutInstruction::set_register_value(&mut frame, reg as u8, arg)?;
+        }
+        Ok(frame)
     }
 }
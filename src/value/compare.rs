@@ -0,0 +1,298 @@
+use crate::value::*;
+use std::cmp::Ordering;
+
+/// Kut equality compares numbers by value, integers and numbers exactly against
+/// each other, strings by content, lists and maps
+/// element-wise and functions and externals by identity, looking through every
+/// `Reference`. The total order ranks values by type first and then by content,
+/// with NaN above every other number. Cycles through references are detected by
+/// remembering the pairs of containers on the current comparison path.
+impl<'template> KutValue<'template> {
+    fn type_rank(&self) -> u8 {
+        match self {
+            KutValue::Nil => 0,
+            KutValue::Undefined => 1,
+            KutValue::Bool(_) => 2,
+            KutValue::Integer(_) | KutValue::Number(_) => 3,
+            KutValue::String(_) => 4,
+            KutValue::List(_) => 5,
+            KutValue::Vector(_) => 6,
+            KutValue::Map(_) => 7,
+            KutValue::Func(_) => 8,
+            KutValue::Native(_) => 9,
+            KutValue::Coroutine(_) => 10,
+            KutValue::Iterator(_) => 11,
+            KutValue::External(_) => 12,
+            KutValue::Reference(r) => r.borrow().type_rank(),
+        }
+    }
+
+    pub(crate) fn identity(&self) -> Option<usize> {
+        match self {
+            KutValue::List(list) => Some(Rc::as_ptr(list) as *const () as usize),
+            KutValue::Vector(vector) => Some(Rc::as_ptr(vector) as *const () as usize),
+            KutValue::Map(map) => Some(Rc::as_ptr(map) as *const () as usize),
+            KutValue::Reference(r) => Some(Rc::as_ptr(r) as *const () as usize),
+            _ => None,
+        }
+    }
+
+    fn guarded<T>(&self, other: &KutValue<'template>, seen: &mut Vec<(usize, usize)>, on_cycle: T, compare: impl FnOnce(&mut Vec<(usize, usize)>) -> T) -> T {
+        let pair = match (self.identity(), other.identity()) {
+            (None, None) => return compare(seen),
+            (lhs, rhs) => (lhs.unwrap_or(0), rhs.unwrap_or(0)),
+        };
+        if seen.contains(&pair) {
+            return on_cycle;
+        }
+        seen.push(pair);
+        let result = compare(seen);
+        seen.pop();
+        result
+    }
+
+    fn numeric_partial_cmp(&self, other: &KutValue<'template>) -> Option<Ordering> {
+        match (self, other) {
+            (KutValue::Integer(a), KutValue::Integer(b)) => Some(a.cmp(b)),
+            (KutValue::Integer(a), KutValue::Number(b)) => KutValue::integer_number_cmp(*a, *b),
+            (KutValue::Number(a), KutValue::Integer(b)) => KutValue::integer_number_cmp(*b, *a).map(Ordering::reverse),
+            (KutValue::Number(a), KutValue::Number(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+
+    fn numeric_total_cmp(&self, other: &KutValue<'template>) -> Ordering {
+        let is_nan = |value: &KutValue| matches!(value, KutValue::Number(num) if num.is_nan());
+        match (is_nan(self), is_nan(other)) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) => self.numeric_partial_cmp(other).unwrap_or(Ordering::Equal),
+        }
+    }
+
+    fn sequence_partial_cmp<'a>(mut lhs: impl Iterator<Item = &'a KutValue<'template>>, mut rhs: impl Iterator<Item = &'a KutValue<'template>>, seen: &mut Vec<(usize, usize)>) -> Option<Ordering> where 'template: 'a {
+        loop {
+            match (lhs.next(), rhs.next()) {
+                (Some(a), Some(b)) => match a.structural_partial_cmp(b, seen) {
+                    Some(Ordering::Equal) => {},
+                    ordering => return ordering,
+                },
+                (Some(_), None) => return Some(Ordering::Greater),
+                (None, Some(_)) => return Some(Ordering::Less),
+                (None, None) => return Some(Ordering::Equal),
+            }
+        }
+    }
+
+    fn sequence_total_cmp<'a>(mut lhs: impl Iterator<Item = &'a KutValue<'template>>, mut rhs: impl Iterator<Item = &'a KutValue<'template>>, seen: &mut Vec<(usize, usize)>) -> Ordering where 'template: 'a {
+        loop {
+            match (lhs.next(), rhs.next()) {
+                (Some(a), Some(b)) => match a.structural_total_cmp(b, seen) {
+                    Ordering::Equal => {},
+                    ordering => return ordering,
+                },
+                (Some(_), None) => return Ordering::Greater,
+                (None, Some(_)) => return Ordering::Less,
+                (None, None) => return Ordering::Equal,
+            }
+        }
+    }
+
+    /// The entries of `map` ordered by key and then by value, so that maps that are equal
+    /// regardless of their insertion order also compare equal. The entries are compared
+    /// on the path of `seen`, so that keys and values containing `map` end the cycle.
+    fn sorted_entries<'a>(map: &'a KutMap<'template>, seen: &mut Vec<(usize, usize)>) -> Vec<(&'a KutKey<'template>, &'a KutValue<'template>)> {
+        let mut entries: Vec<_> = map.iter().collect();
+        entries.sort_by(|(a_key, a), (b_key, b)| a_key.0.structural_total_cmp(&b_key.0, seen).then_with(|| a.structural_total_cmp(b, seen)));
+        entries
+    }
+
+    fn structural_eq(&self, other: &KutValue<'template>, seen: &mut Vec<(usize, usize)>) -> bool {
+        if self.identity().is_some() && self.identity() == other.identity() {
+            return true;
+        }
+        self.guarded(other, seen, true, |seen| match (self, other) {
+            (KutValue::Reference(a), _) => a.borrow().structural_eq(other, seen),
+            (_, KutValue::Reference(b)) => self.structural_eq(&b.borrow(), seen),
+            (KutValue::Nil, KutValue::Nil) => true,
+            (KutValue::Undefined, KutValue::Undefined) => true,
+            (KutValue::Bool(a), KutValue::Bool(b)) => a == b,
+            (KutValue::Integer(_) | KutValue::Number(_), KutValue::Integer(_) | KutValue::Number(_)) => {
+                self.numeric_partial_cmp(other) == Some(Ordering::Equal)
+            },
+            (KutValue::String(a), KutValue::String(b)) => a == b,
+            (KutValue::List(a), KutValue::List(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.structural_eq(b, seen))
+            },
+            (KutValue::Vector(a), KutValue::Vector(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.structural_eq(b, seen))
+            },
+            (KutValue::Map(a), KutValue::Map(b)) => {
+                a.len() == b.len() && a.iter().all(|(key, a)| b.get(key).is_some_and(|b| a.structural_eq(b, seen)))
+            },
+            (KutValue::Func(a), KutValue::Func(b)) => Rc::ptr_eq(a, b),
+            (KutValue::Native(a), KutValue::Native(b)) => Rc::ptr_eq(a, b),
+            (KutValue::Coroutine(a), KutValue::Coroutine(b)) => Rc::ptr_eq(a, b),
+            (KutValue::Iterator(a), KutValue::Iterator(b)) => Rc::ptr_eq(a, b),
+            (KutValue::External(a), KutValue::External(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        })
+    }
+
+    fn structural_partial_cmp(&self, other: &KutValue<'template>, seen: &mut Vec<(usize, usize)>) -> Option<Ordering> {
+        self.guarded(other, seen, Some(Ordering::Equal), |seen| match (self, other) {
+            (KutValue::Reference(a), _) => a.borrow().structural_partial_cmp(other, seen),
+            (_, KutValue::Reference(b)) => self.structural_partial_cmp(&b.borrow(), seen),
+            (KutValue::Bool(a), KutValue::Bool(b)) => Some(a.cmp(b)),
+            (KutValue::Integer(_) | KutValue::Number(_), KutValue::Integer(_) | KutValue::Number(_)) => self.numeric_partial_cmp(other),
+            (KutValue::String(a), KutValue::String(b)) => Some(a.cmp(b)),
+            (KutValue::List(a), KutValue::List(b)) => KutValue::sequence_partial_cmp(a.iter(), b.iter(), seen),
+            (KutValue::Vector(a), KutValue::Vector(b)) => KutValue::sequence_partial_cmp(a.iter(), b.iter(), seen),
+            _ if self.structural_eq(other, &mut vec![]) => Some(Ordering::Equal),
+            _ => None,
+        })
+    }
+
+    fn structural_total_cmp(&self, other: &KutValue<'template>, seen: &mut Vec<(usize, usize)>) -> Ordering {
+        self.guarded(other, seen, Ordering::Equal, |seen| match (self, other) {
+            (KutValue::Reference(a), _) => a.borrow().structural_total_cmp(other, seen),
+            (_, KutValue::Reference(b)) => self.structural_total_cmp(&b.borrow(), seen),
+            (KutValue::Bool(a), KutValue::Bool(b)) => a.cmp(b),
+            (KutValue::Integer(_) | KutValue::Number(_), KutValue::Integer(_) | KutValue::Number(_)) => self.numeric_total_cmp(other),
+            (KutValue::String(a), KutValue::String(b)) => a.cmp(b),
+            (KutValue::List(a), KutValue::List(b)) => KutValue::sequence_total_cmp(a.iter(), b.iter(), seen),
+            (KutValue::Vector(a), KutValue::Vector(b)) => KutValue::sequence_total_cmp(a.iter(), b.iter(), seen),
+            (KutValue::Map(a), KutValue::Map(b)) => {
+                let (a, b) = (KutValue::sorted_entries(a, seen), KutValue::sorted_entries(b, seen));
+                a.iter()
+                    .zip(b.iter())
+                    .map(|((a_key, a), (b_key, b))| a_key.0.structural_total_cmp(&b_key.0, seen).then_with(|| a.structural_total_cmp(b, seen)))
+                    .find(|ordering| ordering.is_ne())
+                    .unwrap_or_else(|| a.len().cmp(&b.len()))
+            },
+            (KutValue::Func(a), KutValue::Func(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            (KutValue::Native(a), KutValue::Native(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            (KutValue::Coroutine(a), KutValue::Coroutine(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            (KutValue::Iterator(a), KutValue::Iterator(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            (KutValue::External(a), KutValue::External(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            _ => self.type_rank().cmp(&other.type_rank()),
+        })
+    }
+
+    /// Total order for sorting: values of different types are ordered by type,
+    /// `-0.0` equals `0.0` and NaN equals itself and is above every other number.
+    pub fn total_cmp(&self, other: &KutValue<'template>) -> Ordering {
+        self.structural_total_cmp(other, &mut vec![])
+    }
+}
+
+impl<'template> PartialEq for KutValue<'template> {
+    fn eq(&self, other: &Self) -> bool {
+        self.structural_eq(other, &mut vec![])
+    }
+}
+
+impl<'template> PartialOrd for KutValue<'template> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.structural_partial_cmp(other, &mut vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::value::*;
+    use std::cmp::Ordering;
+
+    fn map<'template>(entries: &[(&str, i64)]) -> KutValue<'template> {
+        let mut map = KutMap::new();
+        for (key, value) in entries {
+            map.insert(KutKey(KutValue::String(Rc::new((*key).to_owned()))), KutValue::Integer(*value));
+        }
+        KutValue::Map(Rc::new(map))
+    }
+
+    #[test]
+    fn equal_maps_compare_equal_in_any_insertion_order() {
+        let a = map(&[("x", 1), ("y", 2)]);
+        let b = map(&[("y", 2), ("x", 1)]);
+        assert!(a == b);
+        assert_eq!(a.total_cmp(&b), Ordering::Equal);
+        assert_eq!(b.total_cmp(&a), Ordering::Equal);
+    }
+
+    #[test]
+    fn maps_order_by_sorted_entries() {
+        let a = map(&[("y", 1), ("x", 1)]);
+        let b = map(&[("x", 2), ("y", 0)]);
+        assert_eq!(a.total_cmp(&b), Ordering::Less);
+        assert_eq!(b.total_cmp(&a), Ordering::Greater);
+        let shorter = map(&[("y", 1)]);
+        let longer = map(&[("y", 1), ("z", 0)]);
+        assert_eq!(shorter.total_cmp(&longer), Ordering::Less);
+        assert_eq!(map(&[("x", 5)]).total_cmp(&longer), Ordering::Less);
+    }
+
+    /// A map with two keys that are distinct references to the map itself.
+    fn cyclic_map<'template>() -> KutValue<'template> {
+        let keys = [Rc::new(RefCell::new(KutValue::Nil)), Rc::new(RefCell::new(KutValue::Nil))];
+        let mut map = KutMap::new();
+        for key in &keys {
+            map.insert(KutKey(KutValue::Reference(Rc::clone(key))), KutValue::Integer(1));
+        }
+        let map = KutValue::Map(Rc::new(map));
+        for key in &keys {
+            *key.borrow_mut() = map.clone();
+        }
+        map
+    }
+
+    #[test]
+    fn cyclic_map_keys_compare_without_overflowing() {
+        let (a, b) = (cyclic_map(), cyclic_map());
+        assert_eq!(a.total_cmp(&a), Ordering::Equal);
+        assert_eq!(a.total_cmp(&b), Ordering::Equal);
+        assert_eq!(a.partial_cmp(&a), Some(Ordering::Equal));
+    }
+
+    #[test]
+    fn nan_equals_itself_above_every_number() {
+        let nan = KutValue::Number(f64::NAN);
+        assert_eq!(nan.total_cmp(&KutValue::Number(f64::NAN)), Ordering::Equal);
+        assert_eq!(nan.total_cmp(&KutValue::Number(f64::INFINITY)), Ordering::Greater);
+        assert_eq!(KutValue::Integer(i64::MAX).total_cmp(&nan), Ordering::Less);
+        assert_eq!(nan.partial_cmp(&nan), None);
+    }
+
+    #[test]
+    fn negative_zero_equals_zero() {
+        assert_eq!(KutValue::Number(-0.0).total_cmp(&KutValue::Number(0.0)), Ordering::Equal);
+        assert_eq!(KutValue::Number(-0.0).total_cmp(&KutValue::Integer(0)), Ordering::Equal);
+    }
+
+    #[test]
+    fn integers_and_numbers_compare_by_value() {
+        assert_eq!(KutValue::Integer(2).total_cmp(&KutValue::Number(2.0)), Ordering::Equal);
+        assert_eq!(KutValue::Integer(1).total_cmp(&KutValue::Number(1.5)), Ordering::Less);
+        assert_eq!(KutValue::Number(-1.5).total_cmp(&KutValue::Integer(-2)), Ordering::Greater);
+        assert_eq!(KutValue::Integer(i64::MAX).total_cmp(&KutValue::Number(9.3e18)), Ordering::Less);
+    }
+
+    #[test]
+    fn different_types_order_by_type() {
+        let ordered = [
+            KutValue::Nil,
+            KutValue::Undefined,
+            KutValue::Bool(true),
+            KutValue::Number(-1e300),
+            KutValue::String(Rc::new(String::new())),
+            KutValue::List(Rc::new(vec![])),
+            map(&[]),
+        ];
+        for (index, lhs) in ordered.iter().enumerate() {
+            for (other, rhs) in ordered.iter().enumerate() {
+                assert_eq!(lhs.total_cmp(rhs), index.cmp(&other), "{lhs:?} and {rhs:?}");
+            }
+        }
+    }
+}
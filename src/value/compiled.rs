@@ -0,0 +1,413 @@
+use crate::value::*;
+use crate::vm::*;
+use std::fmt;
+
+/// Handler of a pre-decoded instruction, called with the operands decoded into the op.
+pub(crate) type KutOpHandler = for<'template> fn(&mut KutFunction<'template>, &'template KutVm<'template>, &KutOp) -> KutReturnType<'template>;
+
+/// An instruction pre-decoded for `KutEngine::Threaded`: its handler with the operands
+/// inline. The operands of ops with a dedicated handler are verified when decoding, so
+/// the handlers index registers and literals directly. Every other instruction, and any
+/// instruction whose operands are out of range, decodes to a handler that runs it with
+/// `KutInstruction::run`, which reports the error when it is reached.
+#[derive(Clone, Copy)]
+pub struct KutOp {
+    handler: KutOpHandler,
+    a: u8,
+    b: u8,
+    c: u8,
+    d: u32,
+}
+
+impl fmt::Debug for KutOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KutOp").field("a", &self.a).field("b", &self.b).field("c", &self.c).field("d", &self.d).finish_non_exhaustive()
+    }
+}
+
+impl KutOp {
+    fn new(handler: KutOpHandler, a: u8, b: u8, c: u8, d: u32) -> KutOp {
+        KutOp { handler, a, b, c, d }
+    }
+
+    #[inline]
+    pub(crate) fn run<'template>(&self, context: &mut KutFunction<'template>, vm: &'template KutVm<'template>) -> KutReturnType<'template> {
+        (self.handler)(context, vm, self)
+    }
+
+    /// Decodes every instruction of `template`, whose literals are looked up in `vm`.
+    pub(crate) fn decode_template(vm: &KutVm, template: &KutFunctionTemplate) -> Box<[KutOp]> {
+        let registers = template.register_count;
+        let instruction_count = template.instructions.len();
+        let reg = |reg: &u8| *reg < registers;
+        let jump = |target: &u16| *target as usize <= instruction_count;
+        template.instructions.iter().map(|instruction| match instruction {
+            KutInstruction::NoOperation => KutOp::new(op_no_operation, 0, 0, 0, 0),
+            KutInstruction::MovRegister { destination, source } if reg(destination) && reg(source) => KutOp::new(op_mov_register, *destination, *source, 0, 0),
+            KutInstruction::GetLiteralR { reg: target, literal } if reg(target) => match vm.literal_index(template, *literal) {
+                Some(index) => KutOp::new(op_get_literal, *target, 0, 0, index as u32),
+                None => KutOp::new(op_reference, 0, 0, 0, 0),
+            },
+            KutInstruction::PushValue1R { val1 } if reg(val1) => KutOp::new(op_push_value_1, *val1, 0, 0, 0),
+            KutInstruction::PushValue2R { val1, val2 } if reg(val1) && reg(val2) => KutOp::new(op_push_value_2, *val1, *val2, 0, 0),
+            KutInstruction::PushValue3R { val1, val2, val3 } if reg(val1) && reg(val2) && reg(val3) => KutOp::new(op_push_value_3, *val1, *val2, *val3, 0),
+            KutInstruction::CallMethodR { ret_position, arg_count, subject } if reg(ret_position) => KutOp::new(op_call_method_r, *ret_position, *arg_count, *subject, 0),
+            KutInstruction::RetfMethodR { value } if reg(value) => KutOp::new(op_ret_r, *value, 0, 0, 0),
+            KutInstruction::AddNumbersR { reg: target, lhs, rhs } if reg(target) && reg(lhs) && reg(rhs) => KutOp::new(op_add, *target, *lhs, *rhs, 0),
+            KutInstruction::SubNumbersR { reg: target, lhs, rhs } if reg(target) && reg(lhs) && reg(rhs) => KutOp::new(op_sub, *target, *lhs, *rhs, 0),
+            KutInstruction::MulNumbersR { reg: target, lhs, rhs } if reg(target) && reg(lhs) && reg(rhs) => KutOp::new(op_mul, *target, *lhs, *rhs, 0),
+            KutInstruction::CompareEqlR { reg: target, lhs, rhs } if reg(target) && reg(lhs) && reg(rhs) => KutOp::new(op_compare_eql, *target, *lhs, *rhs, 0),
+            KutInstruction::CompareNeqR { reg: target, lhs, rhs } if reg(target) && reg(lhs) && reg(rhs) => KutOp::new(op_compare_neq, *target, *lhs, *rhs, 0),
+            KutInstruction::CompareLssR { reg: target, lhs, rhs } if reg(target) && reg(lhs) && reg(rhs) => KutOp::new(op_compare_lss, *target, *lhs, *rhs, 0),
+            KutInstruction::CompareLeqR { reg: target, lhs, rhs } if reg(target) && reg(lhs) && reg(rhs) => KutOp::new(op_compare_leq, *target, *lhs, *rhs, 0),
+            KutInstruction::JumpAlwaysT { target } if jump(target) => KutOp::new(op_jump, 0, 0, 0, *target as u32),
+            KutInstruction::JumpIfTrueR { cond, target } if reg(cond) && jump(target) => KutOp::new(op_jump_if_true, *cond, 0, 0, *target as u32),
+            KutInstruction::JumpIfFalse { cond, target } if reg(cond) && jump(target) => KutOp::new(op_jump_if_false, *cond, 0, 0, *target as u32),
+            _ => KutOp::new(op_reference, 0, 0, 0, 0),
+        }).collect()
+    }
+}
+
+#[inline]
+fn read<'template>(context: &KutFunction<'template>, reg: u8) -> KutValue<'template> {
+    match &context.registers[reg as usize] {
+        KutValue::Reference(r) => r.borrow().clone(),
+        value => value.clone(),
+    }
+}
+
+#[inline]
+fn write<'template>(context: &mut KutFunction<'template>, reg: u8, value: KutValue<'template>) {
+    match &mut context.registers[reg as usize] {
+        KutValue::Reference(r) => *r.borrow_mut() = value,
+        destination => *destination = value,
+    }
+}
+
+fn op_reference<'template>(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, _op: &KutOp) -> KutReturnType<'template> {
+    context.closure.template.instructions[context.pc - 1].run(context, vm)
+}
+
+fn op_no_operation<'template>(_context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, _op: &KutOp) -> KutReturnType<'template> {
+    Ok(None)
+}
+
+fn op_mov_register<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    if op.a != op.b {
+        let value = read(context, op.b);
+        write(context, op.a, value);
+    }
+    Ok(None)
+}
+
+fn op_get_literal<'template>(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    write(context, op.a, vm.literals[op.d as usize].clone());
+    Ok(None)
+}
+
+fn op_push_value_1<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    let value = read(context, op.a);
+    context.call_stack.push(value);
+    Ok(None)
+}
+
+fn op_push_value_2<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    let values = [read(context, op.a), read(context, op.b)];
+    context.call_stack.extend(values);
+    Ok(None)
+}
+
+fn op_push_value_3<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    let values = [read(context, op.a), read(context, op.b), read(context, op.c)];
+    context.call_stack.extend(values);
+    Ok(None)
+}
+
+fn op_call_method_r<'template>(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    KutInstruction::prepare_call(context, vm, op.b, op.c, KutReturnTarget::Register(op.a))?;
+    Ok(None)
+}
+
+fn op_ret_r<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    Ok(Some(read(context, op.a)))
+}
+
+/// Integers and numbers are computed in place, anything else goes through `KutValue::arithmetic`.
+#[inline]
+fn arithmetic<'template>(context: &mut KutFunction<'template>, op: &KutOp, operation: KutArithmetic) -> KutReturnType<'template> {
+    let result = match (&context.registers[op.b as usize], &context.registers[op.c as usize]) {
+        (KutValue::Integer(lhs), KutValue::Integer(rhs)) => KutValue::Integer(operation.integer(*lhs, *rhs)?),
+        (KutValue::Number(lhs), KutValue::Number(rhs)) => KutValue::Number(operation.number(*lhs, *rhs)),
+        _ => read(context, op.b).arithmetic(operation, &read(context, op.c))?,
+    };
+    write(context, op.a, result);
+    Ok(None)
+}
+
+fn op_add<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    arithmetic(context, op, KutArithmetic::Add)
+}
+
+fn op_sub<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    arithmetic(context, op, KutArithmetic::Sub)
+}
+
+fn op_mul<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    arithmetic(context, op, KutArithmetic::Mul)
+}
+
+/// Integers are compared in place, anything else with the comparison of `KutValue`.
+#[inline]
+fn compare<'template>(context: &mut KutFunction<'template>, op: &KutOp, integers: fn(&i64, &i64) -> bool, values: fn(&KutValue<'template>, &KutValue<'template>) -> bool) -> KutReturnType<'template> {
+    let result = match (&context.registers[op.b as usize], &context.registers[op.c as usize]) {
+        (KutValue::Integer(lhs), KutValue::Integer(rhs)) => integers(lhs, rhs),
+        _ => values(&read(context, op.b), &read(context, op.c)),
+    };
+    write(context, op.a, KutValue::Bool(result));
+    Ok(None)
+}
+
+fn op_compare_eql<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    compare(context, op, i64::eq, KutValue::eq)
+}
+
+fn op_compare_neq<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    compare(context, op, i64::ne, KutValue::ne)
+}
+
+fn op_compare_lss<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    compare(context, op, i64::lt, KutValue::lt)
+}
+
+fn op_compare_leq<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    compare(context, op, i64::le, KutValue::le)
+}
+
+fn op_jump<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    context.pc = op.d as usize;
+    Ok(None)
+}
+
+fn op_jump_if_true<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    if context.registers[op.a as usize].is_truthy() {
+        context.pc = op.d as usize;
+    }
+    Ok(None)
+}
+
+fn op_jump_if_false<'template>(context: &mut KutFunction<'template>, _vm: &'template KutVm<'template>, op: &KutOp) -> KutReturnType<'template> {
+    if !context.registers[op.a as usize].is_truthy() {
+        context.pc = op.d as usize;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::value::*;
+    use crate::vm::*;
+    use crate::execution::*;
+    use KutInstruction::*;
+
+    /// Runs `scenario` under each engine, asserts that both observed the same and
+    /// returns the observations.
+    fn on_both_engines(scenario: impl Fn(KutEngine) -> Vec<String>) -> Vec<String> {
+        let reference = scenario(KutEngine::Reference);
+        let threaded = scenario(KutEngine::Threaded);
+        assert_eq!(reference, threaded);
+        reference
+    }
+
+    fn vm<'template>(engine: KutEngine, literals: Vec<KutValue<'template>>, templates: Vec<KutFunctionTemplate>) -> KutVm<'template> {
+        let mut vm = KutVm::new(literals, templates);
+        vm.engine = engine;
+        vm
+    }
+
+    fn name(name: &str) -> KutValue<'_> {
+        KutValue::String(Rc::new(name.to_owned()))
+    }
+
+    /// Resumes `call` until it finishes, recording every error with the program counter
+    /// of the frame and the remaining fuel, then the result and the fuel consumed.
+    fn drive<'template>(vm: &'template KutVm<'template>, mut call: KutCallHandle<'template, KutValue<'template>>, refill: u64) -> Vec<String> {
+        let mut observations = vec![];
+        loop {
+            match call.resume() {
+                Err(error) => {
+                    let pc = call.frame().map(|frame| frame.pc);
+                    observations.push(format!("{error:?} at {pc:?} with {:?} fuel", vm.remaining_fuel()));
+                    vm.add_fuel(refill);
+                },
+                Ok(value) => {
+                    observations.push(format!("{value:?} after {} fuel", vm.fuel_consumed()));
+                    return observations;
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_operands_fail_alike() {
+        let templates = || vec![
+            KutFunctionTemplate::new(vec![JumpAlwaysT { target: 9 }], vec![], 1).with_name("jump"),
+            KutFunctionTemplate::new(vec![GetLiteralR { reg: 0, literal: 1 }, JumpIfFalse { cond: 0, target: 7 }, RetfMethodR { value: 0 }], vec![], 1).with_name("branch"),
+            KutFunctionTemplate::new(vec![JumpIfTrueR { cond: 4, target: 0 }], vec![], 1).with_name("condition"),
+            KutFunctionTemplate::new(vec![GetLiteralR { reg: 0, literal: 0 }, AddNumbersR { reg: 0, lhs: 0, rhs: 5 }], vec![], 1).with_name("operand"),
+            KutFunctionTemplate::new(vec![GetLiteralR { reg: 0, literal: 40 }], vec![], 1).with_name("literal"),
+            KutFunctionTemplate::new(vec![GetLiteralR { reg: 0, literal: 0 }, MovRegister { destination: 3, source: 0 }], vec![], 1).with_name("move"),
+            KutFunctionTemplate::new(vec![JumpAlwaysT { target: 1 }], vec![], 1).with_name("end"),
+        ];
+        let observations = on_both_engines(|engine| {
+            let vm = &vm(engine, vec![KutValue::Integer(1), KutValue::Bool(false)], templates());
+            ["jump", "branch", "condition", "operand", "literal", "move", "end"].iter().map(|name| {
+                let mut call = vm.start_call::<KutValue>(name, ()).unwrap();
+                let result = call.resume();
+                format!("{name}: {result:?} at {:?}", call.frame().map(|frame| frame.pc))
+            }).collect()
+        });
+        assert!(observations[0].contains("OutOfRangeJump") && observations[0].ends_with("at Some(0)"), "{observations:?}");
+        assert!(observations[1].contains("OutOfRangeJump") && observations[1].ends_with("at Some(1)"), "{observations:?}");
+        assert!(observations[3].contains("OutOfRangeSourceRegister") && observations[3].ends_with("at Some(1)"), "{observations:?}");
+        assert!(observations[5].contains("OutOfRangeDestinationRegister"), "{observations:?}");
+        assert_eq!(observations[6], "end: Ok(Nil) at None");
+    }
+
+    /// `sum(n)` adds the integers below `n` with calls to the global `add`, which
+    /// `define` sets.
+    fn calling_vm<'template>(engine: KutEngine) -> KutVm<'template> {
+        let sum = vec![
+            GetLiteralR { reg: 1, literal: 0 },
+            GetLiteralR { reg: 2, literal: 0 },
+            GetLiteralR { reg: 3, literal: 1 },
+            GetGlobalRg { reg: 5, name: 2 },
+            CompareLssR { reg: 4, lhs: 1, rhs: 0 },
+            JumpIfFalse { cond: 4, target: 10 },
+            PushValue2R { val1: 2, val2: 1 },
+            CallMethodR { ret_position: 2, arg_count: 2, subject: 5 },
+            AddNumbersR { reg: 1, lhs: 1, rhs: 3 },
+            JumpAlwaysT { target: 4 },
+            RetfMethodR { value: 2 },
+        ];
+        let add = vec![AddNumbersR { reg: 2, lhs: 0, rhs: 1 }, RetfMethodR { value: 2 }];
+        let define = vec![CaptureFunc { reg: 0, template: 1 }, SetGlobalRg { reg: 0, name: 2 }];
+        vm(engine, vec![KutValue::Integer(0), KutValue::Integer(1), name("add")], vec![
+            KutFunctionTemplate::new(sum, vec![], 6).with_name("sum"),
+            KutFunctionTemplate::new(add, vec![], 3).with_name("add"),
+            KutFunctionTemplate::new(define, vec![], 1).with_name("define"),
+        ])
+    }
+
+    #[test]
+    fn fuel_runs_out_and_resumes_alike() {
+        let observations = on_both_engines(|engine| {
+            let vm = &calling_vm(engine);
+            vm.call::<KutValue>("define", ()).unwrap();
+            vm.set_fuel(Some(25));
+            vm.reset_fuel_consumed();
+            drive(vm, vm.start_call("sum", (20,)).unwrap(), 25)
+        });
+        assert!(observations.len() > 10, "{observations:?}");
+        assert!(observations.last().unwrap().starts_with("Integer(190)"), "{observations:?}");
+    }
+
+    #[test]
+    fn interrupts_stop_and_resume_alike() {
+        let observations = on_both_engines(|engine| {
+            let vm = &calling_vm(engine);
+            vm.call::<KutValue>("define", ()).unwrap();
+            let interrupt = vm.interrupt_handle();
+            vm.set_global("stop", KutValue::Native(Rc::new(KutNativeFunction::new("stop", move |_, args| {
+                interrupt.interrupt();
+                Ok(args.into_iter().next().unwrap_or(KutValue::Nil))
+            }))));
+            vm.set_global("add", vm.get_global::<KutValue>("stop").unwrap());
+            vm.interrupt_handle().interrupt();
+            drive(vm, vm.start_call("sum", (3,)).unwrap(), 0)
+        });
+        assert_eq!(observations.len(), 5, "{observations:?}");
+        assert!(observations[0].starts_with("Interrupted at Some(0)"), "{observations:?}");
+        assert!(observations[1].starts_with("Interrupted at Some(8)"), "{observations:?}");
+        assert!(observations[4].starts_with("Integer(0)"), "{observations:?}");
+    }
+
+    #[test]
+    fn captured_registers_are_shared_alike() {
+        let main = || vec![
+            GetLiteralR { reg: 0, literal: 0 },
+            CaptureFunc { reg: 1, template: 1 },
+            CallMethodR { ret_position: 2, arg_count: 0, subject: 1 },
+            CallMethodR { ret_position: 2, arg_count: 0, subject: 1 },
+            AddNumbersR { reg: 3, lhs: 0, rhs: 2 },
+            MovRegister { destination: 2, source: 0 },
+            CompareLssR { reg: 4, lhs: 2, rhs: 3 },
+            JumpIfFalse { cond: 4, target: 9 },
+            MulNumbersR { reg: 0, lhs: 0, rhs: 3 },
+            CallMethodR { ret_position: 2, arg_count: 0, subject: 1 },
+            CompareEqlR { reg: 4, lhs: 0, rhs: 2 },
+            JumpIfTrueR { cond: 4, target: 13 },
+            RetfMethodR { value: 4 },
+            RetfMethodR { value: 0 },
+        ];
+        // Doubles the captured register 0 of `main` and returns it.
+        let double = || vec![
+            GetCaptureR { reg: 0, capture: 0 },
+            AddNumbersR { reg: 1, lhs: 0, rhs: 0 },
+            SetCaptureR { reg: 1, capture: 0 },
+            RetfMethodR { value: 1 },
+        ];
+        let observations = on_both_engines(|engine| {
+            let vm = &vm(engine, vec![KutValue::Integer(1)], vec![
+                KutFunctionTemplate::new(main(), vec![], 5).with_name("main"),
+                KutFunctionTemplate::new(double(), vec![KutCaptureInfo::Register(0)], 2),
+            ]);
+            vec![format!("{:?}", vm.call::<KutValue>("main", ()))]
+        });
+        assert_eq!(observations, ["Ok(Integer(64))"]);
+    }
+
+    #[test]
+    fn coroutines_and_iterators_run_alike() {
+        let main = || vec![
+            CaptureFunc { reg: 1, template: 1 },
+            CoroutineRg { reg: 2, func: 1 },
+            IterCreateR { reg: 3, value: 2 },
+            GetLiteralR { reg: 4, literal: 0 },
+            IterNextReg { reg: 5, iter: 3, target: 7 },
+            AddNumbersR { reg: 4, lhs: 4, rhs: 5 },
+            JumpAlwaysT { target: 4 },
+            GetLiteralR { reg: 6, literal: 0 },
+            GetLiteralR { reg: 7, literal: 1 },
+            IterRangeRg { reg: 8, start: 6, end: 0, step: 7 },
+            IterNextReg { reg: 5, iter: 8, target: 13 },
+            AddNumbersR { reg: 4, lhs: 4, rhs: 5 },
+            JumpAlwaysT { target: 10 },
+            CoroStatusR { reg: 6, coroutine: 2 },
+            RetfMethodR { value: 4 },
+        ];
+        // Yields the integers below the captured register 0 of `main`.
+        let generator = || vec![
+            GetCaptureR { reg: 0, capture: 0 },
+            GetLiteralR { reg: 1, literal: 0 },
+            GetLiteralR { reg: 2, literal: 1 },
+            CompareLssR { reg: 3, lhs: 1, rhs: 0 },
+            JumpIfFalse { cond: 3, target: 8 },
+            CoroYieldRg { reg: 3, value: 1 },
+            AddNumbersR { reg: 1, lhs: 1, rhs: 2 },
+            JumpAlwaysT { target: 3 },
+        ];
+        let observations = on_both_engines(|engine| {
+            let vm = &vm(engine, vec![KutValue::Integer(0), KutValue::Integer(1)], vec![
+                KutFunctionTemplate::new(main(), vec![], 9).with_name("main"),
+                KutFunctionTemplate::new(generator(), vec![KutCaptureInfo::Register(0)], 4),
+            ]);
+            let mut observations = vec![format!("{:?}", vm.call::<KutValue>("main", (5,)))];
+            vm.set_fuel(Some(7));
+            observations.extend(drive(vm, vm.start_call("main", (4,)).unwrap(), 7));
+            observations
+        });
+        assert_eq!(observations[0], "Ok(Integer(20))");
+        assert!(observations.last().unwrap().starts_with("Integer(12)"), "{observations:?}");
+        assert!(observations.len() > 10, "{observations:?}");
+    }
+}
@@ -0,0 +1,270 @@
+use crate::value::*;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+/// Conversion of a Kut value to a Rust value. References are looked through, so a
+/// captured variable converts like the value it holds. A value of the wrong type or
+/// out of range for the target fails with `KutError::InvalidConversion`.
+pub trait FromKut<'template>: Sized {
+    /// Whether a missing value converts, so that a typed native may be called
+    /// without this argument when it is one of the last ones.
+    const OPTIONAL: bool = false;
+
+    fn from_kut(value: KutValue<'template>) -> Result<Self, KutError>;
+}
+
+/// Conversion of a Rust value to a Kut value. The result is not accounted in any
+/// `KutMemory`.
+pub trait IntoKut<'template> {
+    fn into_kut(self) -> KutValue<'template>;
+}
+
+impl<'template> KutValue<'template> {
+    fn dereferenced(self) -> KutValue<'template> {
+        match self {
+            KutValue::Reference(r) => r.borrow().clone().dereferenced(),
+            value => value,
+        }
+    }
+
+    fn conversion_error<T>(&self, expected: &str) -> Result<T, KutError> {
+        Err(KutError::InvalidConversion { expected: expected.to_owned(), value_type: self.get_type_string() })
+    }
+
+    fn into_items(self, expected: &str) -> Result<Vec<KutValue<'template>>, KutError> {
+        match self.dereferenced() {
+            KutValue::List(list) => Ok(Rc::try_unwrap(list).unwrap_or_else(|list| (*list).clone())),
+            KutValue::Vector(vector) => Ok(vector.iter().cloned().collect()),
+            other => other.conversion_error(expected),
+        }
+    }
+}
+
+impl<'template> FromKut<'template> for KutValue<'template> {
+    fn from_kut(value: KutValue<'template>) -> Result<Self, KutError> {
+        Ok(value)
+    }
+}
+
+impl<'template> IntoKut<'template> for KutValue<'template> {
+    fn into_kut(self) -> KutValue<'template> {
+        self
+    }
+}
+
+impl<'template> IntoKut<'template> for () {
+    fn into_kut(self) -> KutValue<'template> {
+        KutValue::Nil
+    }
+}
+
+impl<'template> FromKut<'template> for bool {
+    fn from_kut(value: KutValue<'template>) -> Result<Self, KutError> {
+        match value.dereferenced() {
+            KutValue::Bool(b) => Ok(b),
+            other => other.conversion_error("Bool"),
+        }
+    }
+}
+
+impl<'template> IntoKut<'template> for bool {
+    fn into_kut(self) -> KutValue<'template> {
+        KutValue::Bool(self)
+    }
+}
+
+impl<'template> FromKut<'template> for f64 {
+    fn from_kut(value: KutValue<'template>) -> Result<Self, KutError> {
+        let value = value.dereferenced();
+        match value.as_f64() {
+            Some(num) => Ok(num),
+            None => value.conversion_error("Number"),
+        }
+    }
+}
+
+impl<'template> IntoKut<'template> for f64 {
+    fn into_kut(self) -> KutValue<'template> {
+        KutValue::Number(self)
+    }
+}
+
+impl<'template> FromKut<'template> for f32 {
+    fn from_kut(value: KutValue<'template>) -> Result<Self, KutError> {
+        f64::from_kut(value).map(|num| num as f32)
+    }
+}
+
+impl<'template> IntoKut<'template> for f32 {
+    fn into_kut(self) -> KutValue<'template> {
+        KutValue::Number(self as f64)
+    }
+}
+
+/// Integers convert from an `Integer` or from a `Number` with an exact integer value
+/// that fits the target type, unlike `KutValue::as_i64`, which reports a fractional
+/// `Number` as `KutError::NonIntegralNumber`. Integers that do not fit in `i64` become
+/// a `Number`.
+macro_rules! impl_integer_conversion {
+    ($($int:ty),*) => {
+        $(
+            impl<'template> FromKut<'template> for $int {
+                fn from_kut(value: KutValue<'template>) -> Result<Self, KutError> {
+                    let value = value.dereferenced();
+                    if value.as_f64().is_none() {
+                        return value.conversion_error(stringify!($int));
+                    }
+                    let int = value.as_i64().map_err(|_| KutError::InvalidConversion { expected: stringify!($int).to_owned(), value_type: format!("{} {value}", value.get_type_string()) })?;
+                    <$int>::try_from(int).map_err(|_| KutError::InvalidConversion { expected: stringify!($int).to_owned(), value_type: format!("Integer {int}") })
+                }
+            }
+
+            impl<'template> IntoKut<'template> for $int {
+                fn into_kut(self) -> KutValue<'template> {
+                    i64::try_from(self).map_or(KutValue::Number(self as f64), KutValue::Integer)
+                }
+            }
+        )*
+    };
+}
+
+impl_integer_conversion!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl<'template> FromKut<'template> for String {
+    fn from_kut(value: KutValue<'template>) -> Result<Self, KutError> {
+        match value.dereferenced() {
+            KutValue::String(string) => Ok(Rc::try_unwrap(string).unwrap_or_else(|string| (*string).clone())),
+            other => other.conversion_error("String"),
+        }
+    }
+}
+
+impl<'template> IntoKut<'template> for String {
+    fn into_kut(self) -> KutValue<'template> {
+        KutValue::String(Rc::new(self))
+    }
+}
+
+impl<'template> FromKut<'template> for Rc<String> {
+    fn from_kut(value: KutValue<'template>) -> Result<Self, KutError> {
+        match value.dereferenced() {
+            KutValue::String(string) => Ok(string),
+            other => other.conversion_error("String"),
+        }
+    }
+}
+
+impl<'template> IntoKut<'template> for &str {
+    fn into_kut(self) -> KutValue<'template> {
+        KutValue::String(Rc::new(self.to_owned()))
+    }
+}
+
+/// `None` converts to `Nil` and both `Nil` and `Undefined` convert to `None`.
+impl<'template, T: FromKut<'template>> FromKut<'template> for Option<T> {
+    const OPTIONAL: bool = true;
+
+    fn from_kut(value: KutValue<'template>) -> Result<Self, KutError> {
+        match value.dereferenced() {
+            KutValue::Nil | KutValue::Undefined => Ok(None),
+            other => T::from_kut(other).map(Some),
+        }
+    }
+}
+
+impl<'template, T: IntoKut<'template>> IntoKut<'template> for Option<T> {
+    fn into_kut(self) -> KutValue<'template> {
+        self.map_or(KutValue::Nil, T::into_kut)
+    }
+}
+
+/// Converts from a `List` or a `Vector`, and to a `List`.
+impl<'template, T: FromKut<'template>> FromKut<'template> for Vec<T> {
+    fn from_kut(value: KutValue<'template>) -> Result<Self, KutError> {
+        value.into_items("List")?.into_iter().map(T::from_kut).collect()
+    }
+}
+
+impl<'template, T: IntoKut<'template>> IntoKut<'template> for Vec<T> {
+    fn into_kut(self) -> KutValue<'template> {
+        KutValue::List(Rc::new(self.into_iter().map(T::into_kut).collect()))
+    }
+}
+
+/// Converts from a `Map` whose keys are all strings, and to a `Map`.
+impl<'template, T: FromKut<'template>, S: BuildHasher + Default> FromKut<'template> for HashMap<String, T, S> {
+    fn from_kut(value: KutValue<'template>) -> Result<Self, KutError> {
+        let map = match value.dereferenced() {
+            KutValue::Map(map) => map,
+            other => return other.conversion_error("Map"),
+        };
+        map.iter().map(|(key, value)| Ok((String::from_kut(key.0.clone())?, T::from_kut(value.clone())?))).collect()
+    }
+}
+
+impl<'template, T: IntoKut<'template>, S> IntoKut<'template> for HashMap<String, T, S> {
+    fn into_kut(self) -> KutValue<'template> {
+        let mut map = KutMap::new();
+        for (key, value) in self {
+            map.insert(KutKey(key.into_kut()), value.into_kut());
+        }
+        KutValue::Map(Rc::new(map))
+    }
+}
+
+/// Arguments of a call from Rust: `()`, a tuple of `IntoKut` values or a `Vec` of
+/// values that are already converted.
+pub trait KutArguments<'template> {
+    fn into_arguments(self) -> Vec<KutValue<'template>>;
+}
+
+impl<'template> KutArguments<'template> for () {
+    fn into_arguments(self) -> Vec<KutValue<'template>> {
+        vec![]
+    }
+}
+
+impl<'template> KutArguments<'template> for Vec<KutValue<'template>> {
+    fn into_arguments(self) -> Vec<KutValue<'template>> {
+        self
+    }
+}
+
+/// Tuples convert from a `List` or a `Vector` of the same length, and to a `List`.
+macro_rules! impl_tuple_conversion {
+    ($count:literal, $($item:ident),*) => {
+        impl<'template, $($item: FromKut<'template>),*> FromKut<'template> for ($($item,)*) {
+            fn from_kut(value: KutValue<'template>) -> Result<Self, KutError> {
+                let items = value.into_items(concat!("List of ", $count))?;
+                if items.len() != $count {
+                    return Err(KutError::InvalidConversion { expected: concat!("List of ", $count).to_owned(), value_type: format!("List of {}", items.len()) });
+                }
+                let mut items = items.into_iter();
+                Ok(($($item::from_kut(items.next().unwrap_or(KutValue::Nil))?,)*))
+            }
+        }
+
+        impl<'template, $($item: IntoKut<'template>),*> IntoKut<'template> for ($($item,)*) {
+            #[allow(non_snake_case)]
+            fn into_kut(self) -> KutValue<'template> {
+                let ($($item,)*) = self;
+                KutValue::List(Rc::new(vec![$($item.into_kut()),*]))
+            }
+        }
+
+        impl<'template, $($item: IntoKut<'template>),*> KutArguments<'template> for ($($item,)*) {
+            #[allow(non_snake_case)]
+            fn into_arguments(self) -> Vec<KutValue<'template>> {
+                let ($($item,)*) = self;
+                vec![$($item.into_kut()),*]
+            }
+        }
+    };
+}
+
+impl_tuple_conversion!(1, A);
+impl_tuple_conversion!(2, A, B);
+impl_tuple_conversion!(3, A, B, C);
+impl_tuple_conversion!(4, A, B, C, D);
+impl_tuple_conversion!(5, A, B, C, D, E);
+impl_tuple_conversion!(6, A, B, C, D, E, F);
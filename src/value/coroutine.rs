@@ -0,0 +1,137 @@
+use crate::value::*;
+use crate::vm::*;
+
+impl KutCoroutineStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KutCoroutineStatus::Suspended => "suspended",
+            KutCoroutineStatus::Running => "running",
+            KutCoroutineStatus::Dead => "dead",
+        }
+    }
+}
+
+/// A coroutine keeps its suspended frame, with the frames of the functions it called,
+/// while it is not running. Resuming moves the frame into the resumer's callee, so the
+/// coroutine runs like a call and stays resumable after `KutError::OutOfFuel`. A yield
+/// unwinds to the resumer as `KutError::Yielded`, which leaves every frame in place,
+/// and the resumer moves the frame back into the coroutine.
+impl<'template> KutCoroutine<'template> {
+    /// Creates a suspended coroutine that starts `closure` on its first resume.
+    pub fn new(closure: &Rc<KutClosure<'template>>) -> KutCoroutine<'template> {
+        KutCoroutine {
+            closure: Rc::clone(closure),
+            frame: RefCell::new(Some(closure.start())),
+            status: Cell::new(KutCoroutineStatus::Suspended),
+        }
+    }
+
+    pub fn status(&self) -> KutCoroutineStatus {
+        self.status.get()
+    }
+
+    /// Resumes the coroutine from the host with `value`, returning the value it yields
+    /// or, once its function returned, the returned value.
+    pub fn resume(&self, vm: &'template KutVm<'template>, value: KutValue<'template>) -> Result<KutValue<'template>, KutError> {
+        self.advance(vm, Some(value))
+    }
+
+    /// Resumes the coroutine from the host, passing `value` as `take_frame` does.
+    pub(crate) fn advance(&self, vm: &'template KutVm<'template>, value: Option<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+        let mut frame = self.take_frame(vm, value)?;
+        match frame.run(vm) {
+            Ok(value) => {
+                self.finish();
+                Ok(value.unwrap_or(KutValue::Nil))
+            },
+            Err(KutError::Yielded) => {
+                self.suspend(frame);
+                Ok(vm.take_yielded())
+            },
+            Err(error) => {
+                self.suspend(frame);
+                Err(error)
+            },
+        }
+    }
+
+    /// Takes the suspended frame to run it. On the first resume the function starts with
+    /// `value` as its only argument, or without arguments for `None`, like a call. Later
+    /// `value` becomes the result of the pending `CoroYieldRg` or native, nil for `None`.
+    /// The coroutine stays suspended when this fails.
+    pub(crate) fn take_frame(&self, vm: &'template KutVm<'template>, value: Option<KutValue<'template>>) -> Result<KutFunction<'template>, KutError> {
+        let mut slot = self.frame.borrow_mut();
+        let Some(suspended) = slot.as_mut() else {
+            return Err(KutError::InvalidResume { status: self.status.get().as_str().to_owned() });
+        };
+        if suspended.entered {
+            suspended.resume_with(value.unwrap_or(KutValue::Nil))?;
+        } else {
+            *suspended = self.closure.start_with(vm, value.into_iter().collect())?;
+        }
+        self.status.set(KutCoroutineStatus::Running);
+        Ok(slot.take().expect("frame is suspended"))
+    }
+
+    /// Stores `frame` back after it yielded or failed.
+    pub(crate) fn suspend(&self, frame: KutFunction<'template>) {
+        *self.frame.borrow_mut() = Some(frame);
+        self.status.set(KutCoroutineStatus::Suspended);
+    }
+
+    /// Marks the coroutine dead after its function returned.
+    pub(crate) fn finish(&self) {
+        self.status.set(KutCoroutineStatus::Dead);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::value::*;
+    use crate::vm::*;
+    use KutInstruction::*;
+
+    /// Adds its two parameters, the second defaulting to the literal 10.
+    fn add() -> KutFunctionTemplate {
+        let body = vec![AddNumbersR { reg: 2, lhs: 0, rhs: 1 }, RetfMethodR { value: 2 }];
+        KutFunctionTemplate::new(body, vec![], 3).with_parameters(KutParameters::new(1).with_defaults(vec![0]))
+    }
+
+    /// Yields its parameter, which defaults to the literal 10, then returns nil.
+    fn generator() -> KutFunctionTemplate {
+        let body = vec![CoroYieldRg { reg: 1, value: 0 }, RetfMethodR { value: 1 }];
+        KutFunctionTemplate::new(body, vec![], 2).with_parameters(KutParameters::new(0).with_defaults(vec![0]))
+    }
+
+    fn closure<'template>(vm: &'template KutVm<'template>, template: usize) -> Rc<KutClosure<'template>> {
+        Rc::new(KutClosure { template: &vm.templates[template], captures: vec![] })
+    }
+
+    #[test]
+    fn first_resume_binds_parameters() {
+        let vm = &KutVm::new(vec![KutValue::Integer(10)], vec![add()]);
+        let coroutine = KutCoroutine::new(&closure(vm, 0));
+        assert!(matches!(coroutine.resume(vm, KutValue::Integer(5)), Ok(KutValue::Integer(15))));
+        assert_eq!(coroutine.status(), KutCoroutineStatus::Dead);
+    }
+
+    #[test]
+    fn wrong_argument_count_leaves_the_coroutine_suspended() {
+        let template = add().with_parameters(KutParameters::new(2));
+        let vm = &KutVm::new(vec![KutValue::Integer(10)], vec![template]);
+        let coroutine = KutCoroutine::new(&closure(vm, 0));
+        assert!(matches!(coroutine.resume(vm, KutValue::Integer(5)), Err(KutError::WrongArgumentCount { min: 2, given: 1, .. })));
+        assert_eq!(coroutine.status(), KutCoroutineStatus::Suspended);
+    }
+
+    #[test]
+    fn iterators_start_functions_and_coroutines_without_arguments() {
+        let vm = &KutVm::new(vec![KutValue::Integer(10)], vec![generator(), add()]);
+        let coroutine = Rc::new(KutCoroutine::new(&closure(vm, 0)));
+        let iterator = KutIterator::new(&KutValue::Coroutine(coroutine)).unwrap();
+        assert!(matches!(iterator.next(vm), Ok(Some(KutValue::Integer(10)))));
+        assert!(matches!(iterator.next(vm), Ok(None)));
+        let iterator = KutIterator::new(&KutValue::Func(closure(vm, 1))).unwrap();
+        assert!(matches!(iterator.next(vm), Err(KutError::WrongArgumentCount { min: 1, given: 0, .. })));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::value::*;
+use std::fmt;
+
+/// Kut printing rules: integral numbers print without a fractional part, strings
+/// print raw with `{}` and quoted and escaped with `{:#}` or inside containers,
+/// references print as the value they hold and a container that contains itself
+/// prints as `...` where it recurs.
+impl<'template> KutValue<'template> {
+    fn write_number(f: &mut fmt::Formatter<'_>, num: f64) -> fmt::Result {
+        if num.is_nan() {
+            f.write_str("nan")
+        } else if num.is_infinite() {
+            f.write_str(if num > 0.0 { "inf" } else { "-inf" })
+        } else if num != 0.0 && (num.abs() >= 1e16 || num.abs() < 1e-6) {
+            write!(f, "{num:e}")
+        } else {
+            write!(f, "{num}")
+        }
+    }
+
+    fn write_string_repr(f: &mut fmt::Formatter<'_>, string: &str) -> fmt::Result {
+        f.write_str("\"")?;
+        for c in string.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+                c => write!(f, "{c}")?,
+            }
+        }
+        f.write_str("\"")
+    }
+
+    fn write_items<'a>(f: &mut fmt::Formatter<'_>, items: impl Iterator<Item = &'a KutValue<'template>>, seen: &mut Vec<usize>) -> fmt::Result where 'template: 'a {
+        for (position, item) in items.enumerate() {
+            if position > 0 {
+                f.write_str(", ")?;
+            }
+            item.write_value(f, true, seen)?;
+        }
+        Ok(())
+    }
+
+    fn write_value(&self, f: &mut fmt::Formatter<'_>, repr: bool, seen: &mut Vec<usize>) -> fmt::Result {
+        let identity = self.identity();
+        if let Some(identity) = identity {
+            if seen.contains(&identity) {
+                return f.write_str("...");
+            }
+            seen.push(identity);
+        }
+        let result = match self {
+            KutValue::Nil => f.write_str("nil"),
+            KutValue::Undefined => f.write_str("undefined"),
+            KutValue::Bool(b) => write!(f, "{b}"),
+            KutValue::Integer(int) => write!(f, "{int}"),
+            KutValue::Number(num) => KutValue::write_number(f, *num),
+            KutValue::String(string) if repr => KutValue::write_string_repr(f, string),
+            KutValue::String(string) => f.write_str(string),
+            KutValue::List(list) => {
+                f.write_str("[")?;
+                KutValue::write_items(f, list.iter(), seen)?;
+                f.write_str("]")
+            },
+            KutValue::Vector(vector) => {
+                f.write_str("vector[")?;
+                KutValue::write_items(f, vector.iter(), seen)?;
+                f.write_str("]")
+            },
+            KutValue::Map(map) => {
+                f.write_str("{")?;
+                for (position, (key, value)) in map.iter().enumerate() {
+                    if position > 0 {
+                        f.write_str(", ")?;
+                    }
+                    key.0.write_value(f, true, seen)?;
+                    f.write_str(": ")?;
+                    value.write_value(f, true, seen)?;
+                }
+                f.write_str("}")
+            },
+            KutValue::Func(func) => {
+                f.write_str("<function")?;
+                if let Some(name) = &func.template.name {
+                    write!(f, " {name}")?;
+                }
+                if let Some(parameters) = &func.template.parameters {
+                    write!(f, "/{parameters}")?;
+                }
+                f.write_str(">")
+            },
+            KutValue::Reference(r) => r.borrow().write_value(f, repr, seen),
+            KutValue::Native(native) => write!(f, "<native {}>", native.name),
+            KutValue::Coroutine(coroutine) => match &coroutine.closure.template.name {
+                Some(name) => write!(f, "<coroutine {name}>"),
+                None => f.write_str("<coroutine>"),
+            },
+            KutValue::Iterator(_) => f.write_str("<iterator>"),
+            KutValue::External(ext) => write!(f, "<external {}>", ext.type_name),
+        };
+        if identity.is_some() {
+            seen.pop();
+        }
+        result
+    }
+
+    pub fn to_display_string(&self) -> String {
+        format!("{self}")
+    }
+
+    pub fn to_repr_string(&self) -> String {
+        format!("{self:#}")
+    }
+}
+
+/// Prints the number of arguments a function takes: `2`, `1-3` with defaults or `1+`
+/// with a rest list.
+impl fmt::Display for KutParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.rest {
+            write!(f, "{}+", self.required)
+        } else if self.defaults.is_empty() {
+            write!(f, "{}", self.required)
+        } else {
+            write!(f, "{}-{}", self.required, self.required as usize + self.defaults.len())
+        }
+    }
+}
+
+impl<'template> fmt::Display for KutValue<'template> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_value(f, f.alternate(), &mut vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::value::*;
+
+    fn function(template: &KutFunctionTemplate) -> String {
+        KutValue::Func(Rc::new(KutClosure { template, captures: vec![] })).to_display_string()
+    }
+
+    #[test]
+    fn functions_print_their_name_and_arity() {
+        let template = |parameters: Option<KutParameters>| {
+            let template = KutFunctionTemplate::new(vec![], vec![], 4).with_name("f");
+            match parameters {
+                Some(parameters) => template.with_parameters(parameters),
+                None => template,
+            }
+        };
+        assert_eq!(function(&template(None)), "<function f>");
+        assert_eq!(function(&template(Some(KutParameters::new(2)))), "<function f/2>");
+        assert_eq!(function(&template(Some(KutParameters::new(1).with_defaults(vec![0, 1])))), "<function f/1-3>");
+        assert_eq!(function(&template(Some(KutParameters::new(1).with_rest()))), "<function f/1+>");
+        assert_eq!(function(&KutFunctionTemplate::new(vec![], vec![], 0)), "<function>");
+        assert_eq!(function(&KutFunctionTemplate::new(vec![], vec![], 0).with_parameters(KutParameters::new(0))), "<function/0>");
+    }
+
+    fn text<'template>(text: &str) -> KutValue<'template> {
+        KutValue::String(Rc::new(text.to_owned()))
+    }
+
+    #[test]
+    fn strings_print_raw_only_at_the_top_level() {
+        let string = text("a \"b\"\n\u{1}");
+        assert_eq!(format!("{string}"), "a \"b\"\n\u{1}");
+        assert_eq!(format!("{string:#}"), r#""a \"b\"\n\u{1}""#);
+        let list = KutValue::List(Rc::new(vec![text("x"), KutValue::Integer(1), KutValue::Number(1.5)]));
+        assert_eq!(format!("{list}"), r#"["x", 1, 1.5]"#);
+        assert_eq!(format!("{list:#}"), r#"["x", 1, 1.5]"#);
+        let mut map = KutMap::new();
+        map.insert(KutKey(text("k")), text("v"));
+        map.insert(KutKey(KutValue::Integer(2)), KutValue::Vector(Rc::new([text("\t")].into_iter().collect())));
+        let map = KutValue::Map(Rc::new(map));
+        assert_eq!(format!("{map}"), r#"{"k": "v", 2: vector["\t"]}"#);
+        assert_eq!(map.to_repr_string(), map.to_display_string());
+        let reference = KutValue::Reference(Rc::new(RefCell::new(text("r"))));
+        assert_eq!(format!("{reference}"), "r");
+        assert_eq!(format!("{reference:#}"), r#""r""#);
+        assert_eq!(format!("{}", KutValue::List(Rc::new(vec![reference]))), r#"["r"]"#);
+    }
+
+    #[test]
+    fn containers_print_as_dots_where_they_recur() {
+        let cell = Rc::new(RefCell::new(KutValue::Nil));
+        let list = KutValue::List(Rc::new(vec![KutValue::Integer(1), KutValue::Reference(Rc::clone(&cell))]));
+        *cell.borrow_mut() = list.clone();
+        assert_eq!(format!("{list}"), "[1, ...]");
+        let mut map = KutMap::new();
+        map.insert(KutKey(text("self")), KutValue::Reference(Rc::clone(&cell)));
+        *cell.borrow_mut() = KutValue::Map(Rc::new(map));
+        assert_eq!(format!("{}", KutValue::Reference(Rc::clone(&cell))), r#"{"self": ...}"#);
+        *cell.borrow_mut() = KutValue::Nil;
+        let shared = KutValue::List(Rc::new(vec![KutValue::Integer(2)]));
+        let twice = KutValue::List(Rc::new(vec![shared.clone(), shared]));
+        assert_eq!(format!("{twice}"), "[[2], [2]]");
+    }
+}
@@ -1,12 +1,363 @@
 use crate::value::*;
 use crate::vm::*;
+use crate::value::compiled::*;
 // use crate::value::instruction::*;
 
-impl<'closure, 'template> KutFunction<'closure, 'template> {
-    pub fn run(&mut self, vm: &'closure KutVm<'template>) -> KutReturnType<'template> {
-        for instruction in self.closure.template.instructions.iter() {
-            instruction.run(self, vm)?;
+impl<'template> KutFunction<'template> {
+    /// Runs the function until it returns or fails. A failed function keeps its
+    /// program counter and active callees, so `run` can be called again to resume.
+    /// `KutError::OutOfFuel`, `KutError::Interrupted` and errors of hooks are raised
+    /// before the next instruction runs, which resuming runs as if nothing happened.
+    /// Any other error is raised by the instruction, which then stays the next one:
+    /// resuming runs it again, so it fails again unless the host removed the cause,
+    /// e.g. with `KutVm::set_max_call_depth`. Instructions check their operands
+    /// before changing anything, but may have changed their operands when they fail
+    /// with `KutError::OutOfMemory`. A yield inside a coroutine fails with
+    /// `KutError::Yielded` up to the frame that resumed it, and a pending native fails
+    /// with `KutError::Pending` up to the host; both continue after their instruction.
+    pub fn run(&mut self, vm: &'template KutVm<'template>) -> KutReturnType<'template> {
+        vm.enter_frame(|| self.run_frame(vm))
+    }
+
+    fn run_frame(&mut self, vm: &'template KutVm<'template>) -> KutReturnType<'template> {
+        if !self.entered {
+            self.entered = true;
+            if vm.has_hook() {
+                if let Some(hook) = vm.hook.borrow_mut().as_mut() {
+                    hook.enter_function(vm, self)?;
+                }
+            }
+        }
+        self.finish_call(vm)?;
+        let template = self.closure.template;
+        if vm.engine == KutEngine::Threaded && self.registers.len() >= template.register_count as usize && !vm.has_hook() {
+            return self.run_threaded(vm);
+        }
+        while let Some(instruction) = template.instructions.get(self.pc) {
+            let pc = self.pc;
+            vm.check_interrupt()?;
+            vm.consume_fuel(instruction)?;
+            if vm.has_hook() {
+                if let Some(hook) = vm.hook.borrow_mut().as_mut() {
+                    hook.before_instruction(vm, self, pc, instruction)?;
+                }
+            }
+            self.pc += 1;
+            match instruction.run(self, vm) {
+                Ok(None) => self.finish_call(vm)?,
+                Ok(Some(value)) => return self.exit(vm, Some(value)),
+                Err(error @ (KutError::Yielded | KutError::Pending)) => return Err(error),
+                Err(error) => {
+                    self.pc = pc;
+                    if vm.has_hook() {
+                        if let Some(hook) = vm.hook.borrow_mut().as_mut() {
+                            hook.instruction_error(vm, self, pc, instruction, &error);
+                        }
+                    }
+                    return Err(error);
+                },
+            }
+        }
+        self.exit(vm, None)
+    }
+
+    /// Runs the pre-decoded ops of the template, see `KutEngine::Threaded`. Hooks are not
+    /// called, so it is only used while none is installed.
+    fn run_threaded(&mut self, vm: &'template KutVm<'template>) -> KutReturnType<'template> {
+        let template = self.closure.template;
+        let ops = template.compiled.get_or_init(|| KutOp::decode_template(vm, template));
+        while let Some(op) = ops.get(self.pc) {
+            let pc = self.pc;
+            vm.check_interrupt()?;
+            vm.consume_fuel(&template.instructions[pc])?;
+            self.pc += 1;
+            match op.run(self, vm) {
+                Ok(None) => {
+                    if self.callee.is_some() {
+                        self.finish_call(vm)?;
+                    }
+                },
+                Ok(Some(value)) => return Ok(Some(value)),
+                Err(error @ (KutError::Yielded | KutError::Pending)) => return Err(error),
+                Err(error) => {
+                    self.pc = pc;
+                    return Err(error);
+                },
+            }
         }
         Ok(None)
     }
+
+    /// Stores `value` where the innermost active frame waits for it, i.e. as the
+    /// result of a pending native or of a yield. The next `run` continues from there.
+    pub fn resume_with(&mut self, value: KutValue<'template>) -> Result<(), KutError> {
+        let mut innermost = self;
+        while innermost.callee.is_some() {
+            innermost = &mut innermost.callee.as_mut().expect("callee is set").frame;
+        }
+        match innermost.resume_target.take() {
+            Some(target) => innermost.store_result(target, value, false),
+            None => Ok(()),
+        }
+    }
+
+    fn finish_call(&mut self, vm: &'template KutVm<'template>) -> Result<(), KutError> {
+        let Some(call) = self.callee.as_mut() else {
+            return Ok(());
+        };
+        let (value, yielded) = match call.frame.run(vm) {
+            Ok(value) => (value.unwrap_or(KutValue::Nil), false),
+            Err(KutError::Yielded) if call.coroutine.is_some() => (vm.take_yielded(), true),
+            Err(error) => return Err(error),
+        };
+        let KutCall { mut frame, target, coroutine } = *self.callee.take().expect("callee is set");
+        if coroutine.is_none() && frame.multiple_results {
+            return self.store_results(target, std::mem::take(&mut frame.call_stack));
+        }
+        let coroutine_returned = match coroutine {
+            Some(coroutine) if yielded => {
+                coroutine.suspend(frame);
+                false
+            },
+            Some(coroutine) => {
+                coroutine.finish();
+                true
+            },
+            None => false,
+        };
+        self.store_result(target, value, coroutine_returned)
+    }
+
+    /// Takes the values the finished frame returned, given the `value` that `run`
+    /// returned: all of them after `RetfMultiRg` or `RetfMultiSt`, otherwise `value`.
+    pub fn take_results(&mut self, value: Option<KutValue<'template>>) -> Vec<KutValue<'template>> {
+        if self.multiple_results {
+            std::mem::take(&mut self.call_stack)
+        } else {
+            value.into_iter().collect()
+        }
+    }
+
+    /// Stores the results of a call that returned several values.
+    fn store_results(&mut self, target: KutReturnTarget, values: Vec<KutValue<'template>>) -> Result<(), KutError> {
+        let mut padded = values.into_iter().chain(std::iter::repeat(KutValue::Nil));
+        match target {
+            KutReturnTarget::Registers { first, count } => {
+                for (reg, value) in (first..=u8::MAX).zip(padded.take(count as usize)) {
+                    KutInstruction::set_register_value(self, reg, value)?;
+                }
+                Ok(())
+            },
+            KutReturnTarget::StackValues { count } => {
+                self.call_stack.extend(padded.take(count as usize));
+                Ok(())
+            },
+            target => self.store_result(target, padded.next().unwrap_or(KutValue::Nil), false),
+        }
+    }
+
+    /// Stores the result of a call. An iterator item jumps to the exit of the loop instead
+    /// when it is `Undefined`, or when `exhausted` because a coroutine iterator returned.
+    pub(crate) fn store_result(&mut self, target: KutReturnTarget, value: KutValue<'template>, exhausted: bool) -> Result<(), KutError> {
+        match target {
+            KutReturnTarget::Registers { .. } | KutReturnTarget::StackValues { .. } => self.store_results(target, vec![value]),
+            KutReturnTarget::Register(reg) => KutInstruction::set_register_value(self, reg, value),
+            KutReturnTarget::Stack => {
+                self.call_stack.push(value);
+                Ok(())
+            },
+            KutReturnTarget::Iterator { exit, .. } if exhausted || matches!(value, KutValue::Undefined) => {
+                self.pc = exit as usize;
+                Ok(())
+            },
+            KutReturnTarget::Iterator { reg, .. } => KutInstruction::set_register_value(self, reg, value),
+            KutReturnTarget::Discard => Ok(()),
+        }
+    }
+
+    fn exit(&mut self, vm: &'template KutVm<'template>, value: Option<KutValue<'template>>) -> KutReturnType<'template> {
+        if vm.has_hook() {
+            if let Some(hook) = vm.hook.borrow_mut().as_mut() {
+                hook.exit_function(vm, self, value.as_ref())?;
+            }
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::value::*;
+    use crate::vm::*;
+    use KutInstruction::*;
+
+    /// `down(n)` calls itself through the global `down` until `n` is 0.
+    fn recursive_vm<'template>() -> KutVm<'template> {
+        let down = vec![
+            GetLiteralR { reg: 1, literal: 0 },
+            CompareLeqR { reg: 2, lhs: 0, rhs: 1 },
+            JumpIfFalse { cond: 2, target: 4 },
+            RetfMethodR { value: 0 },
+            GetGlobalRg { reg: 3, name: 2 },
+            GetLiteralR { reg: 4, literal: 1 },
+            SubNumbersR { reg: 4, lhs: 0, rhs: 4 },
+            PushValue1R { val1: 4 },
+            CallMethodR { ret_position: 5, arg_count: 1, subject: 3 },
+            RetfMethodR { value: 5 },
+        ];
+        let define = vec![CaptureFunc { reg: 0, template: 0 }, SetGlobalRg { reg: 0, name: 2 }];
+        KutVm::new(vec![KutValue::Integer(0), KutValue::Integer(1), KutValue::String(Rc::new("down".to_owned()))], vec![
+            KutFunctionTemplate::new(down, vec![], 6).with_name("down"),
+            KutFunctionTemplate::new(define, vec![], 1).with_name("define"),
+        ])
+    }
+
+    #[test]
+    fn deep_recursion_fails_with_call_depth_exceeded() {
+        for engine in [KutEngine::Reference, KutEngine::Threaded] {
+            let mut vm = recursive_vm();
+            vm.engine = engine;
+            let vm = &vm;
+            vm.call::<KutValue>("define", ()).unwrap();
+            assert_eq!(vm.call::<i64>("down", (150,)).unwrap(), 0);
+            let error = vm.call::<i64>("down", (100_000,)).unwrap_err();
+            assert!(matches!(error, KutError::CallDepthExceeded { depth: 200 }), "{error:?}");
+            assert_eq!(vm.call::<i64>("down", (10,)).unwrap(), 0);
+        }
+    }
+
+    fn start<'template>(vm: &'template KutVm<'template>, template: usize, args: Vec<KutValue<'template>>) -> KutFunction<'template> {
+        Rc::new(KutClosure { template: &vm.templates[template], captures: vec![] }).start_with(vm, args).unwrap()
+    }
+
+    #[test]
+    fn failed_call_runs_again_on_resume() {
+        for engine in [KutEngine::Reference, KutEngine::Threaded] {
+            let mut vm = recursive_vm();
+            vm.engine = engine;
+            let vm = &vm;
+            vm.call::<KutValue>("define", ()).unwrap();
+            vm.set_max_call_depth(5);
+            let mut frame = start(vm, 0, vec![KutValue::Integer(8)]);
+            assert!(matches!(frame.run(vm), Err(KutError::CallDepthExceeded { depth: 5 })));
+            vm.set_max_call_depth(200);
+            assert!(matches!(frame.run(vm), Ok(Some(KutValue::Integer(0)))));
+        }
+    }
+
+    /// Counts the events it sees into a cell shared with the test.
+    struct Counter(Rc<std::cell::Cell<[usize; 4]>>);
+
+    impl crate::hook::KutHook for Counter {
+        fn enter_function(&mut self, _vm: &KutVm, _frame: &KutFunction) -> Result<(), KutError> {
+            let [enter, exit, before, error] = self.0.get();
+            self.0.set([enter + 1, exit, before, error]);
+            Ok(())
+        }
+
+        fn exit_function(&mut self, _vm: &KutVm, _frame: &KutFunction, _value: Option<&KutValue>) -> Result<(), KutError> {
+            let [enter, exit, before, error] = self.0.get();
+            self.0.set([enter, exit + 1, before, error]);
+            Ok(())
+        }
+
+        fn before_instruction(&mut self, _vm: &KutVm, _frame: &KutFunction, _pc: usize, _instruction: &KutInstruction) -> Result<(), KutError> {
+            let [enter, exit, before, error] = self.0.get();
+            self.0.set([enter, exit, before + 1, error]);
+            Ok(())
+        }
+
+        fn instruction_error(&mut self, _vm: &KutVm, _frame: &KutFunction, _pc: usize, _instruction: &KutInstruction, _error: &KutError) {
+            let [enter, exit, before, error] = self.0.get();
+            self.0.set([enter, exit, before, error + 1]);
+        }
+    }
+
+    #[test]
+    fn hooks_see_every_event_until_taken() {
+        for engine in [KutEngine::Reference, KutEngine::Threaded] {
+            let add = vec![
+                AddNumbersR { reg: 2, lhs: 0, rhs: 1 },
+                RetfMethodR { value: 2 },
+            ];
+            let mut vm = KutVm::new(vec![], vec![KutFunctionTemplate::new(add, vec![], 3)]);
+            vm.engine = engine;
+            let vm = &vm;
+            let counts = Rc::new(std::cell::Cell::new([0; 4]));
+            assert!(!vm.has_hook());
+            assert!(vm.set_hook(Box::new(Counter(Rc::clone(&counts)))).is_none());
+            assert!(vm.has_hook());
+            let mut frame = start(vm, 0, vec![KutValue::Integer(1), KutValue::Integer(2)]);
+            assert!(matches!(frame.run(vm), Ok(Some(KutValue::Integer(3)))));
+            assert_eq!(counts.get(), [1, 1, 2, 0]);
+            let mut frame = start(vm, 0, vec![KutValue::Integer(1), KutValue::Nil]);
+            assert!(frame.run(vm).is_err());
+            assert_eq!(counts.get(), [2, 1, 3, 1]);
+            assert!(vm.take_hook().is_some());
+            assert!(!vm.has_hook());
+            let mut frame = start(vm, 0, vec![KutValue::Integer(1), KutValue::Integer(2)]);
+            assert!(matches!(frame.run(vm), Ok(Some(KutValue::Integer(3)))));
+            assert_eq!(counts.get(), [2, 1, 3, 1]);
+        }
+    }
+
+    #[test]
+    fn multiple_results_are_padded_or_truncated_to_the_target() {
+        let caller = || vec![
+            CaptureFunc { reg: 0, template: 1 },
+            CallMultiRg { first: 1, count: 3, arg_count: 0, subject: 0 },
+            CallMultiRg { first: 4, count: 1, arg_count: 0, subject: 0 },
+            CallMethodR { ret_position: 5, arg_count: 0, subject: 0 },
+            CallMultiSt { count: 3, arg_count: 0, subject: 0 },
+            CallMethodS { arg_count: 0, subject: 0 },
+            CaptureFunc { reg: 0, template: 2 },
+            CallMultiRg { first: 6, count: 2, arg_count: 0, subject: 0 },
+            CallMethodS { arg_count: 0, subject: 0 },
+            RetfMethodR { value: 1 },
+        ];
+        let pair = || vec![
+            GetLiteralR { reg: 0, literal: 0 },
+            GetLiteralR { reg: 1, literal: 1 },
+            RetfMultiRg { first: 0, count: 2 },
+        ];
+        let nothing = || vec![RetfMultiSt { count: 0 }];
+        for engine in [KutEngine::Reference, KutEngine::Threaded] {
+            let mut vm = KutVm::new(vec![KutValue::Integer(1), KutValue::Integer(2)], vec![
+                KutFunctionTemplate::new(caller(), vec![], 8),
+                KutFunctionTemplate::new(pair(), vec![], 2),
+                KutFunctionTemplate::new(nothing(), vec![], 0),
+            ]);
+            vm.engine = engine;
+            let vm = &vm;
+            let mut frame = start(vm, 0, vec![KutValue::Nil; 8]);
+            assert!(matches!(frame.run(vm), Ok(Some(KutValue::Integer(1)))));
+            let registers: Vec<_> = frame.registers[1..].iter().map(|value| value.as_i64().ok()).collect();
+            assert_eq!(registers, [Some(1), Some(2), None, Some(1), Some(1), None, None], "{engine:?}");
+            let stack: Vec<_> = frame.call_stack.iter().map(|value| value.as_i64().ok()).collect();
+            assert_eq!(stack, [Some(1), Some(2), None, Some(1), None], "{engine:?}");
+        }
+    }
+
+    #[test]
+    fn failed_instruction_is_not_skipped() {
+        let add = vec![
+            AddNumbersR { reg: 2, lhs: 0, rhs: 1 },
+            RetfMethodR { value: 2 },
+        ];
+        let vm = &KutVm::new(vec![], vec![KutFunctionTemplate::new(add, vec![], 3)]);
+        let mut frame = start(vm, 0, vec![KutValue::Integer(1), KutValue::Nil]);
+        assert!(matches!(frame.run(vm), Err(KutError::InvalidOperand { .. })));
+        assert_eq!(frame.pc, 0);
+        frame.registers[1] = KutValue::Integer(2);
+        assert!(matches!(frame.run(vm), Ok(Some(KutValue::Integer(3)))));
+    }
+
+    #[test]
+    fn max_call_depth_is_configurable() {
+        let vm = &recursive_vm();
+        vm.set_max_call_depth(10);
+        vm.call::<KutValue>("define", ()).unwrap();
+        assert_eq!(vm.call::<i64>("down", (9,)).unwrap(), 0);
+        assert!(matches!(vm.call::<i64>("down", (10,)), Err(KutError::CallDepthExceeded { depth: 10 })));
+    }
 }
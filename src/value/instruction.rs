@@ -1,13 +1,16 @@
 use crate::value::*;
+use crate::value::iterator::*;
 use crate::vm::*;
+use crate::memory::*;
+use crate::list::*;
 
 /// Instruction executor
 impl KutInstruction {
-    pub fn run<'closure, 'template>(&self, context: &mut KutFunction<'closure, 'template>, vm: &'closure KutVm<'template>) -> KutReturnType<'template> {
+    pub fn run<'template>(&self, context: &mut KutFunction<'template>, vm: &'template KutVm<'template>) -> KutReturnType<'template> {
         match self {
             KutInstruction::NoOperation => KutInstruction::handle_no_operation(),
-            KutInstruction::CallMethodR { ret_position, arg_count, subject } => KutInstruction::handle_call_method_r(context, *ret_position, *arg_count, *subject),
-            KutInstruction::CallMethodS { arg_count, subject } => KutInstruction::handle_call_method_s(context, *arg_count, *subject),
+            KutInstruction::CallMethodR { ret_position, arg_count, subject } => KutInstruction::handle_call_method_r(context, vm, *ret_position, *arg_count, *subject),
+            KutInstruction::CallMethodS { arg_count, subject } => KutInstruction::handle_call_method_s(context, vm, *arg_count, *subject),
             KutInstruction::CaptureFunc { reg, template } => KutInstruction::handle_capture_function(context, vm, *reg, *template),
             KutInstruction::GetCaptureR { reg, capture } => KutInstruction::handle_get_capture_r(context, *reg, *capture),
             KutInstruction::GetLiteralR { reg, literal } => KutInstruction::handle_get_literal(context, vm, *reg, *literal),
@@ -21,16 +24,82 @@ impl KutInstruction {
             KutInstruction::PushValue3R { val1, val2, val3 } => KutInstruction::handle_push_value_3(context, *val1, *val2, *val3),
             KutInstruction::RetfMethodR { value } => KutInstruction::handle_ret_r(context, *value),
             KutInstruction::RetfMethodS => KutInstruction::handle_ret_s(context),
+            KutInstruction::RetfMultiRg { first, count } => KutInstruction::handle_ret_multiple_r(context, *first, *count),
+            KutInstruction::RetfMultiSt { count } => KutInstruction::handle_ret_multiple_s(context, *count),
+            KutInstruction::CallMultiRg { first, count, arg_count, subject } => KutInstruction::handle_call_multiple_r(context, vm, *first, *count, *arg_count, *subject),
+            KutInstruction::CallMultiSt { count, arg_count, subject } => KutInstruction::handle_call_multiple_s(context, vm, *count, *arg_count, *subject),
             KutInstruction::SetCaptureR { reg, capture } => KutInstruction::handle_set_capture_r(context, *reg, *capture),
             KutInstruction::SwapValuesR { reg1, reg2 } => KutInstruction::handle_swap_values(context, *reg1, *reg2),
+            KutInstruction::NewEmptyMap { reg } => KutInstruction::handle_new_empty_map(context, vm, *reg),
+            KutInstruction::MapGetValue { reg, map, key } => KutInstruction::handle_map_get_value(context, *reg, *map, *key),
+            KutInstruction::MapSetValue { map, key, value } => KutInstruction::handle_map_set_value(context, vm, *map, *key, *value),
+            KutInstruction::MapDelValue { map, key } => KutInstruction::handle_map_del_value(context, vm, *map, *key),
+            KutInstruction::MapContains { reg, map, key } => KutInstruction::handle_map_contains(context, *reg, *map, *key),
+            KutInstruction::MapGetKeysR { reg, map } => KutInstruction::handle_map_get_keys(context, vm, *reg, *map),
+            KutInstruction::MapGetCount { reg, map } => KutInstruction::handle_map_get_count(context, *reg, *map),
+            KutInstruction::CompareEqlR { reg, lhs, rhs } => KutInstruction::handle_compare(context, *reg, *lhs, *rhs, |lhs, rhs| lhs == rhs),
+            KutInstruction::CompareNeqR { reg, lhs, rhs } => KutInstruction::handle_compare(context, *reg, *lhs, *rhs, |lhs, rhs| lhs != rhs),
+            KutInstruction::CompareLssR { reg, lhs, rhs } => KutInstruction::handle_compare(context, *reg, *lhs, *rhs, |lhs, rhs| lhs < rhs),
+            KutInstruction::CompareLeqR { reg, lhs, rhs } => KutInstruction::handle_compare(context, *reg, *lhs, *rhs, |lhs, rhs| lhs <= rhs),
+            KutInstruction::CompareOrdR { reg, lhs, rhs } => KutInstruction::handle_compare_ord(context, *reg, *lhs, *rhs),
+            KutInstruction::AddNumbersR { reg, lhs, rhs } => KutInstruction::handle_arithmetic(context, KutArithmetic::Add, *reg, *lhs, *rhs),
+            KutInstruction::SubNumbersR { reg, lhs, rhs } => KutInstruction::handle_arithmetic(context, KutArithmetic::Sub, *reg, *lhs, *rhs),
+            KutInstruction::MulNumbersR { reg, lhs, rhs } => KutInstruction::handle_arithmetic(context, KutArithmetic::Mul, *reg, *lhs, *rhs),
+            KutInstruction::DivNumbersR { reg, lhs, rhs } => KutInstruction::handle_arithmetic(context, KutArithmetic::Div, *reg, *lhs, *rhs),
+            KutInstruction::ModNumbersR { reg, lhs, rhs } => KutInstruction::handle_arithmetic(context, KutArithmetic::Rem, *reg, *lhs, *rhs),
+            KutInstruction::NegateValue { reg, value } => KutInstruction::handle_negate(context, *reg, *value),
+            KutInstruction::IntegerCast { reg, value } => KutInstruction::handle_integer_cast(context, *reg, *value),
+            KutInstruction::NumberCastR { reg, value } => KutInstruction::handle_number_cast(context, *reg, *value),
+            KutInstruction::JumpAlwaysT { target } => KutInstruction::handle_jump(context, *target),
+            KutInstruction::JumpIfTrueR { cond, target } => KutInstruction::handle_jump_if(context, *cond, *target, true),
+            KutInstruction::JumpIfFalse { cond, target } => KutInstruction::handle_jump_if(context, *cond, *target, false),
+            KutInstruction::NewListRegs { reg, first, count } => KutInstruction::handle_new_list_regs(context, vm, *reg, *first, *count),
+            KutInstruction::NewListStck { reg, count } => KutInstruction::handle_new_list_stack(context, vm, *reg, *count),
+            KutInstruction::ListGetItem { reg, list, index } => KutInstruction::handle_list_get_item(context, *reg, *list, *index),
+            KutInstruction::ListSetItem { list, index, value } => KutInstruction::handle_list_set_item(context, vm, *list, *index, *value),
+            KutInstruction::ListPushVal { list, value } => KutInstruction::handle_list_push(context, vm, *list, *value),
+            KutInstruction::ListPopValR { reg, list } => KutInstruction::handle_list_pop(context, vm, *reg, *list),
+            KutInstruction::ListSliceRg { reg, list, start, end } => KutInstruction::handle_list_slice(context, vm, *reg, *list, *start, *end),
+            KutInstruction::ListLengthR { reg, list } => KutInstruction::handle_list_length(context, *reg, *list),
+            KutInstruction::NewEmptyVec { reg } => KutInstruction::handle_new_empty_vector(context, vm, *reg),
+            KutInstruction::VecFromList { reg, list } => KutInstruction::handle_vector_from_list(context, vm, *reg, *list),
+            KutInstruction::VecToListRg { reg, vector } => KutInstruction::handle_vector_to_list(context, vm, *reg, *vector),
+            KutInstruction::VecGetItemR { reg, vector, index } => KutInstruction::handle_vector_get_item(context, *reg, *vector, *index),
+            KutInstruction::VecSetItemR { reg, vector, index, value } => KutInstruction::handle_vector_set_item(context, vm, *reg, *vector, *index, *value),
+            KutInstruction::VecPushValR { reg, vector, value } => KutInstruction::handle_vector_push(context, vm, *reg, *vector, *value),
+            KutInstruction::VecPopLastR { reg, vector } => KutInstruction::handle_vector_pop(context, vm, *reg, *vector),
+            KutInstruction::VecLengthRg { reg, vector } => KutInstruction::handle_vector_length(context, *reg, *vector),
+            KutInstruction::ToStringVal { reg, value } => KutInstruction::handle_to_string(context, vm, *reg, *value, false),
+            KutInstruction::ToReprValue { reg, value } => KutInstruction::handle_to_string(context, vm, *reg, *value, true),
+            KutInstruction::JsonEncodeR { reg, value } => KutInstruction::handle_json_encode(context, vm, *reg, *value, false),
+            KutInstruction::JsonPrettyR { reg, value } => KutInstruction::handle_json_encode(context, vm, *reg, *value, true),
+            KutInstruction::JsonDecodeR { reg, string } => KutInstruction::handle_json_decode(context, vm, *reg, *string),
+            KutInstruction::GetGlobalRg { reg, name } => KutInstruction::handle_get_global(context, vm, *reg, *name),
+            KutInstruction::SetGlobalRg { reg, name } => KutInstruction::handle_set_global(context, vm, *reg, *name),
+            KutInstruction::GetModuleRg { reg, name } => KutInstruction::handle_get_module(context, vm, *reg, *name),
+            KutInstruction::CoroutineRg { reg, func } => KutInstruction::handle_new_coroutine(context, vm, *reg, *func),
+            KutInstruction::CoroResumeR { reg, coroutine, value } => KutInstruction::handle_coroutine_resume(context, vm, *reg, *coroutine, *value),
+            KutInstruction::CoroYieldRg { reg, value } => KutInstruction::handle_coroutine_yield(context, vm, *reg, *value),
+            KutInstruction::CoroStatusR { reg, coroutine } => KutInstruction::handle_coroutine_status(context, vm, *reg, *coroutine),
+            KutInstruction::IterCreateR { reg, value } => KutInstruction::handle_iterator_create(context, vm, *reg, *value),
+            KutInstruction::IterRangeRg { reg, start, end, step } => KutInstruction::handle_iterator_range(context, vm, *reg, *start, *end, *step),
+            KutInstruction::IterNextReg { reg, iter, target } => KutInstruction::handle_iterator_next(context, vm, *reg, *iter, *target),
         }
     }
 
+    pub fn fuel_cost(&self, costs: &KutFuelCosts) -> u64 {
+        match self {
+            KutInstruction::CallMethodR { .. } | KutInstruction::CallMethodS { .. } | KutInstruction::CallMultiRg { .. } | KutInstruction::CallMultiSt { .. } => costs.call,
+            KutInstruction::CoroResumeR { .. } => costs.call,
+            KutInstruction::CaptureFunc { .. } | KutInstruction::PushFuncStk { .. } => costs.capture,
+            _ => costs.instruction,
+        }
+    }
 }
 
 /// Auxillary functions
-impl<'closure, 'template> KutInstruction {
-    fn get_register_value<'reg>(context: &'reg mut KutFunction<'closure, 'template>, reg: u8) -> Result<KutValue<'template>, KutError> {
+impl<'template> KutInstruction {
+    fn get_register_value<'reg>(context: &'reg mut KutFunction<'template>, reg: u8) -> Result<KutValue<'template>, KutError> {
         if let Some(source) = context.registers.get(reg as usize) {
             if let KutValue::Reference(r) = source {
                 Ok((**r).borrow().clone())
@@ -42,7 +111,7 @@ impl<'closure, 'template> KutInstruction {
         }
     }
 
-    fn set_register_value<'reg>(context: &'reg mut KutFunction<'closure, 'template>, reg: u8, value: KutValue<'template>) -> Result<(),KutError> {
+    pub(crate) fn set_register_value<'reg>(context: &'reg mut KutFunction<'template>, reg: u8, value: KutValue<'template>) -> Result<(),KutError> {
         if let Some(destination) = context.registers.get_mut(reg as usize) {
             if let KutValue::Reference(r) = destination {
                 *(**r).borrow_mut() = value;
@@ -55,40 +124,198 @@ impl<'closure, 'template> KutInstruction {
         }
     }
 
-    fn check_register<'reg>(context: &'reg mut KutFunction<'closure, 'template>, reg: u8, err: KutError) -> Result<(),KutError> {
+    /// Gives `modify` mutable access to the value of a register, through its `Reference` if it is captured.
+    fn modify_register_value<'reg, T>(context: &'reg mut KutFunction<'template>, reg: u8, modify: impl FnOnce(&mut KutValue<'template>) -> Result<T, KutError>) -> Result<T, KutError> {
+        if let Some(destination) = context.registers.get_mut(reg as usize) {
+            if let KutValue::Reference(r) = destination {
+                modify(&mut (**r).borrow_mut())
+            } else {
+                modify(destination)
+            }
+        } else {
+            Err(KutError::OutOfRangeDestinationRegister { register: reg, register_count: context.registers.len() })
+        }
+    }
+
+    fn get_map_value<'reg>(context: &'reg mut KutFunction<'template>, reg: u8) -> Result<Rc<KutMap<'template>>, KutError> {
+        match KutInstruction::get_register_value(context, reg)? {
+            KutValue::Map(map) => Ok(map),
+            other => Err(KutError::TypeMismatch { register: reg, expected: "Map".to_owned(), value_type: other.get_type_string() }),
+        }
+    }
+
+    /// Mutates the value of register `reg` and accounts the memory of the result. `Rc::make_mut`
+    /// moves the value to a new allocation when it is shared or weakly tracked, which is then
+    /// accounted as a whole; otherwise only the growth is accounted.
+    fn modify_shared_value<'reg, T>(context: &'reg mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, modify: impl FnOnce(&mut KutValue<'template>) -> Result<T, KutError>) -> Result<T, KutError> {
+        KutInstruction::modify_register_value(context, reg, |value| {
+            let identity = value.identity();
+            let old_size = KutMemory::size_of(value);
+            let result = modify(value)?;
+            if value.identity() == identity {
+                vm.memory.track_growth(value, old_size)?;
+            } else {
+                vm.memory.track(value)?;
+            }
+            Ok(result)
+        })
+    }
+
+    /// Mutates the map in register `reg`, copying it first if it is shared.
+    fn modify_map_value<'reg, T>(context: &'reg mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, modify: impl FnOnce(&mut KutMap<'template>) -> T) -> Result<T, KutError> {
+        KutInstruction::modify_shared_value(context, vm, reg, |value| match value {
+            KutValue::Map(map) => Ok(modify(Rc::make_mut(map))),
+            other => Err(KutError::TypeMismatch { register: reg, expected: "Map".to_owned(), value_type: other.get_type_string() }),
+        })
+    }
+
+    fn get_list_value<'reg>(context: &'reg mut KutFunction<'template>, reg: u8) -> Result<Rc<Vec<KutValue<'template>>>, KutError> {
+        match KutInstruction::get_register_value(context, reg)? {
+            KutValue::List(list) => Ok(list),
+            other => Err(KutError::TypeMismatch { register: reg, expected: "List".to_owned(), value_type: other.get_type_string() }),
+        }
+    }
+
+    /// Mutates the list in register `reg`, copying it first if it is shared.
+    fn modify_list_value<'reg, T>(context: &'reg mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, modify: impl FnOnce(&mut Vec<KutValue<'template>>) -> Result<T, KutError>) -> Result<T, KutError> {
+        KutInstruction::modify_shared_value(context, vm, reg, |value| match value {
+            KutValue::List(list) => modify(Rc::make_mut(list)),
+            other => Err(KutError::TypeMismatch { register: reg, expected: "List".to_owned(), value_type: other.get_type_string() }),
+        })
+    }
+
+    fn get_vector_value<'reg>(context: &'reg mut KutFunction<'template>, reg: u8) -> Result<Rc<KutVector<'template>>, KutError> {
+        match KutInstruction::get_register_value(context, reg)? {
+            KutValue::Vector(vector) => Ok(vector),
+            other => Err(KutError::TypeMismatch { register: reg, expected: "Vector".to_owned(), value_type: other.get_type_string() }),
+        }
+    }
+
+    fn get_iterator_value<'reg>(context: &'reg mut KutFunction<'template>, reg: u8) -> Result<Rc<KutIterator<'template>>, KutError> {
+        match KutInstruction::get_register_value(context, reg)? {
+            KutValue::Iterator(iterator) => Ok(iterator),
+            other => Err(KutError::TypeMismatch { register: reg, expected: "Iterator".to_owned(), value_type: other.get_type_string() }),
+        }
+    }
+
+    fn get_coroutine_value<'reg>(context: &'reg mut KutFunction<'template>, reg: u8) -> Result<Rc<KutCoroutine<'template>>, KutError> {
+        match KutInstruction::get_register_value(context, reg)? {
+            KutValue::Coroutine(coroutine) => Ok(coroutine),
+            other => Err(KutError::TypeMismatch { register: reg, expected: "Coroutine".to_owned(), value_type: other.get_type_string() }),
+        }
+    }
+
+    /// Stores a vector made from the one still held by the caller, accounting its new nodes.
+    fn set_vector_value<'reg>(context: &'reg mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, vector: KutVector<'template>) -> Result<(), KutError> {
+        let vector = KutValue::Vector(Rc::new(vector));
+        vm.memory.track(&vector)?;
+        KutInstruction::set_register_value(context, reg, vector)
+    }
+
+    /// Resolves a possibly negative index, counted from the end, into a list of `length` items.
+    fn list_index(index: &KutValue<'template>, length: usize) -> Result<usize, KutError> {
+        let index = index.as_i64()?;
+        let position = if index < 0 { index + length as i64 } else { index };
+        if position >= 0 && (position as usize) < length {
+            Ok(position as usize)
+        } else {
+            Err(KutError::OutOfRangeIndex { index, length })
+        }
+    }
+
+    fn check_register<'reg>(context: &'reg mut KutFunction<'template>, reg: u8, err: KutError) -> Result<(),KutError> {
         if context.registers.get(reg as usize).is_none() {
             Err(err)
         } else {
             Ok(())
         }
     }
+
+    fn box_captured_registers<'reg>(context: &'reg mut KutFunction<'template>, vm: &'template KutVm<'template>, template: &KutFunctionTemplate) -> Result<(),KutError> {
+        for capture_info in template.capture_infos.iter() {
+            if let KutCaptureInfo::Register(reg) = capture_info {
+                if let Some(val) = context.registers.get_mut(*reg as usize) {
+                    if !matches!(val, KutValue::Reference(_)) {
+                        let reference = KutValue::Reference(Rc::new(RefCell::new(val.clone())));
+                        vm.memory.track(&reference)?;
+                        *val = reference;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn prepare_call<'reg>(context: &'reg mut KutFunction<'template>, vm: &'template KutVm<'template>, arg_count: u8, subject: u8, target: KutReturnTarget) -> Result<(),KutError> {
+        let value = KutInstruction::get_register_value(context, subject)?;
+        let Some(callee) = value.callee() else {
+            return Err(KutError::NonCallableValue { register: subject, value_type: value.get_type_string() });
+        };
+        if context.call_stack.len() < arg_count as usize {
+            return Err(KutError::StackUnderflow);
+        }
+        vm.check_call_depth()?;
+        // The arguments stay on the stack until the call started, so that a failed call
+        // can run again.
+        let first = context.call_stack.len() - arg_count as usize;
+        let args = context.call_stack[first..].to_vec();
+        let result = KutInstruction::call_value(context, vm, callee, args, target);
+        if matches!(result, Ok(()) | Err(KutError::Pending)) {
+            context.call_stack.truncate(first);
+        }
+        result
+    }
+
+    /// Starts a call to `callee`. A `Func` becomes the
+    /// callee frame and is run by `KutFunction::run`, a `Native` runs to completion right
+    /// away unless it is pending, then its result is stored by `KutFunction::resume_with`.
+    fn call_value<'reg>(context: &'reg mut KutFunction<'template>, vm: &'template KutVm<'template>, callee: KutCallee<'template>, args: Vec<KutValue<'template>>, target: KutReturnTarget) -> Result<(),KutError> {
+        let closure = match callee {
+            KutCallee::Func(closure) => closure,
+            KutCallee::Native(native) => {
+                let value = match native.call(vm, args) {
+                    Err(KutError::Pending) => {
+                        context.resume_target = Some(target);
+                        return Err(KutError::Pending);
+                    },
+                    result => result?,
+                };
+                return context.store_result(target, value, false);
+            },
+        };
+        let frame = closure.start_with(vm, args)?;
+        context.callee = Some(Box::new(KutCall { frame, target, coroutine: None }));
+        Ok(())
+    }
 }
 
 /// Instruction handlers
-impl<'closure, 'template> KutInstruction {
+impl<'template> KutInstruction {
     fn handle_no_operation() -> KutReturnType<'template> {
         Ok(None)
     }
     
-    fn handle_call_method_r(_context: &mut KutFunction<'closure, 'template>, _ret_position: u8, _arg_count: u8, _subject: u8) -> KutReturnType<'template> {
-        unimplemented!()
+    fn handle_call_method_r(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, ret_position: u8, arg_count: u8, subject: u8) -> KutReturnType<'template> {
+        KutInstruction::check_register(context, ret_position, KutError::OutOfRangeDestinationRegister { register: ret_position, register_count: context.registers.len() })?;
+        KutInstruction::prepare_call(context, vm, arg_count, subject, KutReturnTarget::Register(ret_position))?;
+        Ok(None)
     }
 
-    fn handle_call_method_s(_context: &mut KutFunction<'closure, 'template>, _arg_count: u8, _subject: u8) -> KutReturnType<'template> {
-        unimplemented!()
+    fn handle_call_method_s(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, arg_count: u8, subject: u8) -> KutReturnType<'template> {
+        KutInstruction::prepare_call(context, vm, arg_count, subject, KutReturnTarget::Stack)?;
+        Ok(None)
     }
 
-    fn handle_capture_function(context: &mut KutFunction<'closure, 'template>, vm: &'template KutVm<'template>, reg: u8, template: u16) -> KutReturnType<'template> {
-        if let Some(tmplt) = vm.templates.get(template as usize) {
-            let closure = KutValue::Func(Rc::new(tmplt.capture(Some(context))?));
-            KutInstruction::set_register_value(context, reg, closure)?;
-            Ok(None)
-        } else {
-            Err(KutError::OutOfRangeTemplate { template, template_count: vm.templates.len() })
-        }
+    fn handle_capture_function(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, template: u16) -> KutReturnType<'template> {
+        let tmplt = vm.template(context.closure.template, template)?;
+        KutInstruction::box_captured_registers(context, vm, tmplt)?;
+        let closure = KutValue::Func(Rc::new(tmplt.capture(Some(context))?));
+        vm.memory.track(&closure)?;
+        KutInstruction::set_register_value(context, reg, closure)?;
+        Ok(None)
     }
 
-    fn handle_get_capture_r(context: &mut KutFunction<'closure, 'template>, reg: u8, capture: u16) -> KutReturnType<'template> {
+    fn handle_get_capture_r(context: &mut KutFunction<'template>, reg: u8, capture: u16) -> KutReturnType<'template> {
         if let Some(cap) = context.closure.captures.get(capture as usize) {
             if let KutValue::Reference(captured) = cap {
                 let value = (*captured).borrow().clone();
@@ -102,17 +329,13 @@ impl<'closure, 'template> KutInstruction {
         }
     }
 
-    fn handle_get_literal(context: &mut KutFunction<'closure, 'template>, vm: &'template KutVm<'template>, reg: u8, literal: u16) -> KutReturnType<'template> {
-        if let Some(lit) = vm.literals.get(literal as usize) {
-            let literal_cloned = lit.clone();
-            KutInstruction::set_register_value(context, reg, literal_cloned)?;
-            Ok(None)
-        } else {
-            Err(KutError::OutOfRangeLiteral { literal, literal_count: vm.literals.len() })
-        }
+    fn handle_get_literal(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, literal: u16) -> KutReturnType<'template> {
+        let literal_cloned = vm.literal(context.closure.template, literal)?.clone();
+        KutInstruction::set_register_value(context, reg, literal_cloned)?;
+        Ok(None)
     }
 
-    fn handle_mov_register(context: &mut KutFunction<'closure, 'template>, destination: u8, source: u8) -> KutReturnType<'template> {
+    fn handle_mov_register(context: &mut KutFunction<'template>, destination: u8, source: u8) -> KutReturnType<'template> {
         if destination == source {
             Ok(None)
         } else {
@@ -122,25 +345,24 @@ impl<'closure, 'template> KutInstruction {
         }
     }
 
-    fn handle_pop_capture(context: &mut KutFunction<'closure, 'template>, capture: u16) -> KutReturnType<'template> {
-        if let Some(source) = context.call_stack.pop() {
-            let val = source.clone();
-            if let Some(destination) = context.closure.captures.get(capture as usize) {
-                if let KutValue::Reference(dest) = destination {
-                    *(*dest).borrow_mut() = val;
+    fn handle_pop_capture(context: &mut KutFunction<'template>, capture: u16) -> KutReturnType<'template> {
+        if let Some(destination) = context.closure.captures.get(capture as usize) {
+            if let KutValue::Reference(dest) = destination {
+                if let Some(source) = context.call_stack.pop() {
+                    *(*dest).borrow_mut() = source;
                     Ok(None)
                 } else {
-                    Err(KutError::NonReferenceCapture { capture, capture_type: destination.get_type_string() })
+                    Err(KutError::StackUnderflow)
                 }
             } else {
-                Err(KutError::OutOfRangeDestinationCapture { capture, capture_count: context.closure.captures.len() })
+                Err(KutError::NonReferenceCapture { capture, capture_type: destination.get_type_string() })
             }
         } else {
-            Err(KutError::StackUnderflow)
+            Err(KutError::OutOfRangeDestinationCapture { capture, capture_count: context.closure.captures.len() })
         }
     }
 
-    fn handle_push_capture(context: &mut KutFunction<'closure, 'template>, capture: u16) -> KutReturnType<'template> {
+    fn handle_push_capture(context: &mut KutFunction<'template>, capture: u16) -> KutReturnType<'template> {
         if let Some(cap) = context.closure.captures.get(capture as usize) {
             if let KutValue::Reference(r) = cap {
                 let value = (*r).borrow().clone();
@@ -154,33 +376,28 @@ impl<'closure, 'template> KutInstruction {
         }
     }
 
-    fn handle_push_template(context: &mut KutFunction<'closure, 'template>, vm: &'template KutVm<'template>, template: u16) -> KutReturnType<'template> {
-        if let Some(tmplt) = vm.templates.get(template as usize) {
-            let closure = KutValue::Func(Rc::new(tmplt.capture(Some(context))?));
-            context.call_stack.push(closure);
-            Ok(None)
-        } else {
-            Err(KutError::OutOfRangeTemplate { template, template_count: vm.templates.len() })
-        }
+    fn handle_push_template(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, template: u16) -> KutReturnType<'template> {
+        let tmplt = vm.template(context.closure.template, template)?;
+        KutInstruction::box_captured_registers(context, vm, tmplt)?;
+        let closure = KutValue::Func(Rc::new(tmplt.capture(Some(context))?));
+        vm.memory.track(&closure)?;
+        context.call_stack.push(closure);
+        Ok(None)
     }
 
-    fn handle_push_literal(context: &mut KutFunction<'closure, 'template>, vm: &'template KutVm<'template>, literal: u16) -> KutReturnType<'template> {
-        if let Some(lit) = vm.literals.get(literal as usize) {
-            let literal_cloned = lit.clone();
-            context.call_stack.push(literal_cloned);
-            Ok(None)
-        } else {
-            Err(KutError::OutOfRangeLiteral { literal, literal_count: vm.literals.len() })
-        }
+    fn handle_push_literal(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, literal: u16) -> KutReturnType<'template> {
+        let literal_cloned = vm.literal(context.closure.template, literal)?.clone();
+        context.call_stack.push(literal_cloned);
+        Ok(None)
     }
 
-    fn handle_push_value_1(context: &mut KutFunction<'closure, 'template>, val1: u8) -> KutReturnType<'template> {
+    fn handle_push_value_1(context: &mut KutFunction<'template>, val1: u8) -> KutReturnType<'template> {
         let value = KutInstruction::get_register_value(context, val1)?;
         context.call_stack.push(value);
         Ok(None)
     }
 
-    fn handle_push_value_2(context: &mut KutFunction<'closure, 'template>, val1: u8, val2: u8) -> KutReturnType<'template> {
+    fn handle_push_value_2(context: &mut KutFunction<'template>, val1: u8, val2: u8) -> KutReturnType<'template> {
         let value = KutInstruction::get_register_value(context, val1)?;
         context.call_stack.push(value);
         let value = KutInstruction::get_register_value(context, val2)?;
@@ -188,7 +405,7 @@ impl<'closure, 'template> KutInstruction {
         Ok(None)
     }
 
-    fn handle_push_value_3(context: &mut KutFunction<'closure, 'template>, val1: u8, val2: u8, val3: u8) -> KutReturnType<'template> {
+    fn handle_push_value_3(context: &mut KutFunction<'template>, val1: u8, val2: u8, val3: u8) -> KutReturnType<'template> {
         let value = KutInstruction::get_register_value(context, val1)?;
         context.call_stack.push(value);
         let value = KutInstruction::get_register_value(context, val2)?;
@@ -198,12 +415,12 @@ impl<'closure, 'template> KutInstruction {
         Ok(None)
     }
 
-    fn handle_ret_r(context: &mut KutFunction<'closure, 'template>, value: u8) -> KutReturnType<'template> {
+    fn handle_ret_r(context: &mut KutFunction<'template>, value: u8) -> KutReturnType<'template> {
         let val = KutInstruction::get_register_value(context, value)?;
         Ok(Some(val))
     }
 
-    fn handle_ret_s(context: &mut KutFunction<'closure, 'template>) -> KutReturnType<'template> {
+    fn handle_ret_s(context: &mut KutFunction<'template>) -> KutReturnType<'template> {
         if let Some(val) = context.call_stack.pop() {
             Ok(Some(val))
         } else {
@@ -211,7 +428,49 @@ impl<'closure, 'template> KutInstruction {
         }
     }
 
-    fn handle_set_capture_r(context: &mut KutFunction<'closure, 'template>, reg: u8, capture: u16) -> KutReturnType<'template> {
+    /// Returns the registers `first..first + count`, the first one is also the single
+    /// result seen by callers that expect one.
+    fn handle_ret_multiple_r(context: &mut KutFunction<'template>, first: u8, count: u8) -> KutReturnType<'template> {
+        let register_count = context.registers.len();
+        if first as usize + count as usize > register_count {
+            let register = (first as usize + count as usize - 1).min(u8::MAX as usize) as u8;
+            return Err(KutError::OutOfRangeSourceRegister { register, register_count });
+        }
+        context.call_stack.clear();
+        for source in first..first + count {
+            let value = KutInstruction::get_register_value(context, source)?;
+            context.call_stack.push(value);
+        }
+        context.multiple_results = true;
+        Ok(Some(context.call_stack.first().cloned().unwrap_or(KutValue::Nil)))
+    }
+
+    /// Returns the top `count` values of the stack, in push order.
+    fn handle_ret_multiple_s(context: &mut KutFunction<'template>, count: u8) -> KutReturnType<'template> {
+        if context.call_stack.len() < count as usize {
+            return Err(KutError::StackUnderflow);
+        }
+        context.call_stack.drain(..context.call_stack.len() - count as usize);
+        context.multiple_results = true;
+        Ok(Some(context.call_stack.first().cloned().unwrap_or(KutValue::Nil)))
+    }
+
+    fn handle_call_multiple_r(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, first: u8, count: u8, arg_count: u8, subject: u8) -> KutReturnType<'template> {
+        let register_count = context.registers.len();
+        if first as usize + count as usize > register_count {
+            let register = (first as usize + count as usize - 1).min(u8::MAX as usize) as u8;
+            return Err(KutError::OutOfRangeDestinationRegister { register, register_count });
+        }
+        KutInstruction::prepare_call(context, vm, arg_count, subject, KutReturnTarget::Registers { first, count })?;
+        Ok(None)
+    }
+
+    fn handle_call_multiple_s(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, count: u8, arg_count: u8, subject: u8) -> KutReturnType<'template> {
+        KutInstruction::prepare_call(context, vm, arg_count, subject, KutReturnTarget::StackValues { count })?;
+        Ok(None)
+    }
+
+    fn handle_set_capture_r(context: &mut KutFunction<'template>, reg: u8, capture: u16) -> KutReturnType<'template> {
         let value = KutInstruction::get_register_value(context, reg)?;
         if let Some(cap) = context.closure.captures.get(capture as usize) {
             if let KutValue::Reference(captured) = cap {
@@ -225,10 +484,517 @@ impl<'closure, 'template> KutInstruction {
         }
     }
 
-    fn handle_swap_values(context: &mut KutFunction<'closure, 'template>, reg1: u8, reg2: u8) -> KutReturnType<'template> {
+    fn handle_swap_values(context: &mut KutFunction<'template>, reg1: u8, reg2: u8) -> KutReturnType<'template> {
         KutInstruction::check_register(context, reg1, KutError::OutOfRangeSwapRegister { register: reg1, register_count: context.registers.len() })?;
         KutInstruction::check_register(context, reg2, KutError::OutOfRangeSwapRegister { register: reg2, register_count: context.registers.len() })?;
         context.registers.swap(reg1 as usize, reg2 as usize);
         Ok(None)
     }
+
+    fn handle_new_empty_map(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8) -> KutReturnType<'template> {
+        let map = KutValue::Map(Rc::new(KutMap::new()));
+        vm.memory.track(&map)?;
+        KutInstruction::set_register_value(context, reg, map)?;
+        Ok(None)
+    }
+
+    fn handle_map_get_value(context: &mut KutFunction<'template>, reg: u8, map: u8, key: u8) -> KutReturnType<'template> {
+        let map = KutInstruction::get_map_value(context, map)?;
+        let key = KutKey(KutInstruction::get_register_value(context, key)?);
+        let value = map.get(&key).cloned().unwrap_or(KutValue::Undefined);
+        KutInstruction::set_register_value(context, reg, value)?;
+        Ok(None)
+    }
+
+    fn handle_map_set_value(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, map: u8, key: u8, value: u8) -> KutReturnType<'template> {
+        let key = KutKey(KutInstruction::get_register_value(context, key)?);
+        let value = KutInstruction::get_register_value(context, value)?;
+        KutInstruction::modify_map_value(context, vm, map, |map| map.insert(key, value))?;
+        Ok(None)
+    }
+
+    fn handle_map_del_value(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, map: u8, key: u8) -> KutReturnType<'template> {
+        let key = KutKey(KutInstruction::get_register_value(context, key)?);
+        KutInstruction::modify_map_value(context, vm, map, |map| map.remove(&key))?;
+        Ok(None)
+    }
+
+    fn handle_map_contains(context: &mut KutFunction<'template>, reg: u8, map: u8, key: u8) -> KutReturnType<'template> {
+        let map = KutInstruction::get_map_value(context, map)?;
+        let key = KutKey(KutInstruction::get_register_value(context, key)?);
+        KutInstruction::set_register_value(context, reg, KutValue::Bool(map.contains_key(&key)))?;
+        Ok(None)
+    }
+
+    fn handle_map_get_keys(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, map: u8) -> KutReturnType<'template> {
+        let map = KutInstruction::get_map_value(context, map)?;
+        let keys = KutValue::List(Rc::new(map.keys().map(|key| key.0.clone()).collect()));
+        vm.memory.track(&keys)?;
+        KutInstruction::set_register_value(context, reg, keys)?;
+        Ok(None)
+    }
+
+    fn handle_map_get_count(context: &mut KutFunction<'template>, reg: u8, map: u8) -> KutReturnType<'template> {
+        let map = KutInstruction::get_map_value(context, map)?;
+        KutInstruction::set_register_value(context, reg, KutValue::Integer(map.len() as i64))?;
+        Ok(None)
+    }
+
+    fn handle_compare(context: &mut KutFunction<'template>, reg: u8, lhs: u8, rhs: u8, compare: impl FnOnce(&KutValue<'template>, &KutValue<'template>) -> bool) -> KutReturnType<'template> {
+        let lhs = KutInstruction::get_register_value(context, lhs)?;
+        let rhs = KutInstruction::get_register_value(context, rhs)?;
+        KutInstruction::set_register_value(context, reg, KutValue::Bool(compare(&lhs, &rhs)))?;
+        Ok(None)
+    }
+
+    fn handle_compare_ord(context: &mut KutFunction<'template>, reg: u8, lhs: u8, rhs: u8) -> KutReturnType<'template> {
+        let lhs = KutInstruction::get_register_value(context, lhs)?;
+        let rhs = KutInstruction::get_register_value(context, rhs)?;
+        let ordering = lhs.total_cmp(&rhs) as i64;
+        KutInstruction::set_register_value(context, reg, KutValue::Integer(ordering))?;
+        Ok(None)
+    }
+
+    fn handle_arithmetic(context: &mut KutFunction<'template>, operation: KutArithmetic, reg: u8, lhs: u8, rhs: u8) -> KutReturnType<'template> {
+        let lhs = KutInstruction::get_register_value(context, lhs)?;
+        let rhs = KutInstruction::get_register_value(context, rhs)?;
+        KutInstruction::set_register_value(context, reg, lhs.arithmetic(operation, &rhs)?)?;
+        Ok(None)
+    }
+
+    fn handle_negate(context: &mut KutFunction<'template>, reg: u8, value: u8) -> KutReturnType<'template> {
+        let value = KutInstruction::get_register_value(context, value)?;
+        KutInstruction::set_register_value(context, reg, value.negate()?)?;
+        Ok(None)
+    }
+
+    fn handle_integer_cast(context: &mut KutFunction<'template>, reg: u8, value: u8) -> KutReturnType<'template> {
+        let value = KutInstruction::get_register_value(context, value)?;
+        KutInstruction::set_register_value(context, reg, KutValue::Integer(value.as_i64()?))?;
+        Ok(None)
+    }
+
+    fn handle_number_cast(context: &mut KutFunction<'template>, reg: u8, value: u8) -> KutReturnType<'template> {
+        let value = KutInstruction::get_register_value(context, value)?;
+        match value.as_f64() {
+            Some(num) => KutInstruction::set_register_value(context, reg, KutValue::Number(num))?,
+            None => return Err(KutError::InvalidOperand { operation: "number conversion".to_owned(), value_type: value.get_type_string() }),
+        }
+        Ok(None)
+    }
+
+    fn handle_jump(context: &mut KutFunction<'template>, target: u16) -> KutReturnType<'template> {
+        let instruction_count = context.closure.template.instructions.len();
+        if target as usize > instruction_count {
+            return Err(KutError::OutOfRangeJump { target, instruction_count });
+        }
+        context.pc = target as usize;
+        Ok(None)
+    }
+
+    fn handle_jump_if(context: &mut KutFunction<'template>, cond: u8, target: u16, expected: bool) -> KutReturnType<'template> {
+        if KutInstruction::get_register_value(context, cond)?.is_truthy() == expected {
+            KutInstruction::handle_jump(context, target)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn handle_new_list_regs(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, first: u8, count: u8) -> KutReturnType<'template> {
+        let mut items = Vec::with_capacity(count as usize);
+        for source in first as usize..first as usize + count as usize {
+            if source > u8::MAX as usize {
+                return Err(KutError::OutOfRangeSourceRegister { register: u8::MAX, register_count: context.registers.len() });
+            }
+            items.push(KutInstruction::get_register_value(context, source as u8)?);
+        }
+        let list = KutValue::List(Rc::new(items));
+        vm.memory.track(&list)?;
+        KutInstruction::set_register_value(context, reg, list)?;
+        Ok(None)
+    }
+
+    fn handle_new_list_stack(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, count: u8) -> KutReturnType<'template> {
+        if context.call_stack.len() < count as usize {
+            return Err(KutError::StackUnderflow);
+        }
+        let items = context.call_stack.split_off(context.call_stack.len() - count as usize);
+        let list = KutValue::List(Rc::new(items));
+        vm.memory.track(&list)?;
+        KutInstruction::set_register_value(context, reg, list)?;
+        Ok(None)
+    }
+
+    fn handle_list_get_item(context: &mut KutFunction<'template>, reg: u8, list: u8, index: u8) -> KutReturnType<'template> {
+        let list = KutInstruction::get_list_value(context, list)?;
+        let index = KutInstruction::list_index(&KutInstruction::get_register_value(context, index)?, list.len())?;
+        KutInstruction::set_register_value(context, reg, list[index].clone())?;
+        Ok(None)
+    }
+
+    fn handle_list_set_item(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, list: u8, index: u8, value: u8) -> KutReturnType<'template> {
+        let index = KutInstruction::get_register_value(context, index)?;
+        let value = KutInstruction::get_register_value(context, value)?;
+        KutInstruction::modify_list_value(context, vm, list, |list| {
+            let index = KutInstruction::list_index(&index, list.len())?;
+            list[index] = value;
+            Ok(())
+        })?;
+        Ok(None)
+    }
+
+    fn handle_list_push(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, list: u8, value: u8) -> KutReturnType<'template> {
+        let value = KutInstruction::get_register_value(context, value)?;
+        KutInstruction::modify_list_value(context, vm, list, |list| {
+            list.push(value);
+            Ok(())
+        })?;
+        Ok(None)
+    }
+
+    fn handle_list_pop(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, list: u8) -> KutReturnType<'template> {
+        let value = KutInstruction::modify_list_value(context, vm, list, |list| {
+            list.pop().ok_or(KutError::OutOfRangeIndex { index: -1, length: 0 })
+        })?;
+        KutInstruction::set_register_value(context, reg, value)?;
+        Ok(None)
+    }
+
+    fn handle_list_slice(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, list: u8, start: u8, end: u8) -> KutReturnType<'template> {
+        let list = KutInstruction::get_list_value(context, list)?;
+        let bound = |index: KutValue<'template>| -> Result<usize, KutError> {
+            let index = index.as_i64()?;
+            let position = if index < 0 { index + list.len() as i64 } else { index };
+            if position >= 0 && position as usize <= list.len() {
+                Ok(position as usize)
+            } else {
+                Err(KutError::OutOfRangeIndex { index, length: list.len() })
+            }
+        };
+        let start = bound(KutInstruction::get_register_value(context, start)?)?;
+        let end = bound(KutInstruction::get_register_value(context, end)?)?.max(start);
+        let slice = KutValue::List(Rc::new(list[start..end].to_vec()));
+        vm.memory.track(&slice)?;
+        KutInstruction::set_register_value(context, reg, slice)?;
+        Ok(None)
+    }
+
+    fn handle_list_length(context: &mut KutFunction<'template>, reg: u8, list: u8) -> KutReturnType<'template> {
+        let list = KutInstruction::get_list_value(context, list)?;
+        KutInstruction::set_register_value(context, reg, KutValue::Integer(list.len() as i64))?;
+        Ok(None)
+    }
+
+    fn handle_new_empty_vector(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8) -> KutReturnType<'template> {
+        KutInstruction::set_vector_value(context, vm, reg, KutVector::new())?;
+        Ok(None)
+    }
+
+    fn handle_vector_from_list(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, list: u8) -> KutReturnType<'template> {
+        let list = KutInstruction::get_list_value(context, list)?;
+        KutInstruction::set_vector_value(context, vm, reg, list.iter().cloned().collect())?;
+        Ok(None)
+    }
+
+    fn handle_vector_to_list(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, vector: u8) -> KutReturnType<'template> {
+        let vector = KutInstruction::get_vector_value(context, vector)?;
+        let list = KutValue::List(Rc::new(vector.iter().cloned().collect()));
+        vm.memory.track(&list)?;
+        KutInstruction::set_register_value(context, reg, list)?;
+        Ok(None)
+    }
+
+    fn handle_vector_get_item(context: &mut KutFunction<'template>, reg: u8, vector: u8, index: u8) -> KutReturnType<'template> {
+        let vector = KutInstruction::get_vector_value(context, vector)?;
+        let index = KutInstruction::list_index(&KutInstruction::get_register_value(context, index)?, vector.len())?;
+        KutInstruction::set_register_value(context, reg, vector.get(index).cloned().unwrap_or(KutValue::Nil))?;
+        Ok(None)
+    }
+
+    fn handle_vector_set_item(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, vector: u8, index: u8, value: u8) -> KutReturnType<'template> {
+        let vector = KutInstruction::get_vector_value(context, vector)?;
+        let index = KutInstruction::list_index(&KutInstruction::get_register_value(context, index)?, vector.len())?;
+        let value = KutInstruction::get_register_value(context, value)?;
+        let updated = vector.set(index, value).unwrap_or_else(|| (*vector).clone());
+        KutInstruction::set_vector_value(context, vm, reg, updated)?;
+        Ok(None)
+    }
+
+    fn handle_vector_push(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, vector: u8, value: u8) -> KutReturnType<'template> {
+        let vector = KutInstruction::get_vector_value(context, vector)?;
+        let value = KutInstruction::get_register_value(context, value)?;
+        KutInstruction::set_vector_value(context, vm, reg, vector.push(value))?;
+        Ok(None)
+    }
+
+    fn handle_vector_pop(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, vector: u8) -> KutReturnType<'template> {
+        let vector = KutInstruction::get_vector_value(context, vector)?;
+        let popped = vector.pop().ok_or(KutError::OutOfRangeIndex { index: -1, length: 0 })?;
+        KutInstruction::set_vector_value(context, vm, reg, popped)?;
+        Ok(None)
+    }
+
+    fn handle_vector_length(context: &mut KutFunction<'template>, reg: u8, vector: u8) -> KutReturnType<'template> {
+        let vector = KutInstruction::get_vector_value(context, vector)?;
+        KutInstruction::set_register_value(context, reg, KutValue::Integer(vector.len() as i64))?;
+        Ok(None)
+    }
+
+    fn handle_to_string(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, value: u8, repr: bool) -> KutReturnType<'template> {
+        let value = KutInstruction::get_register_value(context, value)?;
+        let string = if repr { value.to_repr_string() } else { value.to_display_string() };
+        let string = KutValue::String(Rc::new(string));
+        vm.memory.track(&string)?;
+        KutInstruction::set_register_value(context, reg, string)?;
+        Ok(None)
+    }
+
+    fn handle_json_encode(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, value: u8, pretty: bool) -> KutReturnType<'template> {
+        let value = KutInstruction::get_register_value(context, value)?;
+        let json = value.to_json_tracked(pretty, &vm.memory)?;
+        let json = KutValue::String(Rc::new(json));
+        vm.memory.track(&json)?;
+        KutInstruction::set_register_value(context, reg, json)?;
+        Ok(None)
+    }
+
+    fn handle_json_decode(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, string: u8) -> KutReturnType<'template> {
+        let value = match KutInstruction::get_register_value(context, string)? {
+            KutValue::String(json) => KutValue::from_json_tracked(&json, &vm.memory)?,
+            other => return Err(KutError::TypeMismatch { register: string, expected: "String".to_owned(), value_type: other.get_type_string() }),
+        };
+        KutInstruction::set_register_value(context, reg, value)?;
+        Ok(None)
+    }
+
+    fn handle_get_global(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, name: u16) -> KutReturnType<'template> {
+        let slot = vm.global_slot(context.closure.template, name)?;
+        let value = vm.globals.borrow().get(slot).cloned();
+        match value {
+            Some(value) => KutInstruction::set_register_value(context, reg, value)?,
+            None => return Err(KutError::UndefinedGlobal { name: vm.globals.borrow().name(slot).unwrap_or_default().to_owned() }),
+        }
+        Ok(None)
+    }
+
+    fn handle_set_global(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, name: u16) -> KutReturnType<'template> {
+        let slot = vm.global_slot(context.closure.template, name)?;
+        let value = KutInstruction::get_register_value(context, reg)?;
+        vm.globals.borrow_mut().set(slot, value);
+        Ok(None)
+    }
+
+    fn handle_get_module(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, name: u16) -> KutReturnType<'template> {
+        let exports = match vm.literal(context.closure.template, name)? {
+            KutValue::String(name) => {
+                KutInstruction::check_register(context, reg, KutError::OutOfRangeDestinationRegister { register: reg, register_count: context.registers.len() })?;
+                if let Some(frame) = vm.module_entry(name)? {
+                    // Runs the entry as a call, then this instruction again for the exports.
+                    context.pc -= 1;
+                    context.callee = Some(Box::new(KutCall { frame, target: KutReturnTarget::Discard, coroutine: None }));
+                    return Ok(None);
+                }
+                vm.module_exports(name)?
+            },
+            other => return Err(KutError::InvalidOperand { operation: "module name".to_owned(), value_type: other.get_type_string() }),
+        };
+        KutInstruction::set_register_value(context, reg, exports)?;
+        Ok(None)
+    }
+
+    fn handle_new_coroutine(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, func: u8) -> KutReturnType<'template> {
+        let closure = match KutInstruction::get_register_value(context, func)? {
+            KutValue::Func(closure) => closure,
+            other => return Err(KutError::TypeMismatch { register: func, expected: "Func".to_owned(), value_type: other.get_type_string() }),
+        };
+        let coroutine = KutValue::Coroutine(Rc::new(KutCoroutine::new(&closure)));
+        vm.memory.track(&coroutine)?;
+        KutInstruction::set_register_value(context, reg, coroutine)?;
+        Ok(None)
+    }
+
+    /// Moves the coroutine frame into the callee, `KutFunction::run` stores what it
+    /// yields or returns into `reg`.
+    fn handle_coroutine_resume(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, coroutine: u8, value: u8) -> KutReturnType<'template> {
+        KutInstruction::check_register(context, reg, KutError::OutOfRangeDestinationRegister { register: reg, register_count: context.registers.len() })?;
+        let coroutine = KutInstruction::get_coroutine_value(context, coroutine)?;
+        let value = KutInstruction::get_register_value(context, value)?;
+        vm.check_call_depth()?;
+        let frame = coroutine.take_frame(vm, Some(value))?;
+        context.callee = Some(Box::new(KutCall { frame, target: KutReturnTarget::Register(reg), coroutine: Some(coroutine) }));
+        Ok(None)
+    }
+
+    /// Suspends the running coroutine with the value of `value`, the value it is resumed
+    /// with is stored into `reg`.
+    fn handle_coroutine_yield(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, value: u8) -> KutReturnType<'template> {
+        KutInstruction::check_register(context, reg, KutError::OutOfRangeDestinationRegister { register: reg, register_count: context.registers.len() })?;
+        let value = KutInstruction::get_register_value(context, value)?;
+        vm.set_yielded(value);
+        context.resume_target = Some(KutReturnTarget::Register(reg));
+        Err(KutError::Yielded)
+    }
+
+    fn handle_coroutine_status(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, coroutine: u8) -> KutReturnType<'template> {
+        let coroutine = KutInstruction::get_coroutine_value(context, coroutine)?;
+        let status = KutValue::String(Rc::new(coroutine.status().as_str().to_owned()));
+        vm.memory.track(&status)?;
+        KutInstruction::set_register_value(context, reg, status)?;
+        Ok(None)
+    }
+
+    fn handle_iterator_create(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, value: u8) -> KutReturnType<'template> {
+        let iterator = match KutInstruction::get_register_value(context, value)? {
+            iterator @ KutValue::Iterator(_) => iterator,
+            value => {
+                let iterator = KutValue::Iterator(Rc::new(KutIterator::new(&value)?));
+                vm.memory.track(&iterator)?;
+                iterator
+            },
+        };
+        KutInstruction::set_register_value(context, reg, iterator)?;
+        Ok(None)
+    }
+
+    fn handle_iterator_range(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, start: u8, end: u8, step: u8) -> KutReturnType<'template> {
+        let start = KutInstruction::get_register_value(context, start)?.as_i64()?;
+        let end = KutInstruction::get_register_value(context, end)?.as_i64()?;
+        let step = KutInstruction::get_register_value(context, step)?.as_i64()?;
+        let iterator = KutValue::Iterator(Rc::new(KutIterator::range(start, end, step)?));
+        vm.memory.track(&iterator)?;
+        KutInstruction::set_register_value(context, reg, iterator)?;
+        Ok(None)
+    }
+
+    /// Stores the next item of the iterator in `iter` into `reg`, or jumps to `target` when
+    /// it is exhausted. Function and coroutine iterators run as the callee, whose result
+    /// is handled by `KutFunction::store_result`.
+    fn handle_iterator_next(context: &mut KutFunction<'template>, vm: &'template KutVm<'template>, reg: u8, iter: u8, target: u16) -> KutReturnType<'template> {
+        let instruction_count = context.closure.template.instructions.len();
+        if target as usize > instruction_count {
+            return Err(KutError::OutOfRangeJump { target, instruction_count });
+        }
+        KutInstruction::check_register(context, reg, KutError::OutOfRangeDestinationRegister { register: reg, register_count: context.registers.len() })?;
+        let iterator = KutInstruction::get_iterator_value(context, iter)?;
+        match iterator.step(vm)? {
+            KutStep::Item(item) => KutInstruction::set_register_value(context, reg, item)?,
+            KutStep::Done => context.pc = target as usize,
+            KutStep::Call(callee) => {
+                vm.check_call_depth()?;
+                KutInstruction::call_value(context, vm, callee, vec![], KutReturnTarget::Iterator { reg, exit: target })?;
+            },
+            KutStep::Resume(coroutine) => {
+                vm.check_call_depth()?;
+                let frame = coroutine.take_frame(vm, None)?;
+                context.callee = Some(Box::new(KutCall { frame, target: KutReturnTarget::Iterator { reg, exit: target }, coroutine: Some(coroutine) }));
+            },
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::value::*;
+    use crate::vm::*;
+    use KutInstruction::*;
+
+    fn frame<'template>(vm: &'template KutVm<'template>, registers: Vec<KutValue<'template>>) -> KutFunction<'template> {
+        Rc::new(KutClosure { template: &vm.templates[0], captures: vec![] }).start_with(vm, registers).unwrap()
+    }
+
+    fn list<'template>(items: impl IntoIterator<Item = i64>) -> KutValue<'template> {
+        KutValue::List(Rc::new(items.into_iter().map(KutValue::Integer).collect()))
+    }
+
+    fn items(value: &KutValue) -> Vec<i64> {
+        match value {
+            KutValue::List(list) => list.iter().map(|item| item.as_i64().unwrap()).collect(),
+            other => panic!("{other:?} is not a list"),
+        }
+    }
+
+    fn vm<'template>() -> KutVm<'template> {
+        KutVm::new(vec![], vec![KutFunctionTemplate::new(vec![], vec![], 4)])
+    }
+
+    #[test]
+    fn mutating_a_shared_list_copies_it_first() {
+        let vm = &vm();
+        let mut frame = frame(vm, vec![list([1, 2, 3]), KutValue::Nil, KutValue::Integer(-1), KutValue::Integer(9)]);
+        frame.registers[1] = frame.registers[0].clone();
+        ListSetItem { list: 0, index: 2, value: 3 }.run(&mut frame, vm).unwrap();
+        ListPushVal { list: 0, value: 3 }.run(&mut frame, vm).unwrap();
+        assert_eq!(items(&frame.registers[0]), [1, 2, 9, 9]);
+        assert_eq!(items(&frame.registers[1]), [1, 2, 3]);
+        ListPopValR { reg: 3, list: 1 }.run(&mut frame, vm).unwrap();
+        assert_eq!(items(&frame.registers[1]), [1, 2]);
+        assert_eq!(items(&frame.registers[0]), [1, 2, 9, 9]);
+        assert!(matches!(frame.registers[3], KutValue::Integer(3)));
+    }
+
+    #[test]
+    fn mutating_a_list_through_a_reference_is_seen_by_every_holder() {
+        let vm = &vm();
+        let shared = KutValue::Reference(Rc::new(std::cell::RefCell::new(list([1, 2]))));
+        let mut frame = frame(vm, vec![shared.clone(), shared, KutValue::Integer(0), KutValue::Integer(5)]);
+        ListSetItem { list: 0, index: 2, value: 3 }.run(&mut frame, vm).unwrap();
+        ListGetItem { reg: 2, list: 1, index: 2 }.run(&mut frame, vm).unwrap();
+        assert!(matches!(frame.registers[2], KutValue::Integer(5)));
+    }
+
+    #[test]
+    fn negative_indices_count_from_the_end() {
+        let vm = &vm();
+        let mut frame = frame(vm, vec![list([1, 2, 3]), KutValue::Integer(-1), KutValue::Nil, KutValue::Integer(7)]);
+        ListGetItem { reg: 2, list: 0, index: 1 }.run(&mut frame, vm).unwrap();
+        assert!(matches!(frame.registers[2], KutValue::Integer(3)));
+        frame.registers[1] = KutValue::Integer(-3);
+        ListSetItem { list: 0, index: 1, value: 3 }.run(&mut frame, vm).unwrap();
+        assert_eq!(items(&frame.registers[0]), [7, 2, 3]);
+        for index in [-4, 3] {
+            frame.registers[1] = KutValue::Integer(index);
+            let error = ListGetItem { reg: 2, list: 0, index: 1 }.run(&mut frame, vm).unwrap_err();
+            assert!(matches!(error, KutError::OutOfRangeIndex { index: wanted, length: 3 } if wanted == index), "{error:?}");
+            let error = ListSetItem { list: 0, index: 1, value: 3 }.run(&mut frame, vm).unwrap_err();
+            assert!(matches!(error, KutError::OutOfRangeIndex { length: 3, .. }), "{error:?}");
+        }
+        frame.registers[1] = KutValue::Number(1.0);
+        ListGetItem { reg: 2, list: 0, index: 1 }.run(&mut frame, vm).unwrap();
+        assert!(matches!(frame.registers[2], KutValue::Integer(2)));
+        frame.registers[1] = KutValue::Number(1.5);
+        assert!(matches!(ListGetItem { reg: 2, list: 0, index: 1 }.run(&mut frame, vm), Err(KutError::NonIntegralNumber { .. })));
+        assert_eq!(items(&frame.registers[0]), [7, 2, 3]);
+    }
+
+    #[test]
+    fn slices_clamp_the_end_to_the_start() {
+        let vm = &vm();
+        let slice = |start: i64, end: i64| {
+            let mut frame = frame(vm, vec![list([1, 2, 3, 4]), KutValue::Integer(start), KutValue::Integer(end), KutValue::Nil]);
+            ListSliceRg { reg: 3, list: 0, start: 1, end: 2 }.run(&mut frame, vm).map(|_| items(&frame.registers[3]))
+        };
+        assert_eq!(slice(1, 3).unwrap(), [2, 3]);
+        assert_eq!(slice(-3, -1).unwrap(), [2, 3]);
+        assert_eq!(slice(0, 4).unwrap(), [1, 2, 3, 4]);
+        assert_eq!(slice(4, 4).unwrap(), Vec::<i64>::new());
+        assert_eq!(slice(3, 1).unwrap(), Vec::<i64>::new());
+        assert_eq!(slice(-1, 0).unwrap(), Vec::<i64>::new());
+        assert!(matches!(slice(0, 5), Err(KutError::OutOfRangeIndex { index: 5, length: 4 })));
+        assert!(matches!(slice(-5, 2), Err(KutError::OutOfRangeIndex { index: -5, length: 4 })));
+    }
+
+    #[test]
+    fn popping_an_empty_list_fails_without_changing_it() {
+        let vm = &vm();
+        let mut frame = frame(vm, vec![list([1]), KutValue::Nil, KutValue::Nil, KutValue::Nil]);
+        ListPopValR { reg: 1, list: 0 }.run(&mut frame, vm).unwrap();
+        assert!(matches!(frame.registers[1], KutValue::Integer(1)));
+        let error = ListPopValR { reg: 1, list: 0 }.run(&mut frame, vm).unwrap_err();
+        assert!(matches!(error, KutError::OutOfRangeIndex { index: -1, length: 0 }), "{error:?}");
+        assert!(items(&frame.registers[0]).is_empty());
+        assert!(matches!(frame.registers[1], KutValue::Integer(1)));
+        let error = ListPopValR { reg: 1, list: 2 }.run(&mut frame, vm).unwrap_err();
+        assert!(matches!(error, KutError::TypeMismatch { register: 2, .. }), "{error:?}");
+    }
 }
@@ -0,0 +1,170 @@
+use crate::value::*;
+use crate::vm::*;
+
+/// How an iterator advanced. Iterators that need script code to produce their next
+/// item hand out the function or coroutine to run, so that `IterNextReg` can run it
+/// as a resumable callee.
+pub(crate) enum KutStep<'template> {
+    Item(KutValue<'template>),
+    Done,
+    Call(KutCallee<'template>),
+    Resume(Rc<KutCoroutine<'template>>),
+}
+
+/// Lists and vectors yield their items, strings their characters as strings, maps a
+/// `[key, value]` list for every entry in insertion order and ranges their integers.
+/// Collections are iterated as they were when the iterator was created. A `Func` or
+/// `Native` is called without arguments for every item until it returns `Undefined`,
+/// a `Coroutine` is started without arguments and resumed with nil until it returns,
+/// and an `External` is advanced by its `next` function.
+impl<'template> KutIterator<'template> {
+    pub fn new(value: &KutValue<'template>) -> Result<KutIterator<'template>, KutError> {
+        let state = match value {
+            KutValue::List(list) => KutIteratorState::List(Rc::clone(list), 0),
+            KutValue::Vector(vector) => KutIteratorState::Vector(Rc::clone(vector), 0),
+            KutValue::String(string) => KutIteratorState::String(Rc::clone(string), 0),
+            KutValue::Map(map) => KutIteratorState::Map(Rc::clone(map), 0),
+            KutValue::Func(closure) => KutIteratorState::Call(KutCallee::Func(Rc::clone(closure))),
+            KutValue::Native(native) => KutIteratorState::Call(KutCallee::Native(Rc::clone(native))),
+            KutValue::Coroutine(coroutine) => KutIteratorState::Coroutine(Rc::clone(coroutine)),
+            KutValue::External(ext) if ext.next.is_some() => KutIteratorState::External(Rc::clone(ext)),
+            KutValue::Reference(r) => return KutIterator::new(&r.borrow()),
+            other => return Err(KutError::InvalidOperand { operation: "iteration".to_owned(), value_type: other.get_type_string() }),
+        };
+        Ok(KutIterator { state: RefCell::new(state) })
+    }
+
+    /// Iterates from `start` up to, but excluding, `end` in steps of `step`, which
+    /// counts down when negative.
+    pub fn range(start: i64, end: i64, step: i64) -> Result<KutIterator<'template>, KutError> {
+        if step == 0 {
+            return Err(KutError::InvalidOperand { operation: "range with step 0".to_owned(), value_type: "Integer".to_owned() });
+        }
+        Ok(KutIterator { state: RefCell::new(KutIteratorState::Range { next: start, end, step }) })
+    }
+
+    /// Advances the iterator from Rust, running function and coroutine iterators to
+    /// their next item. Returns `None` once the iterator is exhausted.
+    pub fn next(&self, vm: &'template KutVm<'template>) -> Result<Option<KutValue<'template>>, KutError> {
+        let item = match self.step(vm)? {
+            KutStep::Item(item) => item,
+            KutStep::Done => return Ok(None),
+            KutStep::Call(KutCallee::Native(native)) => native.call(vm, vec![])?,
+            KutStep::Call(KutCallee::Func(closure)) => closure.start_with(vm, vec![])?.run(vm)?.unwrap_or(KutValue::Nil),
+            KutStep::Resume(coroutine) => {
+                let item = coroutine.advance(vm, None)?;
+                if coroutine.status() == KutCoroutineStatus::Dead {
+                    return Ok(None);
+                }
+                item
+            },
+        };
+        Ok(if matches!(item, KutValue::Undefined) { None } else { Some(item) })
+    }
+
+    pub(crate) fn step(&self, vm: &'template KutVm<'template>) -> Result<KutStep<'template>, KutError> {
+        let mut state = self.state.borrow_mut();
+        let item = match &mut *state {
+            KutIteratorState::List(list, index) => {
+                *index += 1;
+                list.get(*index - 1).cloned()
+            },
+            KutIteratorState::Vector(vector, index) => {
+                *index += 1;
+                vector.get(*index - 1).cloned()
+            },
+            KutIteratorState::String(string, offset) => match string[*offset..].chars().next() {
+                Some(ch) => {
+                    let item = KutValue::String(Rc::new(ch.to_string()));
+                    vm.memory.track(&item)?;
+                    *offset += ch.len_utf8();
+                    Some(item)
+                },
+                None => None,
+            },
+            KutIteratorState::Map(map, index) => {
+                let entry = map.entries.iter().enumerate().skip(*index).find_map(|(entry, pair)| pair.as_ref().map(|pair| (entry, pair)));
+                match entry {
+                    Some((entry, (key, value))) => {
+                        let item = KutValue::List(Rc::new(vec![key.0.clone(), value.clone()]));
+                        vm.memory.track(&item)?;
+                        *index = entry + 1;
+                        Some(item)
+                    },
+                    None => None,
+                }
+            },
+            KutIteratorState::Range { next, end, step } => {
+                if (*step > 0 && *next < *end) || (*step < 0 && *next > *end) {
+                    let item = *next;
+                    *next = next.checked_add(*step).unwrap_or(*end);
+                    return Ok(KutStep::Item(KutValue::Integer(item)));
+                }
+                None
+            },
+            KutIteratorState::Call(function) => return Ok(KutStep::Call(function.clone())),
+            KutIteratorState::Coroutine(coroutine) => {
+                if coroutine.status() == KutCoroutineStatus::Dead {
+                    return Ok(KutStep::Done);
+                }
+                return Ok(KutStep::Resume(Rc::clone(coroutine)));
+            },
+            KutIteratorState::External(ext) => match ext.next.and_then(|next| next(vm, ext.data)) {
+                Some(item) => {
+                    vm.memory.track(&item)?;
+                    return Ok(KutStep::Item(item));
+                },
+                None => None,
+            },
+        };
+        Ok(item.map_or(KutStep::Done, KutStep::Item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::*;
+    use crate::value::*;
+    use crate::vm::*;
+
+    /// Advances `iterator` to its end with room for one item at a time, raising the limit
+    /// after every `OutOfMemory` while keeping every item alive.
+    fn collect_tightly<'template>(vm: &'template KutVm<'template>, iterator: &KutIterator<'template>, item_size: usize) -> (Vec<KutValue<'template>>, usize) {
+        let mut items = vec![];
+        let mut failures = 0;
+        vm.memory.set_limit(Some(vm.memory.used() + item_size));
+        loop {
+            match iterator.next(vm) {
+                Ok(Some(item)) => items.push(item),
+                Ok(None) => return (items, failures),
+                Err(KutError::OutOfMemory { .. }) => {
+                    failures += 1;
+                    vm.memory.set_limit(Some(vm.memory.used() + item_size));
+                },
+                Err(error) => panic!("{error:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn string_items_are_not_skipped_after_running_out_of_memory() {
+        let vm = &KutVm::new(vec![], vec![]);
+        let iterator = KutIterator::new(&KutValue::String(Rc::new("aé€".to_owned()))).unwrap();
+        let (items, failures) = collect_tightly(vm, &iterator, KutMemory::string_size(3));
+        assert_eq!(failures, 2);
+        assert_eq!(items.iter().map(KutValue::to_display_string).collect::<Vec<_>>(), ["a", "é", "€"]);
+    }
+
+    #[test]
+    fn map_items_are_not_skipped_after_running_out_of_memory() {
+        let vm = &KutVm::new(vec![], vec![]);
+        let mut map = KutMap::new();
+        for (key, value) in [("a", 1), ("b", 2), ("c", 3)] {
+            map.insert(KutKey(KutValue::String(Rc::new(key.to_owned()))), KutValue::Integer(value));
+        }
+        let iterator = KutIterator::new(&KutValue::Map(Rc::new(map))).unwrap();
+        let (items, failures) = collect_tightly(vm, &iterator, KutMemory::list_size(2));
+        assert_eq!(failures, 2);
+        assert_eq!(items.iter().map(KutValue::to_repr_string).collect::<Vec<_>>(), [r#"["a", 1]"#, r#"["b", 2]"#, r#"["c", 3]"#]);
+    }
+}
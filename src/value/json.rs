@@ -0,0 +1,499 @@
+use crate::value::*;
+use crate::memory::*;
+
+const MAX_DEPTH: usize = 512;
+
+struct KutJsonParser<'text, 'memory, 'template> {
+    text: &'text [u8],
+    position: usize,
+    depth: usize,
+    memory: Option<&'memory KutMemory<'template>>,
+}
+
+impl<'text, 'memory, 'template> KutJsonParser<'text, 'memory, 'template> {
+    fn error<T>(&self, message: &str) -> Result<T, KutError> {
+        Err(KutError::InvalidJson { position: self.position, message: message.to_owned() })
+    }
+
+    fn track(&self, value: KutValue<'template>) -> Result<KutValue<'template>, KutError> {
+        if let Some(memory) = self.memory {
+            memory.track(&value)?;
+        }
+        Ok(value)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.text.get(self.position) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.text.get(self.position).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), KutError> {
+        if self.peek() == Some(byte) {
+            self.position += 1;
+            Ok(())
+        } else {
+            self.error(&format!("expected '{}'", byte as char))
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str, value: KutValue<'template>) -> Result<KutValue<'template>, KutError> {
+        if self.text[self.position..].starts_with(keyword.as_bytes()) {
+            self.position += keyword.len();
+            Ok(value)
+        } else {
+            self.error("unexpected character")
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<KutValue<'template>, KutError> {
+        match self.peek() {
+            None => self.error("unexpected end of input"),
+            Some(b'n') => self.keyword("null", KutValue::Nil),
+            Some(b't') => self.keyword("true", KutValue::Bool(true)),
+            Some(b'f') => self.keyword("false", KutValue::Bool(false)),
+            Some(b'"') => {
+                let string = KutValue::String(Rc::new(self.parse_string()?));
+                self.track(string)
+            },
+            Some(b'[') => self.nested(KutJsonParser::parse_array),
+            Some(b'{') => self.nested(KutJsonParser::parse_object),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            Some(_) => self.error("unexpected character"),
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<KutValue<'template>, KutError>) -> Result<KutValue<'template>, KutError> {
+        if self.depth == MAX_DEPTH {
+            return self.error("nesting is too deep");
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn parse_array(&mut self) -> Result<KutValue<'template>, KutError> {
+        self.expect(b'[')?;
+        let mut items = vec![];
+        if self.peek() == Some(b']') {
+            self.position += 1;
+        } else {
+            loop {
+                items.push(self.parse_value()?);
+                match self.peek() {
+                    Some(b',') => self.position += 1,
+                    Some(b']') => {
+                        self.position += 1;
+                        break;
+                    },
+                    _ => return self.error("expected ',' or ']'"),
+                }
+            }
+        }
+        self.track(KutValue::List(Rc::new(items)))
+    }
+
+    fn parse_object(&mut self) -> Result<KutValue<'template>, KutError> {
+        self.expect(b'{')?;
+        let mut map = KutMap::new();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+        } else {
+            loop {
+                if self.peek() != Some(b'"') {
+                    return self.error("expected a string key");
+                }
+                let key = self.parse_value()?;
+                self.expect(b':')?;
+                let value = self.parse_value()?;
+                map.insert(KutKey(key), value);
+                match self.peek() {
+                    Some(b',') => self.position += 1,
+                    Some(b'}') => {
+                        self.position += 1;
+                        break;
+                    },
+                    _ => return self.error("expected ',' or '}'"),
+                }
+            }
+        }
+        self.track(KutValue::Map(Rc::new(map)))
+    }
+
+    fn parse_number(&mut self) -> Result<KutValue<'template>, KutError> {
+        let start = self.position;
+        let digits = |parser: &mut Self| {
+            let from = parser.position;
+            while parser.text.get(parser.position).is_some_and(u8::is_ascii_digit) {
+                parser.position += 1;
+            }
+            parser.position - from
+        };
+        if self.text[self.position] == b'-' {
+            self.position += 1;
+        }
+        match self.text.get(self.position) {
+            Some(b'0') => self.position += 1,
+            Some(b'1'..=b'9') => {
+                digits(self);
+            },
+            _ => return self.error("expected a digit"),
+        }
+        if self.text.get(self.position) == Some(&b'.') {
+            self.position += 1;
+            if digits(self) == 0 {
+                return self.error("expected a digit");
+            }
+        }
+        if let Some(b'e' | b'E') = self.text.get(self.position) {
+            self.position += 1;
+            if let Some(b'+' | b'-') = self.text.get(self.position) {
+                self.position += 1;
+            }
+            if digits(self) == 0 {
+                return self.error("expected a digit");
+            }
+        }
+        let text = std::str::from_utf8(&self.text[start..self.position]).unwrap_or_default();
+        match text.parse::<f64>() {
+            Ok(num) => Ok(KutValue::Number(num)),
+            Err(_) => self.error("invalid number"),
+        }
+    }
+
+    fn parse_hex(&mut self) -> Result<u32, KutError> {
+        let hex = self.text.get(self.position..self.position + 4).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+        match hex.and_then(|hex| u32::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()) {
+            Some(code) => {
+                self.position += 4;
+                Ok(code)
+            },
+            None => self.error("expected four hexadecimal digits"),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, KutError> {
+        self.position += 1;
+        let mut string = String::new();
+        loop {
+            let start = self.position;
+            while let Some(byte) = self.text.get(self.position) {
+                if matches!(byte, b'"' | b'\\') || *byte < 0x20 {
+                    break;
+                }
+                self.position += 1;
+            }
+            match std::str::from_utf8(&self.text[start..self.position]) {
+                Ok(run) => string.push_str(run),
+                Err(_) => return self.error("invalid UTF-8"),
+            }
+            match self.text.get(self.position) {
+                None => return self.error("unterminated string"),
+                Some(b'"') => {
+                    self.position += 1;
+                    return Ok(string);
+                },
+                Some(b'\\') => {
+                    self.position += 1;
+                    let escape = self.text.get(self.position).copied();
+                    self.position += 1;
+                    match escape {
+                        Some(b'"') => string.push('"'),
+                        Some(b'\\') => string.push('\\'),
+                        Some(b'/') => string.push('/'),
+                        Some(b'b') => string.push('\u{8}'),
+                        Some(b'f') => string.push('\u{c}'),
+                        Some(b'n') => string.push('\n'),
+                        Some(b'r') => string.push('\r'),
+                        Some(b't') => string.push('\t'),
+                        Some(b'u') => string.push(self.parse_unicode_escape()?),
+                        _ => {
+                            self.position -= 1;
+                            return self.error("invalid escape");
+                        },
+                    }
+                },
+                Some(_) => return self.error("control character in string"),
+            }
+        }
+    }
+
+    fn parse_unicode_escape(&mut self) -> Result<char, KutError> {
+        let high = self.parse_hex()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            if !self.text[self.position..].starts_with(b"\\u") {
+                return self.error("unpaired surrogate");
+            }
+            self.position += 2;
+            let low = self.parse_hex()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return self.error("unpaired surrogate");
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.error("unpaired surrogate"),
+        }
+    }
+}
+
+struct KutJsonWriter<'memory, 'template> {
+    output: String,
+    pretty: bool,
+    depth: usize,
+    seen: Vec<usize>,
+    memory: Option<&'memory KutMemory<'template>>,
+}
+
+impl<'memory, 'template> KutJsonWriter<'memory, 'template> {
+    fn newline(&mut self) {
+        if self.pretty {
+            self.output.push('\n');
+            for _ in 0..self.depth {
+                self.output.push_str("  ");
+            }
+        }
+    }
+
+    fn write_string(&mut self, string: &str) {
+        self.output.push('"');
+        for c in string.chars() {
+            match c {
+                '"' => self.output.push_str("\\\""),
+                '\\' => self.output.push_str("\\\\"),
+                '\n' => self.output.push_str("\\n"),
+                '\r' => self.output.push_str("\\r"),
+                '\t' => self.output.push_str("\\t"),
+                c if (c as u32) < 0x20 => self.output.push_str(&format!("\\u{:04x}", c as u32)),
+                c => self.output.push(c),
+            }
+        }
+        self.output.push('"');
+    }
+
+    fn write_sequence<'a, 'value: 'a>(&mut self, open: char, close: char, items: impl Iterator<Item = (Option<&'a KutValue<'value>>, &'a KutValue<'value>)>) -> Result<(), KutError> {
+        self.output.push(open);
+        self.depth += 1;
+        let mut empty = true;
+        for (key, value) in items {
+            if !empty {
+                self.output.push(',');
+            }
+            empty = false;
+            self.newline();
+            if let Some(key) = key {
+                match key {
+                    KutValue::String(key) => self.write_string(key),
+                    other => return Err(KutError::InvalidOperand { operation: "json object key".to_owned(), value_type: other.get_type_string() }),
+                }
+                self.output.push_str(if self.pretty { ": " } else { ":" });
+            }
+            self.write_value(value)?;
+        }
+        self.depth -= 1;
+        if !empty {
+            self.newline();
+        }
+        self.output.push(close);
+        Ok(())
+    }
+
+    fn write_value(&mut self, value: &KutValue) -> Result<(), KutError> {
+        let identity = value.identity();
+        if let Some(identity) = identity {
+            if self.seen.contains(&identity) {
+                return Err(KutError::CyclicValue { operation: "json encoding".to_owned() });
+            }
+            self.seen.push(identity);
+        }
+        match value {
+            KutValue::Nil => self.output.push_str("null"),
+            KutValue::Bool(b) => self.output.push_str(if *b { "true" } else { "false" }),
+            KutValue::Integer(int) => self.output.push_str(&int.to_string()),
+            KutValue::Number(num) if num.is_finite() => self.output.push_str(&num.to_string()),
+            KutValue::Number(num) => return Err(KutError::InvalidOperand { operation: "json encoding".to_owned(), value_type: format!("Number {num}") }),
+            KutValue::String(string) => self.write_string(string),
+            KutValue::List(list) => self.write_sequence('[', ']', list.iter().map(|item| (None, item)))?,
+            KutValue::Vector(vector) => self.write_sequence('[', ']', vector.iter().map(|item| (None, item)))?,
+            KutValue::Map(map) => self.write_sequence('{', '}', map.iter().map(|(key, value)| (Some(&key.0), value)))?,
+            KutValue::Reference(r) => self.write_value(&r.borrow())?,
+            other => return Err(KutError::InvalidOperand { operation: "json encoding".to_owned(), value_type: other.get_type_string() }),
+        }
+        if identity.is_some() {
+            self.seen.pop();
+        }
+        if let Some(memory) = self.memory {
+            memory.reserve(KutMemory::string_size(self.output.len()))?;
+        }
+        Ok(())
+    }
+}
+
+/// JSON objects decode to a `Map` with `String` keys in document order, arrays to a
+/// `List`, numbers to a `Number` and `null` to `Nil`. Encoding accepts lists and
+/// vectors as arrays and maps with string keys as objects, and fails on functions,
+/// externals, `Undefined`, non-finite numbers and values that contain themselves.
+impl<'template> KutValue<'template> {
+    pub fn from_json(text: &str) -> Result<KutValue<'template>, KutError> {
+        KutValue::parse_json(text, None)
+    }
+
+    /// Same as `from_json`, accounting every decoded string and container in `memory`.
+    pub fn from_json_tracked(text: &str, memory: &KutMemory<'template>) -> Result<KutValue<'template>, KutError> {
+        KutValue::parse_json(text, Some(memory))
+    }
+
+    fn parse_json(text: &str, memory: Option<&KutMemory<'template>>) -> Result<KutValue<'template>, KutError> {
+        let mut parser = KutJsonParser { text: text.as_bytes(), position: 0, depth: 0, memory };
+        let value = parser.parse_value()?;
+        if parser.peek().is_some() {
+            return parser.error("trailing characters");
+        }
+        Ok(value)
+    }
+
+    pub fn to_json(&self) -> Result<String, KutError> {
+        self.write_json(false, None)
+    }
+
+    /// Encodes with one item per line, indented by two spaces per level.
+    pub fn to_json_pretty(&self) -> Result<String, KutError> {
+        self.write_json(true, None)
+    }
+
+    /// Same as `to_json` or, if `pretty`, `to_json_pretty`, failing with
+    /// `KutError::OutOfMemory` as soon as the output would not fit in `memory`.
+    pub fn to_json_tracked(&self, pretty: bool, memory: &KutMemory<'template>) -> Result<String, KutError> {
+        self.write_json(pretty, Some(memory))
+    }
+
+    fn write_json(&self, pretty: bool, memory: Option<&KutMemory<'template>>) -> Result<String, KutError> {
+        let mut writer = KutJsonWriter { output: String::new(), pretty, depth: 0, seen: vec![], memory };
+        writer.write_value(self)?;
+        Ok(writer.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::*;
+    use crate::value::*;
+
+    fn decode(text: &str) -> Result<KutValue<'static>, KutError> {
+        KutValue::from_json(text)
+    }
+
+    fn decode_error(text: &str) -> (usize, String) {
+        match decode(text) {
+            Err(KutError::InvalidJson { position, message }) => (position, message),
+            other => panic!("{text} decoded to {other:?}"),
+        }
+    }
+
+    fn string(text: &str) -> KutValue<'static> {
+        KutValue::String(Rc::new(text.to_owned()))
+    }
+
+    #[test]
+    fn surrogate_pairs_decode_to_one_character() {
+        assert_eq!(decode(r#""\ud83d\ude00""#).unwrap(), string("😀"));
+        assert_eq!(decode(r#""\u00e9\u20AC""#).unwrap(), string("é€"));
+        for lone in [r#""\ud83d""#, r#""\ud83dx""#, r#""\ud83d\u0041""#, r#""\ude00""#] {
+            assert_eq!(decode_error(lone).1, "unpaired surrogate", "{lone}");
+        }
+    }
+
+    #[test]
+    fn invalid_escapes_are_rejected() {
+        assert_eq!(decode_error(r#""\x""#), (2, "invalid escape".to_owned()));
+        assert_eq!(decode_error(r#""\u12""#).1, "expected four hexadecimal digits");
+        assert_eq!(decode_error(r#""\u+041""#).1, "expected four hexadecimal digits");
+        assert_eq!(decode_error("\"a\nb\"").1, "control character in string");
+        assert_eq!(decode_error(r#""abc"#).1, "unterminated string");
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(decode(&nested(512)).is_ok());
+        assert_eq!(decode_error(&nested(513)), (512, "nesting is too deep".to_owned()));
+        assert_eq!(decode_error(&"{\"a\":".repeat(600)).1, "nesting is too deep");
+    }
+
+    #[test]
+    fn trailing_characters_are_rejected() {
+        assert_eq!(decode_error("[1] x"), (4, "trailing characters".to_owned()));
+        assert_eq!(decode_error("1 2").1, "trailing characters");
+        assert!(decode(" [1] \n").is_ok());
+    }
+
+    #[test]
+    fn numbers_follow_the_json_grammar() {
+        assert!(matches!(decode("-0").unwrap(), KutValue::Number(num) if num == 0.0 && num.is_sign_negative()));
+        for (text, expected) in [("1e3", 1000.0), ("1E+2", 100.0), ("2.5e-1", 0.25), ("-12.5", -12.5), ("0", 0.0)] {
+            assert!(matches!(decode(text).unwrap(), KutValue::Number(num) if num == expected), "{text}");
+        }
+        assert_eq!(decode_error("01").1, "trailing characters");
+        for invalid in ["-", "1.", "1e", "1e+", "-a"] {
+            assert_eq!(decode_error(invalid).1, "expected a digit", "{invalid}");
+        }
+        for invalid in [".5", "+1"] {
+            assert_eq!(decode_error(invalid).1, "unexpected character", "{invalid}");
+        }
+    }
+
+    #[test]
+    fn unencodable_values_are_errors() {
+        for number in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(matches!(KutValue::Number(number).to_json(), Err(KutError::InvalidOperand { .. })));
+        }
+        let function = KutValue::Native(Rc::new(KutNativeFunction::new("f", |_, _| Ok(KutValue::Nil))));
+        assert!(matches!(KutValue::List(Rc::new(vec![function])).to_json(), Err(KutError::InvalidOperand { value_type, .. }) if value_type == "Native"));
+        let mut map = KutMap::new();
+        map.insert(KutKey(KutValue::Integer(1)), KutValue::Nil);
+        assert!(matches!(KutValue::Map(Rc::new(map)).to_json(), Err(KutError::InvalidOperand { operation, .. }) if operation == "json object key"));
+    }
+
+    #[test]
+    fn cycles_are_errors_but_shared_values_are_not() {
+        let cell = Rc::new(RefCell::new(KutValue::Nil));
+        let list = KutValue::List(Rc::new(vec![KutValue::Reference(Rc::clone(&cell))]));
+        *cell.borrow_mut() = list.clone();
+        assert!(matches!(list.to_json(), Err(KutError::CyclicValue { .. })));
+        *cell.borrow_mut() = KutValue::Nil;
+        let shared = KutValue::List(Rc::new(vec![KutValue::Integer(1)]));
+        let twice = KutValue::List(Rc::new(vec![shared.clone(), shared]));
+        assert_eq!(twice.to_json().unwrap(), "[[1],[1]]");
+    }
+
+    #[test]
+    fn compact_and_pretty_output_round_trip() {
+        let text = r#"{"name":"kut \"json\"\n","items":[1,2.5,true,null,[],{}],"nested":{"a":[{"b":"é"}]}}"#;
+        let value = decode(text).unwrap();
+        assert_eq!(value.to_json().unwrap(), text);
+        let pretty = value.to_json_pretty().unwrap();
+        assert!(pretty.starts_with("{\n  \"name\": \"kut \\\"json\\\"\\n\",\n  \"items\": [\n    1,\n"), "{pretty}");
+        assert!(pretty.contains("\"nested\": {\n    \"a\": [\n      {\n        \"b\": \"é\"\n      }\n    ]\n  }\n}"), "{pretty}");
+        assert_eq!(decode(&pretty).unwrap(), value);
+        assert_eq!(KutValue::Integer(-7).to_json().unwrap(), "-7");
+        assert_eq!(string("\u{1}").to_json().unwrap(), r#""\u0001""#);
+    }
+
+    #[test]
+    fn tracked_encoding_stops_at_the_limit() {
+        let memory = KutMemory::new();
+        memory.set_limit(Some(KutMemory::string_size(100)));
+        let long = KutValue::List(Rc::new(vec![string(&"a".repeat(60)), string(&"b".repeat(60))]));
+        assert!(matches!(long.to_json_tracked(false, &memory), Err(KutError::OutOfMemory { .. })));
+        assert!(string("short").to_json_tracked(true, &memory).is_ok());
+    }
+}
@@ -0,0 +1,233 @@
+use crate::value::*;
+use std::hash::{Hash, Hasher};
+
+#[derive(PartialEq, Eq, Hash)]
+enum KutNumberKey {
+    Integer(i64),
+    Float(u64),
+}
+
+/// Key equality is structural for scalars, strings, lists and vectors and by identity for
+/// everything that is mutable or opaque. Numbers are keyed by value, so `Integer(1)`
+/// is the same key as `Number(1.0)`, `-0.0` the same as `0.0` and every NaN the same.
+impl<'template> KutKey<'template> {
+    fn number_key(value: &KutValue) -> Option<KutNumberKey> {
+        match value {
+            KutValue::Integer(int) => Some(KutNumberKey::Integer(*int)),
+            KutValue::Number(num) if num.is_nan() => Some(KutNumberKey::Float(f64::NAN.to_bits())),
+            KutValue::Number(_) => match value.as_i64() {
+                Ok(int) => Some(KutNumberKey::Integer(int)),
+                Err(_) => value.as_f64().map(|num| KutNumberKey::Float(num.to_bits())),
+            },
+            _ => None,
+        }
+    }
+
+    fn hash_value<H: Hasher>(value: &KutValue, state: &mut H) {
+        match value {
+            KutValue::Nil => 0u8.hash(state),
+            KutValue::Undefined => 1u8.hash(state),
+            KutValue::Bool(b) => (2u8, b).hash(state),
+            KutValue::Integer(_) | KutValue::Number(_) => (3u8, KutKey::number_key(value)).hash(state),
+            KutValue::String(string) => (4u8, string).hash(state),
+            KutValue::List(list) => {
+                (5u8, list.len()).hash(state);
+                for item in list.iter() {
+                    KutKey::hash_value(item, state);
+                }
+            },
+            KutValue::Vector(vector) => {
+                (6u8, vector.len()).hash(state);
+                for item in vector.iter() {
+                    KutKey::hash_value(item, state);
+                }
+            },
+            KutValue::Func(func) => (7u8, Rc::as_ptr(func)).hash(state),
+            KutValue::Reference(r) => (8u8, Rc::as_ptr(r)).hash(state),
+            KutValue::External(ext) => (9u8, Rc::as_ptr(ext)).hash(state),
+            KutValue::Map(map) => (10u8, Rc::as_ptr(map)).hash(state),
+            KutValue::Native(native) => (11u8, Rc::as_ptr(native)).hash(state),
+            KutValue::Coroutine(coroutine) => (12u8, Rc::as_ptr(coroutine)).hash(state),
+            KutValue::Iterator(iterator) => (13u8, Rc::as_ptr(iterator)).hash(state),
+        }
+    }
+
+    fn value_eq(lhs: &KutValue<'template>, rhs: &KutValue<'template>) -> bool {
+        match (lhs, rhs) {
+            (KutValue::Nil, KutValue::Nil) => true,
+            (KutValue::Undefined, KutValue::Undefined) => true,
+            (KutValue::Bool(a), KutValue::Bool(b)) => a == b,
+            (KutValue::Integer(_) | KutValue::Number(_), KutValue::Integer(_) | KutValue::Number(_)) => {
+                KutKey::number_key(lhs) == KutKey::number_key(rhs)
+            },
+            (KutValue::String(a), KutValue::String(b)) => a == b,
+            (KutValue::List(a), KutValue::List(b)) => {
+                Rc::ptr_eq(a, b) || (a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| KutKey::value_eq(a, b)))
+            },
+            (KutValue::Vector(a), KutValue::Vector(b)) => {
+                Rc::ptr_eq(a, b) || (a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| KutKey::value_eq(a, b)))
+            },
+            (KutValue::Func(a), KutValue::Func(b)) => Rc::ptr_eq(a, b),
+            (KutValue::Reference(a), KutValue::Reference(b)) => Rc::ptr_eq(a, b),
+            (KutValue::External(a), KutValue::External(b)) => Rc::ptr_eq(a, b),
+            (KutValue::Map(a), KutValue::Map(b)) => Rc::ptr_eq(a, b),
+            (KutValue::Native(a), KutValue::Native(b)) => Rc::ptr_eq(a, b),
+            (KutValue::Coroutine(a), KutValue::Coroutine(b)) => Rc::ptr_eq(a, b),
+            (KutValue::Iterator(a), KutValue::Iterator(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl<'template> Hash for KutKey<'template> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        KutKey::hash_value(&self.0, state);
+    }
+}
+
+impl<'template> PartialEq for KutKey<'template> {
+    fn eq(&self, other: &Self) -> bool {
+        KutKey::value_eq(&self.0, &other.0)
+    }
+}
+
+impl<'template> Eq for KutKey<'template> {}
+
+/// Insertion ordered hash map. Removed entries leave a hole in `entries` that is
+/// compacted away once holes make up half of it.
+impl<'template> KutMap<'template> {
+    pub fn new() -> KutMap<'template> {
+        KutMap::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.capacity()
+    }
+
+    pub fn get(&self, key: &KutKey<'template>) -> Option<&KutValue<'template>> {
+        let position = *self.index.get(key)?;
+        self.entries[position].as_ref().map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, key: &KutKey<'template>) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn insert(&mut self, key: KutKey<'template>, value: KutValue<'template>) -> Option<KutValue<'template>> {
+        if let Some(position) = self.index.get(&key) {
+            let entry = self.entries[*position].as_mut().map(|(_, old)| old);
+            return entry.map(|old| std::mem::replace(old, value));
+        }
+        self.index.insert(key.clone(), self.entries.len());
+        self.entries.push(Some((key, value)));
+        None
+    }
+
+    pub fn remove(&mut self, key: &KutKey<'template>) -> Option<KutValue<'template>> {
+        let position = self.index.remove(key)?;
+        let removed = self.entries[position].take().map(|(_, value)| value);
+        if 2 * self.index.len() < self.entries.len() {
+            self.entries.retain(Option::is_some);
+            for (position, entry) in self.entries.iter().enumerate() {
+                if let Some((key, _)) = entry {
+                    self.index.insert(key.clone(), position);
+                }
+            }
+        }
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&KutKey<'template>, &KutValue<'template>)> {
+        self.entries.iter().flatten().map(|(key, value)| (key, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &KutKey<'template>> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &KutValue<'template>> {
+        self.iter().map(|(_, value)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn key<'template>(value: KutValue<'template>) -> KutKey<'template> {
+        KutKey(value)
+    }
+
+    fn map_with<'template>(keys: impl IntoIterator<Item = KutValue<'template>>) -> KutMap<'template> {
+        let mut map = KutMap::new();
+        for (position, value) in keys.into_iter().enumerate() {
+            map.insert(key(value), KutValue::Integer(position as i64));
+        }
+        map
+    }
+
+    #[test]
+    fn numbers_are_keyed_by_value() {
+        let map = map_with([KutValue::Integer(1), KutValue::Number(0.0), KutValue::Number(f64::NAN), KutValue::Number(1.5)]);
+        assert_eq!(map.len(), 4);
+        assert!(key(KutValue::Integer(1)) == key(KutValue::Number(1.0)));
+        assert!(matches!(map.get(&key(KutValue::Number(1.0))), Some(KutValue::Integer(0))));
+        assert!(key(KutValue::Number(-0.0)) == key(KutValue::Number(0.0)));
+        assert!(matches!(map.get(&key(KutValue::Number(-0.0))), Some(KutValue::Integer(1))));
+        assert!(matches!(map.get(&key(KutValue::Integer(0))), Some(KutValue::Integer(1))));
+        assert!(key(KutValue::Number(f64::NAN)) == key(KutValue::Number(-f64::NAN)));
+        assert!(matches!(map.get(&key(KutValue::Number(f64::from_bits(0x7ff8_0000_0000_0001)))), Some(KutValue::Integer(2))));
+        assert!(map.get(&key(KutValue::Integer(2))).is_none());
+        assert!(key(KutValue::Number(1e300)) == key(KutValue::Number(1e300)));
+        assert!(key(KutValue::Integer(1)) != key(KutValue::Bool(true)));
+        assert!(key(KutValue::Integer(1)) != key(KutValue::String(Rc::new("1".to_owned()))));
+    }
+
+    #[test]
+    fn mutable_values_are_keyed_by_identity() {
+        let inner = Rc::new(KutMap::new());
+        let reference = Rc::new(RefCell::new(KutValue::Integer(1)));
+        let map = map_with([KutValue::Map(Rc::clone(&inner)), KutValue::Reference(Rc::clone(&reference))]);
+        assert!(matches!(map.get(&key(KutValue::Map(inner))), Some(KutValue::Integer(0))));
+        assert!(map.get(&key(KutValue::Map(Rc::new(KutMap::new())))).is_none());
+        assert!(matches!(map.get(&key(KutValue::Reference(Rc::clone(&reference)))), Some(KutValue::Integer(1))));
+        assert!(map.get(&key(KutValue::Reference(Rc::new(RefCell::new(KutValue::Integer(1)))))).is_none());
+        *reference.borrow_mut() = KutValue::Integer(2);
+        assert!(matches!(map.get(&key(KutValue::Reference(reference))), Some(KutValue::Integer(1))));
+    }
+
+    #[test]
+    fn lists_are_keyed_by_their_items() {
+        let list = |items: Vec<KutValue<'static>>| KutValue::List(Rc::new(items));
+        let map = map_with([list(vec![KutValue::Integer(1), KutValue::Nil])]);
+        assert!(matches!(map.get(&key(list(vec![KutValue::Number(1.0), KutValue::Nil]))), Some(KutValue::Integer(0))));
+        assert!(map.get(&key(list(vec![KutValue::Integer(1)]))).is_none());
+    }
+
+    #[test]
+    fn compaction_after_many_removals_keeps_insertion_order() {
+        let mut map = map_with((0..1000).map(KutValue::Integer));
+        for int in (0..1000).filter(|int| int % 10 != 3) {
+            assert!(matches!(map.remove(&key(KutValue::Integer(int))), Some(KutValue::Integer(position)) if position == int));
+        }
+        assert!(map.entries.len() < 1000);
+        assert_eq!(map.len(), 100);
+        let keys: Vec<_> = map.keys().map(|key| key.0.clone()).collect();
+        assert!(keys.iter().zip((0..1000).filter(|int| int % 10 == 3)).all(|(key, int)| matches!(key, KutValue::Integer(key) if *key == int)));
+        for int in (0..1000).filter(|int| int % 10 == 3) {
+            assert!(matches!(map.get(&key(KutValue::Integer(int))), Some(KutValue::Integer(position)) if *position == int));
+        }
+        map.insert(key(KutValue::Integer(-1)), KutValue::Nil);
+        assert!(matches!(map.keys().last(), Some(KutKey(KutValue::Integer(-1)))));
+        assert!(map.remove(&key(KutValue::Integer(0))).is_none());
+    }
+}
@@ -2,15 +2,35 @@ pub mod function;
 pub mod instruction;
 pub mod template;
 pub mod closure;
+pub mod map;
+pub mod compare;
+pub mod number;
+pub mod display;
+pub mod json;
+pub mod convert;
+pub mod native;
+pub mod coroutine;
+pub mod iterator;
+pub mod compiled;
+use crate::list::*;
+use crate::value::native::*;
+use crate::vm::KutVm;
 use std::rc::Rc;
+use std::collections::HashMap;
 // use std::ffi::CStr;
 use std::ffi::c_void;
-use std::cell::RefCell;
+use std::cell::{Cell, OnceCell, RefCell};
+
+/// Advances the external object behind its `data` pointer, returning `None` once it is
+/// exhausted. Like natives it gets the VM it is called from.
+pub type KutExternalNext = for<'template> fn(&'template KutVm<'template>, *mut c_void) -> Option<KutValue<'template>>;
 
 #[derive(Debug)]
 pub struct KutObject {
     // dispatch: unsafe extern "C" fn(*mut KutValue, *const CStr, *mut KutValue, *mut c_void),
     pub data: *mut c_void,
+    pub type_name: &'static str,
+    pub next: Option<KutExternalNext>,
 }
 
 // #[derive(Eq, Hash, PartialEq)]
@@ -18,12 +38,29 @@ pub struct KutObject {
 pub enum KutValue<'template> {
     Nil,
     Undefined,
+    Bool(bool),
+    Integer(i64),
     Number(f64),
     String(Rc<String>),
     List(Rc<Vec<KutValue<'template>>>),
+    Vector(Rc<KutVector<'template>>),
     Func(Rc<KutClosure<'template>>),
     Reference(Rc<RefCell<KutValue<'template>>>),
     External(Rc<KutObject>),
+    Map(Rc<KutMap<'template>>),
+    Native(Rc<KutNativeFunction>),
+    Coroutine(Rc<KutCoroutine<'template>>),
+    Iterator(Rc<KutIterator<'template>>),
+}
+
+/// A value used as a map key, see `src/value/map.rs` for its equality rules.
+#[derive(Debug, Clone)]
+pub struct KutKey<'template>(pub KutValue<'template>);
+
+#[derive(Debug, Clone, Default)]
+pub struct KutMap<'template> {
+    entries: Vec<Option<(KutKey<'template>, KutValue<'template>)>>,
+    index: HashMap<KutKey<'template>, usize>,
 }
 
 #[derive(Debug)]
@@ -35,6 +72,10 @@ pub enum KutInstruction {
     CallMethodS{arg_count: u8, subject: u8},
     RetfMethodR{value: u8},
     RetfMethodS,
+    RetfMultiRg{first: u8, count: u8},
+    RetfMultiSt{count: u8},
+    CallMultiRg{first: u8, count: u8, arg_count: u8, subject: u8},
+    CallMultiSt{count: u8, arg_count: u8, subject: u8},
     PushValue1R{val1: u8},
     PushValue2R{val1: u8, val2: u8},
     PushValue3R{val1: u8, val2: u8, val3: u8},
@@ -48,6 +89,81 @@ pub enum KutInstruction {
     PushCapture{capture: u16},
     PushFuncStk{template: u16},
     PopCaptureS{capture: u16},
+
+    NewEmptyMap{reg: u8},
+    MapGetValue{reg: u8, map: u8, key: u8},
+    MapSetValue{map: u8, key: u8, value: u8},
+    MapDelValue{map: u8, key: u8},
+    MapContains{reg: u8, map: u8, key: u8},
+    MapGetKeysR{reg: u8, map: u8},
+    MapGetCount{reg: u8, map: u8},
+
+    CompareEqlR{reg: u8, lhs: u8, rhs: u8},
+    CompareNeqR{reg: u8, lhs: u8, rhs: u8},
+    CompareLssR{reg: u8, lhs: u8, rhs: u8},
+    CompareLeqR{reg: u8, lhs: u8, rhs: u8},
+    CompareOrdR{reg: u8, lhs: u8, rhs: u8},
+
+    AddNumbersR{reg: u8, lhs: u8, rhs: u8},
+    SubNumbersR{reg: u8, lhs: u8, rhs: u8},
+    MulNumbersR{reg: u8, lhs: u8, rhs: u8},
+    DivNumbersR{reg: u8, lhs: u8, rhs: u8},
+    ModNumbersR{reg: u8, lhs: u8, rhs: u8},
+    NegateValue{reg: u8, value: u8},
+    IntegerCast{reg: u8, value: u8},
+    NumberCastR{reg: u8, value: u8},
+
+    JumpAlwaysT{target: u16},
+    JumpIfTrueR{cond: u8, target: u16},
+    JumpIfFalse{cond: u8, target: u16},
+
+    NewListRegs{reg: u8, first: u8, count: u8},
+    NewListStck{reg: u8, count: u8},
+    ListGetItem{reg: u8, list: u8, index: u8},
+    ListSetItem{list: u8, index: u8, value: u8},
+    ListPushVal{list: u8, value: u8},
+    ListPopValR{reg: u8, list: u8},
+    ListSliceRg{reg: u8, list: u8, start: u8, end: u8},
+    ListLengthR{reg: u8, list: u8},
+
+    NewEmptyVec{reg: u8},
+    VecFromList{reg: u8, list: u8},
+    VecToListRg{reg: u8, vector: u8},
+    VecGetItemR{reg: u8, vector: u8, index: u8},
+    VecSetItemR{reg: u8, vector: u8, index: u8, value: u8},
+    VecPushValR{reg: u8, vector: u8, value: u8},
+    VecPopLastR{reg: u8, vector: u8},
+    VecLengthRg{reg: u8, vector: u8},
+
+    ToStringVal{reg: u8, value: u8},
+    ToReprValue{reg: u8, value: u8},
+
+    JsonEncodeR{reg: u8, value: u8},
+    JsonPrettyR{reg: u8, value: u8},
+    JsonDecodeR{reg: u8, string: u8},
+
+    GetGlobalRg{reg: u8, name: u16},
+    SetGlobalRg{reg: u8, name: u16},
+
+    GetModuleRg{reg: u8, name: u16},
+
+    CoroutineRg{reg: u8, func: u8},
+    CoroResumeR{reg: u8, coroutine: u8, value: u8},
+    CoroYieldRg{reg: u8, value: u8},
+    CoroStatusR{reg: u8, coroutine: u8},
+
+    IterCreateR{reg: u8, value: u8},
+    IterRangeRg{reg: u8, start: u8, end: u8, step: u8},
+    IterNextReg{reg: u8, iter: u8, target: u16},
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum KutArithmetic {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
 }
 
 #[derive(Debug)]
@@ -56,11 +172,25 @@ pub enum KutCaptureInfo {
     Register(u8),
 }
 
+/// The parameters that the arguments of a call are bound to, in the first registers:
+/// the required ones, then the defaulted ones with the literal index of their default,
+/// then the rest list if there is one.
+#[derive(Debug, Clone, Default)]
+pub struct KutParameters {
+    pub required: u8,
+    pub defaults: Vec<u16>,
+    pub rest: bool,
+}
+
 #[derive(Debug)]
 pub struct KutFunctionTemplate {
     pub instructions: Vec<KutInstruction>,
     pub capture_infos: Vec<KutCaptureInfo>,
     pub register_count: u8,
+    pub name: Option<String>,
+    pub module: usize,
+    pub parameters: Option<KutParameters>,
+    pub(crate) compiled: OnceCell<Box<[compiled::KutOp]>>,
 }
 
 #[derive(Debug)]
@@ -70,10 +200,76 @@ pub struct KutClosure<'template> {
 }
 
 #[derive(Debug)]
-pub struct KutFunction<'closure,'template> where 'closure: 'template {
-    pub closure: &'closure KutClosure<'template>,
+pub struct KutFunction<'template> {
+    pub closure: Rc<KutClosure<'template>>,
     pub registers: Vec<KutValue<'template>>,
     pub call_stack: Vec<KutValue<'template>>,
+    pub pc: usize,
+    pub entered: bool,
+    pub callee: Option<Box<KutCall<'template>>>,
+    pub resume_target: Option<KutReturnTarget>,
+    pub multiple_results: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum KutReturnTarget {
+    Register(u8),
+    Stack,
+    /// `count` results, padded with nil or truncated.
+    Registers{first: u8, count: u8},
+    StackValues{count: u8},
+    /// The next item of an iterator, see `IterNextReg`.
+    Iterator{reg: u8, exit: u16},
+    /// Drops the result, such as that of the entry of a module, see `GetModuleRg`.
+    Discard,
+}
+
+/// A value that can be called, see `KutValue::callee`.
+#[derive(Debug, Clone)]
+pub enum KutCallee<'template> {
+    Func(Rc<KutClosure<'template>>),
+    Native(Rc<KutNativeFunction>),
+}
+
+#[derive(Debug)]
+pub struct KutCall<'template> {
+    pub frame: KutFunction<'template>,
+    pub target: KutReturnTarget,
+    pub coroutine: Option<Rc<KutCoroutine<'template>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KutCoroutineStatus {
+    Suspended,
+    Running,
+    Dead,
+}
+
+/// A function with its own frame stack that can be suspended by `CoroYieldRg` and
+/// resumed by `CoroResumeR`, see `src/value/coroutine.rs`.
+#[derive(Debug)]
+pub struct KutCoroutine<'template> {
+    pub closure: Rc<KutClosure<'template>>,
+    frame: RefCell<Option<KutFunction<'template>>>,
+    status: Cell<KutCoroutineStatus>,
+}
+
+#[derive(Debug)]
+enum KutIteratorState<'template> {
+    List(Rc<Vec<KutValue<'template>>>, usize),
+    Vector(Rc<KutVector<'template>>, usize),
+    String(Rc<String>, usize),
+    Map(Rc<KutMap<'template>>, usize),
+    Range{next: i64, end: i64, step: i64},
+    Call(KutCallee<'template>),
+    Coroutine(Rc<KutCoroutine<'template>>),
+    External(Rc<KutObject>),
+}
+
+/// The iteration protocol shared by every iterable value, see `src/value/iterator.rs`.
+#[derive(Debug)]
+pub struct KutIterator<'template> {
+    state: RefCell<KutIteratorState<'template>>,
 }
 
 #[derive(Debug)]
@@ -88,6 +284,35 @@ pub enum KutError {
     OutOfRangeDestinationRegister{register: u8, register_count: usize},
     OutOfRangeSourceRegister{register: u8, register_count: usize},
     OutOfRangeSwapRegister{register: u8, register_count: usize},
+    NonCallableValue{register: u8, value_type: String},
+    Aborted{reason: String},
+    OutOfFuel{needed: u64, remaining: u64},
+    OutOfMemory{requested: usize, used: usize, limit: usize},
+    Interrupted,
+    TypeMismatch{register: u8, expected: String, value_type: String},
+    InvalidOperand{operation: String, value_type: String},
+    IntegerOverflow{operation: String},
+    DivisionByZero,
+    NonIntegralNumber{value: f64},
+    OutOfRangeJump{target: u16, instruction_count: usize},
+    OutOfRangeIndex{index: i64, length: usize},
+    InvalidJson{position: usize, message: String},
+    CyclicValue{operation: String},
+    InvalidConversion{expected: String, value_type: String},
+    WrongArgumentCount{function: String, min: usize, max: Option<usize>, given: usize},
+    InvalidArgument{function: String, message: String},
+    UndefinedGlobal{name: String},
+    UndefinedFunction{name: String},
+    ModuleNotFound{name: String},
+    InvalidModule{name: String, message: String},
+    CircularImport{chain: Vec<String>},
+    UnlinkedModule{name: String},
+    PermissionDenied{capability: String, target: String},
+    Io{operation: String, message: String},
+    Yielded,
+    Pending,
+    InvalidResume{status: String},
+    CallDepthExceeded{depth: usize},
 }
 
 pub type KutReturnType<'a> = Result<Option<KutValue<'a>>, KutError>;
@@ -97,36 +322,59 @@ impl<'template> Clone for KutValue<'template> {
         match self {
             Self::Nil => Self::Nil,
             Self::Undefined => Self::Undefined,
+            Self::Bool(b) => Self::Bool(*b),
+            Self::Integer(int) => Self::Integer(*int),
             Self::Number(num) => Self::Number(*num),
             Self::String(string) => Self::String(Rc::clone(string)),
             Self::List(list) => Self::List(Rc::clone(list)),
+            Self::Vector(vector) => Self::Vector(Rc::clone(vector)),
             Self::Func(func) => Self::Func(Rc::clone(func)),
             Self::Reference(r) => Self::Reference(Rc::clone(r)),
             Self::External(ext) => Self::External(Rc::clone(ext)),
+            Self::Map(map) => Self::Map(Rc::clone(map)),
+            Self::Native(native) => Self::Native(Rc::clone(native)),
+            Self::Coroutine(coroutine) => Self::Coroutine(Rc::clone(coroutine)),
+            Self::Iterator(iterator) => Self::Iterator(Rc::clone(iterator)),
         }
     }
 }
 
 impl<'template> KutValue<'template> {
-    fn get_type_string(&self) -> String {
+    pub(crate) fn get_type_string(&self) -> String {
         match self {
             KutValue::Nil => "Nil",
             KutValue::Undefined => "Undefined",
+            KutValue::Bool(_) => "Bool",
+            KutValue::Integer(_) => "Integer",
             KutValue::Number(_) => "Number",
             KutValue::String(_) => "String",
             KutValue::List(_) => "List",
+            KutValue::Vector(_) => "Vector",
             KutValue::Func(_) => "Func",
             KutValue::Reference(_) => "Reference",
             KutValue::External(_) => "External",
+            KutValue::Map(_) => "Map",
+            KutValue::Native(_) => "Native",
+            KutValue::Coroutine(_) => "Coroutine",
+            KutValue::Iterator(_) => "Iterator",
         }.to_owned()
     }
+
+    /// The function to run when the value is called, for a `Func` or `Native`.
+    pub fn callee(&self) -> Option<KutCallee<'template>> {
+        match self {
+            KutValue::Func(closure) => Some(KutCallee::Func(Rc::clone(closure))),
+            KutValue::Native(native) => Some(KutCallee::Native(Rc::clone(native))),
+            _ => None,
+        }
+    }
 }
 
 impl From<KutError> for String {
     fn from(value: KutError) -> Self {
         match value {
             KutError::StackUnderflow => {
-                format!("KutError::StackUnderflow: try to pop from empty call stack")
+                "KutError::StackUnderflow: try to pop from empty call stack".to_owned()
             },
             KutError::CaptureEmptyEnvironment { needed_captures } => {
                 format!("KutError::CaptureEmptyEnvironment: {needed_captures} captures are needed")
@@ -154,7 +402,99 @@ impl From<KutError> for String {
             },
             KutError::OutOfRangeSwapRegister { register, register_count } => {
                 format!("KutError::OutOfRangeSwapRegister: try to get and set register {register} when there are {register_count} registers")
-            }
+            },
+            KutError::NonCallableValue { register, value_type } => {
+                format!("KutError::NonCallableValue: try to call register {register} when its type is {value_type} instead of Func")
+            },
+            KutError::Aborted { reason } => {
+                format!("KutError::Aborted: execution aborted by {reason}")
+            },
+            KutError::OutOfFuel { needed, remaining } => {
+                format!("KutError::OutOfFuel: next instruction needs {needed} fuel when there is {remaining} fuel left")
+            },
+            KutError::OutOfMemory { requested, used, limit } => {
+                format!("KutError::OutOfMemory: try to allocate {requested} bytes when {used} of {limit} bytes are used")
+            },
+            KutError::Interrupted => {
+                "KutError::Interrupted: execution stopped by an interrupt handle".to_owned()
+            },
+            KutError::TypeMismatch { register, expected, value_type } => {
+                format!("KutError::TypeMismatch: try to use register {register} as {expected} when its type is {value_type}")
+            },
+            KutError::InvalidOperand { operation, value_type } => {
+                format!("KutError::InvalidOperand: try to use {value_type} as operand of {operation}")
+            },
+            KutError::IntegerOverflow { operation } => {
+                format!("KutError::IntegerOverflow: integer {operation} overflowed")
+            },
+            KutError::DivisionByZero => {
+                "KutError::DivisionByZero: try to divide an integer by zero".to_owned()
+            },
+            KutError::NonIntegralNumber { value } => {
+                format!("KutError::NonIntegralNumber: try to convert {value} to Integer when it is not an exact integer")
+            },
+            KutError::OutOfRangeJump { target, instruction_count } => {
+                format!("KutError::OutOfRangeJump: try to jump to {target} when there are {instruction_count} instructions")
+            },
+            KutError::OutOfRangeIndex { index, length } => {
+                format!("KutError::OutOfRangeIndex: try to use index {index} when there are {length} items")
+            },
+            KutError::InvalidJson { position, message } => {
+                format!("KutError::InvalidJson: {message} at byte {position}")
+            },
+            KutError::CyclicValue { operation } => {
+                format!("KutError::CyclicValue: try to use a value that contains itself in {operation}")
+            },
+            KutError::InvalidConversion { expected, value_type } => {
+                format!("KutError::InvalidConversion: try to convert {value_type} to {expected}")
+            },
+            KutError::WrongArgumentCount { function, min, max, given } => {
+                let expected = match max {
+                    Some(max) if max == min => min.to_string(),
+                    Some(max) => format!("{min} to {max}"),
+                    None => format!("at least {min}"),
+                };
+                format!("KutError::WrongArgumentCount: try to call {function} with {given} arguments when it takes {expected}")
+            },
+            KutError::InvalidArgument { function, message } => {
+                format!("KutError::InvalidArgument: try to call {function} when {message}")
+            },
+            KutError::UndefinedGlobal { name } => {
+                format!("KutError::UndefinedGlobal: try to read global {name} before it is defined")
+            },
+            KutError::UndefinedFunction { name } => {
+                format!("KutError::UndefinedFunction: try to call {name} when there is no global or template with that name")
+            },
+            KutError::ModuleNotFound { name } => {
+                format!("KutError::ModuleNotFound: try to load module {name} when the loader cannot find it")
+            },
+            KutError::InvalidModule { name, message } => {
+                format!("KutError::InvalidModule: module {name} is invalid: {message}")
+            },
+            KutError::CircularImport { chain } => {
+                format!("KutError::CircularImport: modules import each other in a cycle {}", chain.join(" -> "))
+            },
+            KutError::UnlinkedModule { name } => {
+                format!("KutError::UnlinkedModule: try to import module {name} when it is not linked into the VM")
+            },
+            KutError::PermissionDenied { capability, target } => {
+                format!("KutError::PermissionDenied: try to use capability {capability} on {target} when it is not granted")
+            },
+            KutError::Io { operation, message } => {
+                format!("KutError::Io: {operation} failed on {message}")
+            },
+            KutError::Yielded => {
+                "KutError::Yielded: try to yield outside of a coroutine".to_owned()
+            },
+            KutError::Pending => {
+                "KutError::Pending: a native function waits for a future, run the function with call_async".to_owned()
+            },
+            KutError::InvalidResume { status } => {
+                format!("KutError::InvalidResume: try to resume a coroutine or call that is {status}")
+            },
+            KutError::CallDepthExceeded { depth } => {
+                format!("KutError::CallDepthExceeded: try to call a function when {depth} frames are running")
+            },
         }
     }
 }
@@ -0,0 +1,120 @@
+use crate::value::*;
+use crate::value::convert::*;
+use crate::vm::*;
+use std::fmt;
+
+/// Signature of a native function: the VM it is called from and the arguments
+/// popped from the caller's stack, in push order. It is generic over the VM
+/// lifetime so that `KutValue` stays covariant in it.
+pub type KutNativeFn = dyn for<'template> Fn(&'template KutVm<'template>, Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError>;
+
+/// Plain function form of `KutNativeFn`, for natives that need no state.
+pub type KutNativeFnPointer = for<'template> fn(&'template KutVm<'template>, Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError>;
+
+pub struct KutNativeFunction {
+    pub name: String,
+    function: Box<KutNativeFn>,
+}
+
+/// Rust closures that can be wrapped with `KutNativeFunction::typed`, implemented
+/// for `Fn(A, B, ...) -> Result<R, KutError>` of up to six `FromKut` parameters.
+pub trait KutTypedNative<Args> {
+    fn call_typed<'template>(&self, name: &str, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError>;
+}
+
+impl KutNativeFunction {
+    pub fn new(name: &str, function: impl for<'template> Fn(&'template KutVm<'template>, Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> + 'static) -> KutNativeFunction {
+        KutNativeFunction { name: name.to_owned(), function: Box::new(function) }
+    }
+
+    /// Wraps a Rust closure whose parameters are converted with `FromKut` and whose
+    /// result is converted with `IntoKut`. Missing trailing arguments are passed as
+    /// `Nil`, so `Option` parameters at the end are optional. Calls with fewer
+    /// arguments than the other parameters fail with `KutError::WrongArgumentCount`.
+    pub fn typed<Args>(name: &str, function: impl KutTypedNative<Args> + 'static) -> KutNativeFunction {
+        let owned_name = name.to_owned();
+        KutNativeFunction::new(name, move |_, args| function.call_typed(&owned_name, args))
+    }
+
+    pub fn call<'template>(&self, vm: &'template KutVm<'template>, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+        (self.function)(vm, args)
+    }
+}
+
+impl fmt::Debug for KutNativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KutNativeFunction").field("name", &self.name).finish_non_exhaustive()
+    }
+}
+
+macro_rules! impl_typed_native {
+    ($count:literal $(, $arg:ident)*) => {
+        impl<Function, Result_, $($arg),*> KutTypedNative<($($arg,)*)> for Function
+        where
+            Function: Fn($($arg),*) -> Result<Result_, KutError>,
+            Result_: for<'template> IntoKut<'template>,
+            $($arg: for<'template> FromKut<'template>,)*
+        {
+            #[allow(unused_variables, unused_mut)]
+            fn call_typed<'template>(&self, name: &str, args: Vec<KutValue<'template>>) -> Result<KutValue<'template>, KutError> {
+                let optional: [bool; $count] = [$(<$arg as FromKut<'static>>::OPTIONAL),*];
+                let min = optional.iter().rposition(|optional| !optional).map_or(0, |last| last + 1);
+                if args.len() < min || args.len() > $count {
+                    return Err(KutError::WrongArgumentCount { function: name.to_owned(), min, max: Some($count), given: args.len() });
+                }
+                let mut args = args.into_iter();
+                Ok(self($($arg::from_kut(args.next().unwrap_or(KutValue::Nil))?),*)?.into_kut())
+            }
+        }
+    };
+}
+
+impl_typed_native!(0);
+impl_typed_native!(1, A);
+impl_typed_native!(2, A, B);
+impl_typed_native!(3, A, B, C);
+impl_typed_native!(4, A, B, C, D);
+impl_typed_native!(5, A, B, C, D, E);
+impl_typed_native!(6, A, B, C, D, E, F);
+
+#[cfg(test)]
+mod tests {
+    use crate::value::*;
+    use crate::vm::*;
+
+    #[test]
+    fn typed_natives_accept_up_to_their_parameter_count() {
+        let vm = &KutVm::new(vec![], vec![]);
+        let add = KutNativeFunction::typed("add", |a: i64, b: Option<i64>| Ok(a + b.unwrap_or(1)));
+        assert!(matches!(add.call(vm, vec![KutValue::Integer(2)]), Ok(KutValue::Integer(3))));
+        let error = add.call(vm, vec![KutValue::Integer(2); 3]).unwrap_err();
+        assert!(matches!(error, KutError::WrongArgumentCount { min: 1, max: Some(2), given: 3, .. }), "{error:?}");
+    }
+
+    #[test]
+    fn typed_natives_require_the_parameters_before_the_trailing_options() {
+        let vm = &KutVm::new(vec![], vec![]);
+        let clamp = KutNativeFunction::typed("clamp", |x: f64, low: Option<f64>, high: f64, scale: Option<f64>| Ok(x.max(low.unwrap_or(0.0)).min(high) * scale.unwrap_or(1.0)));
+        let error = clamp.call(vm, vec![KutValue::Number(5.0)]).unwrap_err();
+        assert!(matches!(error, KutError::WrongArgumentCount { min: 3, max: Some(4), given: 1, .. }), "{error:?}");
+        assert!(matches!(clamp.call(vm, vec![KutValue::Number(5.0), KutValue::Nil, KutValue::Number(2.0)]), Ok(KutValue::Number(2.0))));
+        let error = clamp.call(vm, vec![KutValue::Nil, KutValue::Nil, KutValue::Number(2.0)]).unwrap_err();
+        assert!(matches!(error, KutError::InvalidConversion { .. }), "{error:?}");
+        let optional = KutNativeFunction::typed("optional", |a: Option<i64>| Ok(a.unwrap_or(7)));
+        assert!(matches!(optional.call(vm, vec![]), Ok(KutValue::Integer(7))));
+        let none = KutNativeFunction::typed("none", || Ok(()));
+        let error = none.call(vm, vec![KutValue::Nil]).unwrap_err();
+        assert!(matches!(error, KutError::WrongArgumentCount { min: 0, max: Some(0), given: 1, .. }), "{error:?}");
+    }
+
+    #[test]
+    fn fractional_numbers_fail_to_convert_to_integers() {
+        let vm = &KutVm::new(vec![], vec![]);
+        let double = KutNativeFunction::typed("double", |a: i64| Ok(a * 2));
+        assert!(matches!(double.call(vm, vec![KutValue::Number(3.0)]), Ok(KutValue::Integer(6))));
+        let error = double.call(vm, vec![KutValue::Number(1.5)]).unwrap_err();
+        assert!(matches!(&error, KutError::InvalidConversion { expected, value_type } if expected == "i64" && value_type == "Number 1.5"), "{error:?}");
+        let error = double.call(vm, vec![KutValue::Number(1e300)]).unwrap_err();
+        assert!(matches!(error, KutError::InvalidConversion { .. }), "{error:?}");
+    }
+}
@@ -0,0 +1,181 @@
+use crate::value::*;
+use std::cmp::Ordering;
+
+const TWO_POW_63: f64 = 9_223_372_036_854_775_808.0;
+
+impl KutArithmetic {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KutArithmetic::Add => "addition",
+            KutArithmetic::Sub => "subtraction",
+            KutArithmetic::Mul => "multiplication",
+            KutArithmetic::Div => "division",
+            KutArithmetic::Rem => "remainder",
+        }
+    }
+
+    pub(crate) fn integer(&self, lhs: i64, rhs: i64) -> Result<i64, KutError> {
+        let result = match self {
+            KutArithmetic::Add => lhs.checked_add(rhs),
+            KutArithmetic::Sub => lhs.checked_sub(rhs),
+            KutArithmetic::Mul => lhs.checked_mul(rhs),
+            KutArithmetic::Div | KutArithmetic::Rem if rhs == 0 => return Err(KutError::DivisionByZero),
+            KutArithmetic::Div => lhs.checked_div(rhs),
+            KutArithmetic::Rem => lhs.checked_rem(rhs),
+        };
+        result.ok_or_else(|| KutError::IntegerOverflow { operation: self.name().to_owned() })
+    }
+
+    pub(crate) fn number(&self, lhs: f64, rhs: f64) -> f64 {
+        match self {
+            KutArithmetic::Add => lhs + rhs,
+            KutArithmetic::Sub => lhs - rhs,
+            KutArithmetic::Mul => lhs * rhs,
+            KutArithmetic::Div => lhs / rhs,
+            KutArithmetic::Rem => lhs % rhs,
+        }
+    }
+}
+
+/// Numeric rules: two integers stay integers, with overflow and division by zero
+/// reported as errors and division truncating towards zero. As soon as one side is
+/// a `Number` the integer is promoted and IEEE arithmetic applies. `Bool` is not a
+/// number. Only `Nil`, `Undefined` and `Bool(false)` are falsy.
+impl<'template> KutValue<'template> {
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            KutValue::Nil | KutValue::Undefined | KutValue::Bool(false) => false,
+            KutValue::Reference(r) => r.borrow().is_truthy(),
+            _ => true,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            KutValue::Number(num) => Some(*num),
+            KutValue::Integer(int) => Some(*int as f64),
+            _ => None,
+        }
+    }
+
+    /// Converts an `Integer`, or a `Number` with an exact integer value, to `i64`.
+    pub fn as_i64(&self) -> Result<i64, KutError> {
+        match self {
+            KutValue::Integer(int) => Ok(*int),
+            KutValue::Number(num) if num.fract() == 0.0 && *num >= -TWO_POW_63 && *num < TWO_POW_63 => Ok(*num as i64),
+            KutValue::Number(num) => Err(KutError::NonIntegralNumber { value: *num }),
+            other => Err(KutError::InvalidOperand { operation: "integer conversion".to_owned(), value_type: other.get_type_string() }),
+        }
+    }
+
+    pub fn arithmetic(&self, operation: KutArithmetic, rhs: &KutValue<'template>) -> Result<KutValue<'template>, KutError> {
+        match (self, rhs) {
+            (KutValue::Integer(lhs), KutValue::Integer(rhs)) => Ok(KutValue::Integer(operation.integer(*lhs, *rhs)?)),
+            _ => match (self.as_f64(), rhs.as_f64()) {
+                (Some(lhs), Some(rhs)) => Ok(KutValue::Number(operation.number(lhs, rhs))),
+                (None, _) => Err(KutError::InvalidOperand { operation: operation.name().to_owned(), value_type: self.get_type_string() }),
+                (_, None) => Err(KutError::InvalidOperand { operation: operation.name().to_owned(), value_type: rhs.get_type_string() }),
+            },
+        }
+    }
+
+    pub fn negate(&self) -> Result<KutValue<'template>, KutError> {
+        match self {
+            KutValue::Integer(int) => int.checked_neg().map(KutValue::Integer).ok_or_else(|| KutError::IntegerOverflow { operation: "negation".to_owned() }),
+            KutValue::Number(num) => Ok(KutValue::Number(-num)),
+            other => Err(KutError::InvalidOperand { operation: "negation".to_owned(), value_type: other.get_type_string() }),
+        }
+    }
+
+    /// Compares an integer with a number exactly, without rounding the integer to `f64`.
+    pub(crate) fn integer_number_cmp(int: i64, num: f64) -> Option<Ordering> {
+        if num.is_nan() {
+            None
+        } else if num >= TWO_POW_63 {
+            Some(Ordering::Less)
+        } else if num < -TWO_POW_63 {
+            Some(Ordering::Greater)
+        } else {
+            let whole = num.trunc();
+            Some(int.cmp(&(whole as i64)).then_with(|| 0.0.partial_cmp(&(num - whole)).unwrap_or(Ordering::Equal)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn integers(operation: KutArithmetic, lhs: i64, rhs: i64) -> Result<KutValue<'static>, KutError> {
+        KutValue::Integer(lhs).arithmetic(operation, &KutValue::Integer(rhs))
+    }
+
+    #[test]
+    fn integer_arithmetic_is_checked() {
+        assert!(matches!(integers(KutArithmetic::Add, i64::MAX, 1), Err(KutError::IntegerOverflow { .. })));
+        assert!(matches!(integers(KutArithmetic::Sub, i64::MIN, 1), Err(KutError::IntegerOverflow { .. })));
+        assert!(matches!(integers(KutArithmetic::Mul, i64::MAX, 2), Err(KutError::IntegerOverflow { .. })));
+        assert!(matches!(integers(KutArithmetic::Div, i64::MIN, -1), Err(KutError::IntegerOverflow { .. })));
+        assert!(matches!(integers(KutArithmetic::Rem, i64::MIN, -1), Err(KutError::IntegerOverflow { .. })));
+        assert!(matches!(KutValue::Integer(i64::MIN).negate(), Err(KutError::IntegerOverflow { .. })));
+        assert!(matches!(integers(KutArithmetic::Add, i64::MAX - 1, 1), Ok(KutValue::Integer(i64::MAX))));
+        assert!(matches!(integers(KutArithmetic::Div, -7, 2), Ok(KutValue::Integer(-3))));
+        assert!(matches!(integers(KutArithmetic::Rem, -7, 2), Ok(KutValue::Integer(-1))));
+    }
+
+    #[test]
+    fn integer_division_by_zero_fails() {
+        assert!(matches!(integers(KutArithmetic::Div, 1, 0), Err(KutError::DivisionByZero)));
+        assert!(matches!(integers(KutArithmetic::Rem, 1, 0), Err(KutError::DivisionByZero)));
+        assert!(matches!(integers(KutArithmetic::Div, 0, 0), Err(KutError::DivisionByZero)));
+        let quotient = KutValue::Integer(1).arithmetic(KutArithmetic::Div, &KutValue::Number(0.0));
+        assert!(matches!(quotient, Ok(KutValue::Number(num)) if num == f64::INFINITY));
+        let remainder = KutValue::Number(1.0).arithmetic(KutArithmetic::Rem, &KutValue::Integer(0));
+        assert!(matches!(remainder, Ok(KutValue::Number(num)) if num.is_nan()));
+    }
+
+    #[test]
+    fn mixing_with_a_number_promotes_the_integer() {
+        let sum = KutValue::Integer(1).arithmetic(KutArithmetic::Add, &KutValue::Number(0.5));
+        assert!(matches!(sum, Ok(KutValue::Number(num)) if num == 1.5));
+        let product = KutValue::Number(2.0).arithmetic(KutArithmetic::Mul, &KutValue::Integer(3));
+        assert!(matches!(product, Ok(KutValue::Number(num)) if num == 6.0));
+        let sum = KutValue::Integer(i64::MAX).arithmetic(KutArithmetic::Add, &KutValue::Number(1.0));
+        assert!(matches!(sum, Ok(KutValue::Number(num)) if num == TWO_POW_63));
+        let quotient = KutValue::Integer(7).arithmetic(KutArithmetic::Div, &KutValue::Number(2.0));
+        assert!(matches!(quotient, Ok(KutValue::Number(num)) if num == 3.5));
+        let error = KutValue::Integer(1).arithmetic(KutArithmetic::Add, &KutValue::Bool(true)).unwrap_err();
+        assert!(matches!(&error, KutError::InvalidOperand { operation, .. } if operation == "addition"), "{error:?}");
+    }
+
+    #[test]
+    fn as_i64_accepts_exact_integers_below_two_pow_63() {
+        assert!(matches!(KutValue::Number(-TWO_POW_63).as_i64(), Ok(i64::MIN)));
+        assert!(matches!(KutValue::Number(TWO_POW_63).as_i64(), Err(KutError::NonIntegralNumber { .. })));
+        assert!(matches!(KutValue::Number(-TWO_POW_63 * 2.0).as_i64(), Err(KutError::NonIntegralNumber { .. })));
+        assert!(matches!(KutValue::Number(TWO_POW_63 - 1024.0).as_i64(), Ok(9_223_372_036_854_774_784)));
+        assert!(matches!(KutValue::Number(-0.0).as_i64(), Ok(0)));
+        assert!(matches!(KutValue::Number(0.5).as_i64(), Err(KutError::NonIntegralNumber { .. })));
+        assert!(matches!(KutValue::Number(f64::NAN).as_i64(), Err(KutError::NonIntegralNumber { .. })));
+        assert!(matches!(KutValue::Number(f64::INFINITY).as_i64(), Err(KutError::NonIntegralNumber { .. })));
+        assert!(matches!(KutValue::Integer(i64::MAX).as_i64(), Ok(i64::MAX)));
+        assert!(matches!(KutValue::Nil.as_i64(), Err(KutError::InvalidOperand { .. })));
+    }
+
+    #[test]
+    fn integers_compare_exactly_with_numbers() {
+        let two_pow_53 = 1_i64 << 53;
+        let float = KutValue::Number(two_pow_53 as f64);
+        assert!(KutValue::Integer(two_pow_53) == float);
+        assert_eq!(KutValue::Integer(two_pow_53 + 1).partial_cmp(&float), Some(Ordering::Greater));
+        assert!(KutValue::Integer(two_pow_53 + 1) != float);
+        assert_eq!(float.partial_cmp(&KutValue::Integer(two_pow_53 + 1)), Some(Ordering::Less));
+        assert_eq!(KutValue::Integer(i64::MAX).partial_cmp(&KutValue::Number(TWO_POW_63)), Some(Ordering::Less));
+        assert_eq!(KutValue::Integer(i64::MIN).partial_cmp(&KutValue::Number(-TWO_POW_63)), Some(Ordering::Equal));
+        assert_eq!(KutValue::Integer(-2).partial_cmp(&KutValue::Number(-2.5)), Some(Ordering::Greater));
+        assert_eq!(KutValue::Integer(-3).partial_cmp(&KutValue::Number(-2.5)), Some(Ordering::Less));
+        assert_eq!(KutValue::Integer(2).partial_cmp(&KutValue::Number(2.5)), Some(Ordering::Less));
+        assert_eq!(KutValue::Integer(0).partial_cmp(&KutValue::Number(f64::NAN)), None);
+        assert_eq!(KutValue::Integer(i64::MIN).partial_cmp(&KutValue::Number(f64::NEG_INFINITY)), Some(Ordering::Greater));
+    }
+}
@@ -1,18 +1,54 @@
 use crate::value::*;
 
+impl KutParameters {
+    pub fn new(required: u8) -> KutParameters {
+        KutParameters { required, defaults: vec![], rest: false }
+    }
+
+    pub fn with_defaults(mut self, defaults: Vec<u16>) -> KutParameters {
+        self.defaults = defaults;
+        self
+    }
+
+    pub fn with_rest(mut self) -> KutParameters {
+        self.rest = true;
+        self
+    }
+
+    /// The number of registers the parameters are bound to.
+    pub fn register_count(&self) -> usize {
+        self.required as usize + self.defaults.len() + self.rest as usize
+    }
+}
+
 impl KutFunctionTemplate {
     pub fn new(instructions: Vec<KutInstruction>, capture_infos: Vec<KutCaptureInfo>, register_count: u8) -> KutFunctionTemplate {
-        KutFunctionTemplate { instructions, capture_infos, register_count }
+        KutFunctionTemplate { instructions, capture_infos, register_count, name: None, module: 0, parameters: None, compiled: OnceCell::new() }
+    }
+
+    pub fn with_name(mut self, name: &str) -> KutFunctionTemplate {
+        self.name = Some(name.to_owned());
+        self
     }
-    pub fn capture<'closure,'template>(&'template self, _env: Option<&mut KutFunction<'closure,'template>>) -> Result<KutClosure<'template>, KutError> {
+
+    /// Declares the parameters of the template. Without them, calls bind any number of
+    /// arguments to the first registers.
+    pub fn with_parameters(mut self, parameters: KutParameters) -> KutFunctionTemplate {
+        self.parameters = Some(parameters);
+        self
+    }
+
+    pub fn capture<'template>(&'template self, _env: Option<&mut KutFunction<'template>>) -> Result<KutClosure<'template>, KutError> {
         if let Some(env) = _env  {
             let mut captures: Vec<KutValue> = Vec::with_capacity(self.capture_infos.len());
             for capture_info in self.capture_infos.iter() {
                 match capture_info {
                     KutCaptureInfo::Register(reg) => {
                         if let Some(val) = env.registers.get_mut(*reg as usize) {
-                            let inner_val = (*val).clone();
-                            *val = KutValue::Reference(Rc::new(RefCell::new(inner_val)));
+                            if !matches!(val, KutValue::Reference(_)) {
+                                let inner_val = (*val).clone();
+                                *val = KutValue::Reference(Rc::new(RefCell::new(inner_val)));
+                            }
                             captures.push((*val).clone());
                         } else {
                             return Err(KutError::OutOfRangeDestinationRegister { register: *reg, register_count: env.registers.len() });
@@ -27,8 +63,8 @@ impl KutFunctionTemplate {
                     }
                 }
             }
-            Ok(KutClosure { template: self, captures: captures })
-        } else if self.capture_infos.len() == 0 {
+            Ok(KutClosure { template: self, captures })
+        } else if self.capture_infos.is_empty() {
             Ok(KutClosure { template: self, captures: vec![] })
         } else {
             Err(KutError::CaptureEmptyEnvironment { needed_captures: self.capture_infos.len() })
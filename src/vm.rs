@@ -1,13 +1,537 @@
 use crate::value::*;
+use crate::hook::*;
+use crate::memory::*;
+use crate::global::*;
+use crate::module::*;
+use crate::execution::*;
+use crate::value::convert::*;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Fuel charged for each kind of instruction.
+#[derive(Debug, Clone, Copy)]
+pub struct KutFuelCosts {
+    pub instruction: u64,
+    pub call: u64,
+    pub capture: u64,
+}
+
+impl Default for KutFuelCosts {
+    fn default() -> Self {
+        KutFuelCosts { instruction: 1, call: 10, capture: 5 }
+    }
+}
+
+/// How `KutFunction::run` executes instructions. `Reference` interprets each
+/// `KutInstruction` as it is, `Threaded` pre-decodes every template on its first run
+/// into handlers with their operands inline, which skips most of the operand checks
+/// and the dispatch on the instruction. Both behave the same, except that `Threaded`
+/// falls back to `Reference` while a hook is installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KutEngine {
+    #[default]
+    Reference,
+    Threaded,
+}
+
+/// Capabilities of the `io` module, all denied by default. A path is granted when
+/// it resolves, following symbolic links, to one of the allowed paths or to a path
+/// under one of them.
+#[derive(Debug, Clone, Default)]
+pub struct KutCapabilities {
+    pub stdout: bool,
+    pub read_paths: Vec<PathBuf>,
+    pub write_paths: Vec<PathBuf>,
+}
+
+impl KutCapabilities {
+    pub fn check_stdout(&self) -> Result<(), KutError> {
+        if self.stdout {
+            Ok(())
+        } else {
+            Err(KutError::PermissionDenied { capability: "stdout".to_owned(), target: "the console".to_owned() })
+        }
+    }
+
+    /// Returns the resolved form of `path` if it may be read.
+    pub fn check_read(&self, path: &str) -> Result<PathBuf, KutError> {
+        KutCapabilities::check_path("read", &self.read_paths, path)
+    }
+
+    /// Returns the resolved form of `path` if it may be written.
+    pub fn check_write(&self, path: &str) -> Result<PathBuf, KutError> {
+        KutCapabilities::check_path("write", &self.write_paths, path)
+    }
+
+    fn check_path(capability: &str, allowed: &[PathBuf], path: &str) -> Result<PathBuf, KutError> {
+        let denied = || KutError::PermissionDenied { capability: capability.to_owned(), target: path.to_owned() };
+        let resolved = KutCapabilities::resolve(Path::new(path)).ok_or_else(denied)?;
+        let granted = allowed.iter().filter_map(|allowed| KutCapabilities::resolve(allowed)).any(|allowed| resolved.starts_with(allowed));
+        if granted {
+            Ok(resolved)
+        } else {
+            Err(denied())
+        }
+    }
+
+    /// Canonicalizes the longest existing ancestor of `path` and appends the rest,
+    /// which must not step out with `..`.
+    fn resolve(path: &Path) -> Option<PathBuf> {
+        let mut existing = path;
+        let mut rest = vec![];
+        loop {
+            if let Ok(canonical) = existing.canonicalize() {
+                return rest.iter().rev().try_fold(canonical, |resolved, component: &Component| match component {
+                    Component::Normal(name) => Some(resolved.join(name)),
+                    Component::CurDir => Some(resolved),
+                    _ => None,
+                });
+            }
+            rest.extend(existing.components().next_back());
+            existing = match existing.parent() {
+                Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+                Some(parent) => parent,
+                None => return None,
+            };
+        }
+    }
+}
+
+/// Cloneable handle that stops a running `KutVm` from any thread. Triggering it
+/// only stores to an atomic flag, so it is also safe to call from a signal handler.
+#[derive(Debug, Clone)]
+pub struct KutInterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl KutInterruptHandle {
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+}
 
 #[derive(Debug)]
 pub struct KutVm<'template> {
     pub literals: Vec<KutValue<'template>>,
     pub templates: Vec<KutFunctionTemplate>,
+    pub(crate) hook: RefCell<Option<Box<dyn KutHook>>>,
+    has_hook: Cell<bool>,
+    pub fuel_costs: KutFuelCosts,
+    pub engine: KutEngine,
+    pub memory: KutMemory<'template>,
+    pub capabilities: KutCapabilities,
+    pub globals: RefCell<KutGlobals<'template>>,
+    literal_slots: Vec<Cell<Option<usize>>>,
+    modules: Vec<KutLinkedModule<'template>>,
+    module_indices: HashMap<String, usize>,
+    loader: Box<dyn KutModuleLoader>,
+    yielded: RefCell<Option<KutValue<'template>>>,
+    pending: RefCell<Option<KutPending>>,
+    fuel: Cell<Option<u64>>,
+    fuel_consumed: Cell<u64>,
+    call_depth: Cell<usize>,
+    max_call_depth: Cell<usize>,
+    interrupt: Arc<AtomicBool>,
 }
 
 impl<'template> KutVm<'template> {
+    /// Creates a VM whose literals and templates form the module `main`, with the
+    /// native `math`, `string` and `io` modules registered. `io` is unusable until
+    /// `capabilities` grants it something.
     pub fn new(literals: Vec<KutValue<'template>>, templates: Vec<KutFunctionTemplate>) -> KutVm<'template> {
-        KutVm { literals, templates }
+        let mut vm = KutVm::bare(literals, templates);
+        for (name, exports) in [("math", crate::library::math::exports()), ("string", crate::library::string::exports()), ("io", crate::library::io::exports())] {
+            vm.modules.push(KutLinkedModule::native(name, exports));
+            vm.module_indices.insert(name.to_owned(), vm.modules.len() - 1);
+        }
+        vm
+    }
+
+    /// Creates a VM like `new` but without any native modules.
+    pub fn bare(literals: Vec<KutValue<'template>>, templates: Vec<KutFunctionTemplate>) -> KutVm<'template> {
+        let main = KutLinkedModule::new("main", 0..literals.len(), 0..templates.len(), vec![], None, OnceCell::new());
+        KutVm {
+            literal_slots: vec![Cell::new(None); literals.len()],
+            modules: vec![main],
+            module_indices: HashMap::from([("main".to_owned(), 0)]),
+            loader: Box::new(KutFileLoader::default()),
+            literals,
+            templates,
+            hook: RefCell::new(None),
+            has_hook: Cell::new(false),
+            fuel_costs: KutFuelCosts::default(),
+            engine: KutEngine::default(),
+            memory: KutMemory::new(),
+            capabilities: KutCapabilities::default(),
+            globals: RefCell::new(KutGlobals::new()),
+            yielded: RefCell::new(None),
+            pending: RefCell::new(None),
+            fuel: Cell::new(None),
+            fuel_consumed: Cell::new(0),
+            call_depth: Cell::new(0),
+            max_call_depth: Cell::new(200),
+            interrupt: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Installs `hook`, returning the previously installed one. Must not be
+    /// called from inside a hook.
+    pub fn set_hook(&self, hook: Box<dyn KutHook>) -> Option<Box<dyn KutHook>> {
+        self.has_hook.set(true);
+        self.hook.replace(Some(hook))
+    }
+
+    pub fn take_hook(&self) -> Option<Box<dyn KutHook>> {
+        self.has_hook.set(false);
+        self.hook.take()
+    }
+
+    /// Whether a hook is installed. Cached apart from `hook`, so that the dispatch
+    /// loop only borrows the hook when there is one to call.
+    pub fn has_hook(&self) -> bool {
+        self.has_hook.get()
+    }
+
+    pub fn interrupt_handle(&self) -> KutInterruptHandle {
+        KutInterruptHandle { flag: Arc::clone(&self.interrupt) }
+    }
+
+    /// Holds the value of a yield until the resumer of the coroutine takes it.
+    pub(crate) fn set_yielded(&self, value: KutValue<'template>) {
+        self.yielded.replace(Some(value));
+    }
+
+    pub(crate) fn take_yielded(&self) -> KutValue<'template> {
+        self.yielded.take().unwrap_or(KutValue::Nil)
+    }
+
+    /// Limits how many frames run at once, counting the one the host runs. Every frame
+    /// runs on the native stack, so this bounds how deep it grows. The default of 200
+    /// fits a 2 MiB thread stack even in debug builds, hosts that run scripts on a
+    /// larger stack can raise it.
+    pub fn set_max_call_depth(&self, depth: usize) {
+        self.max_call_depth.set(depth);
+    }
+
+    pub fn max_call_depth(&self) -> usize {
+        self.max_call_depth.get()
+    }
+
+    /// The number of frames running now, counting the one the host runs.
+    pub fn call_depth(&self) -> usize {
+        self.call_depth.get()
+    }
+
+    /// Runs `frame` as one more frame on the native stack.
+    pub(crate) fn enter_frame<T>(&self, frame: impl FnOnce() -> T) -> T {
+        let depth = self.call_depth.get();
+        self.call_depth.set(depth + 1);
+        let result = frame();
+        self.call_depth.set(depth);
+        result
+    }
+
+    /// Fails with `KutError::CallDepthExceeded` when starting one more frame would run
+    /// more than `max_call_depth` frames at once.
+    pub(crate) fn check_call_depth(&self) -> Result<(), KutError> {
+        if self.call_depth.get() >= self.max_call_depth.get() {
+            return Err(KutError::CallDepthExceeded { depth: self.max_call_depth.get() });
+        }
+        Ok(())
+    }
+
+    /// Fails with `KutError::Interrupted` once for every trigger of an interrupt handle.
+    pub fn check_interrupt(&self) -> Result<(), KutError> {
+        if self.interrupt.load(Ordering::Relaxed) && self.interrupt.swap(false, Ordering::Relaxed) {
+            Err(KutError::Interrupted)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn set_global(&self, name: &str, value: impl IntoKut<'template>) {
+        self.globals.borrow_mut().set_by_name(name, value.into_kut());
+    }
+
+    /// Reads the global `name` converted to `T`, failing with `KutError::UndefinedGlobal`
+    /// if it was never set.
+    pub fn get_global<T: FromKut<'template>>(&self, name: &str) -> Result<T, KutError> {
+        let value = self.globals.borrow().get_by_name(name).cloned();
+        T::from_kut(value.ok_or_else(|| KutError::UndefinedGlobal { name: name.to_owned() })?)
+    }
+
+    /// Calls the function named `name` with `args` and converts its result to `R`. A
+    /// global holding a `Func` or `Native` is looked up first, then a template with that
+    /// name, which must not need any captures. A failed call is abandoned, use
+    /// `start_call` to resume it, and so is a pending native, see `call_async`.
+    pub fn call<R: FromKut<'template>>(&'template self, name: &str, args: impl KutArguments<'template>) -> Result<R, KutError> {
+        self.start_call(name, args)?.resume()
+    }
+
+    /// Prepares a call like `call` without running it. The call runs when the returned
+    /// handle is resumed, and can be resumed again after it failed.
+    pub fn start_call<R: FromKut<'template>>(&'template self, name: &str, args: impl KutArguments<'template>) -> Result<KutCallHandle<'template, R>, KutError> {
+        KutCallHandle::new(self, self.function(name)?, args.into_arguments())
+    }
+
+    /// Calls the function named `name` like `call` and returns every value it returned,
+    /// see `RetfMultiRg`. A function that returns nothing gives an empty list.
+    pub fn call_multiple(&'template self, name: &str, args: impl KutArguments<'template>) -> Result<Vec<KutValue<'template>>, KutError> {
+        match self.function(name)? {
+            KutCallee::Native(native) => Ok(vec![native.call(self, args.into_arguments())?]),
+            KutCallee::Func(closure) => {
+                let mut frame = closure.start_with(self, args.into_arguments())?;
+                let value = frame.run(self)?;
+                Ok(frame.take_results(value))
+            },
+        }
+    }
+
+    /// Starts a call like `call` that can wait for the futures of pending natives. The
+    /// script runs when the returned future is polled.
+    pub fn call_async<R: FromKut<'template>>(&'template self, name: &str, args: impl KutArguments<'template>) -> Result<KutExecution<'template, R>, KutError> {
+        match self.function(name)? {
+            KutCallee::Native(native) => match native.call(self, args.into_arguments()) {
+                Err(KutError::Pending) => Ok(KutExecution::new(self, None, self.take_pending(), None)),
+                result => Ok(KutExecution::new(self, None, None, Some(result?))),
+            },
+            KutCallee::Func(closure) => Ok(KutExecution::new(self, Some(closure.start_with(self, args.into_arguments())?), None, None)),
+        }
+    }
+
+    /// Finds the `Func` or `Native` that `call` runs for `name`.
+    fn function(&'template self, name: &str) -> Result<KutCallee<'template>, KutError> {
+        if let Some(callee) = self.globals.borrow().get_by_name(name).and_then(KutValue::callee) {
+            return Ok(callee);
+        }
+        match self.templates.iter().find(|template| template.name.as_deref() == Some(name)) {
+            Some(template) => {
+                let closure = Rc::new(template.capture(None)?);
+                self.memory.track(&KutValue::Func(Rc::clone(&closure)))?;
+                Ok(KutCallee::Func(closure))
+            },
+            None => Err(KutError::UndefinedFunction { name: name.to_owned() }),
+        }
+    }
+
+    /// Suspends the running script until `future` completes, for natives that would
+    /// otherwise block. The native returns the result of this call, and the value of
+    /// `future` becomes its result when the host resumes the script, either through
+    /// `call_async` or by calling `KutFunction::resume_with` with the value built by
+    /// `take_pending` and running the frame again.
+    pub fn pending<T>(&self, future: impl Future<Output = Result<T, KutError>> + 'static) -> Result<KutValue<'template>, KutError>
+    where
+        T: for<'t> IntoKut<'t> + 'static,
+    {
+        self.pending.replace(Some(KutPending::new(future)));
+        Err(KutError::Pending)
+    }
+
+    /// Takes the future of the native that failed the script with `KutError::Pending`.
+    pub fn take_pending(&self) -> Option<KutPending> {
+        self.pending.take()
+    }
+
+    /// Replaces the loader that `load_module` and `add_module` resolve imports with.
+    pub fn set_loader(&mut self, loader: Box<dyn KutModuleLoader>) -> Box<dyn KutModuleLoader> {
+        std::mem::replace(&mut self.loader, loader)
+    }
+
+    /// Links `module` and, first, the modules it imports that are not linked yet.
+    pub fn add_module(&mut self, module: KutModule<'template>) -> Result<usize, KutError> {
+        if self.module_indices.contains_key(&module.name) {
+            return Err(KutError::InvalidModule { name: module.name, message: "a module with the same name is already linked".to_owned() });
+        }
+        let mut loading = vec![module.name.clone()];
+        self.link_module(module, &mut loading)
+    }
+
+    /// Links the module `name` through the loader, unless it is already linked.
+    pub fn load_module(&mut self, name: &str) -> Result<usize, KutError> {
+        self.resolve_module(name, &mut vec![])
+    }
+
+    /// Registers a module of host values, such as native functions, that scripts can
+    /// import like any other module.
+    pub fn add_native_module(&mut self, name: &str, exports: Vec<(&str, KutValue<'template>)>) -> Result<usize, KutError> {
+        if self.module_indices.contains_key(name) {
+            return Err(KutError::InvalidModule { name: name.to_owned(), message: "a module with the same name is already linked".to_owned() });
+        }
+        self.modules.push(KutLinkedModule::native(name, exports));
+        self.module_indices.insert(name.to_owned(), self.modules.len() - 1);
+        Ok(self.modules.len() - 1)
+    }
+
+    /// Makes module `name` unavailable to later imports, e.g. to drop `math` from a
+    /// sandbox. Functions that were already imported keep working.
+    pub fn remove_module(&mut self, name: &str) -> bool {
+        self.module_indices.remove(name).is_some()
+    }
+
+    pub fn module(&self, name: &str) -> Option<&KutLinkedModule<'template>> {
+        self.module_indices.get(name).map(|index| &self.modules[*index])
+    }
+
+    fn resolve_module(&mut self, name: &str, loading: &mut Vec<String>) -> Result<usize, KutError> {
+        if let Some(index) = self.module_indices.get(name) {
+            return Ok(*index);
+        }
+        if let Some(position) = loading.iter().position(|loading| loading == name) {
+            let mut chain = loading.split_off(position);
+            chain.push(name.to_owned());
+            return Err(KutError::CircularImport { chain });
+        }
+        let module = self.loader.load(name)?.rebind()?;
+        loading.push(name.to_owned());
+        let index = self.link_module(module, loading)?;
+        loading.pop();
+        Ok(index)
+    }
+
+    fn link_module(&mut self, module: KutModule<'template>, loading: &mut Vec<String>) -> Result<usize, KutError> {
+        for import in module.imports.iter() {
+            self.resolve_module(import, loading)?;
+        }
+        let index = self.modules.len();
+        let literals = self.literals.len()..self.literals.len() + module.literals.len();
+        let templates = self.templates.len()..self.templates.len() + module.templates.len();
+        let mut exports = vec![];
+        for (name, template) in module.exports {
+            if template as usize >= templates.len() {
+                return Err(KutError::InvalidModule { name: module.name, message: format!("export {name} names template {template} of {}", templates.len()) });
+            }
+            exports.push((name, templates.start + template as usize));
+        }
+        let entry = match module.entry {
+            Some(entry) if entry as usize >= templates.len() => {
+                return Err(KutError::InvalidModule { name: module.name, message: format!("entry names template {entry} of {}", templates.len()) });
+            },
+            entry => entry.map(|entry| templates.start + entry as usize),
+        };
+        self.literals.extend(module.literals);
+        self.literal_slots.resize(self.literals.len(), Cell::new(None));
+        self.templates.extend(module.templates.into_iter().map(|template| KutFunctionTemplate { module: index, ..template }));
+        self.module_indices.insert(module.name.clone(), index);
+        self.modules.push(KutLinkedModule::new(&module.name, literals, templates, exports, entry, OnceCell::new()));
+        Ok(index)
+    }
+
+    /// Returns the exports of the linked module `name` as a map from export name to
+    /// function, building it on the first import only. The entry of the module runs
+    /// to completion first on the first import.
+    pub fn module_exports(&'template self, name: &str) -> Result<KutValue<'template>, KutError> {
+        if let Some(mut entry) = self.module_entry(name)? {
+            entry.run(self)?;
+        }
+        let module = self.module(name).ok_or_else(|| KutError::UnlinkedModule { name: name.to_owned() })?;
+        if let Some(value) = module.value.get() {
+            return Ok(value.clone());
+        }
+        let mut map = KutMap::new();
+        for (name, template) in module.exports.iter() {
+            let key = name.as_str().into_kut();
+            let closure = KutValue::Func(Rc::new(self.templates[*template].capture(None)?));
+            self.memory.track(&key)?;
+            self.memory.track(&closure)?;
+            map.insert(KutKey(key), closure);
+        }
+        let value = KutValue::Map(Rc::new(map));
+        self.memory.track(&value)?;
+        Ok(module.value.get_or_init(|| value).clone())
+    }
+
+    /// Starts the entry of the linked module `name` on its first import. Returns `None`
+    /// when the module has no entry or it was already started, so that the entry runs
+    /// once even when it fails.
+    pub(crate) fn module_entry(&'template self, name: &str) -> Result<Option<KutFunction<'template>>, KutError> {
+        let module = self.module(name).ok_or_else(|| KutError::UnlinkedModule { name: name.to_owned() })?;
+        let Some(entry) = module.entry.filter(|_| !module.entered.get()) else {
+            return Ok(None);
+        };
+        self.check_call_depth()?;
+        let frame = Rc::new(self.templates[entry].capture(None)?).start_with(self, vec![])?;
+        module.entered.set(true);
+        Ok(Some(frame))
+    }
+
+    /// Looks up literal `literal` of the module that `template` belongs to.
+    pub fn literal(&self, template: &KutFunctionTemplate, literal: u16) -> Result<&KutValue<'template>, KutError> {
+        match self.literal_index(template, literal) {
+            Some(index) => Ok(&self.literals[index]),
+            None => Err(KutError::OutOfRangeLiteral { literal, literal_count: self.modules[template.module].literals.len() }),
+        }
+    }
+
+    /// The index in `literals` of literal `literal` of the module of `template`.
+    pub(crate) fn literal_index(&self, template: &KutFunctionTemplate, literal: u16) -> Option<usize> {
+        let literals = &self.modules[template.module].literals;
+        literals.start.checked_add(literal as usize).filter(|index| *index < literals.end)
+    }
+
+    /// Looks up template `index` of the module that `template` belongs to.
+    pub fn template(&self, template: &KutFunctionTemplate, index: u16) -> Result<&KutFunctionTemplate, KutError> {
+        let templates = &self.modules[template.module].templates;
+        match templates.start.checked_add(index as usize) {
+            Some(index) if index < templates.end => Ok(&self.templates[index]),
+            _ => Err(KutError::OutOfRangeTemplate { template: index, template_count: templates.len() }),
+        }
+    }
+
+    /// Interns the name held by string literal `literal` of the module of `template` as
+    /// a global slot, remembering the slot for the next instruction that names it.
+    pub fn global_slot(&self, template: &KutFunctionTemplate, literal: u16) -> Result<usize, KutError> {
+        let name = self.literal(template, literal)?;
+        let cache = &self.literal_slots[self.modules[template.module].literals.start + literal as usize];
+        if let Some(slot) = cache.get() {
+            return Ok(slot);
+        }
+        let slot = match name {
+            KutValue::String(name) => self.globals.borrow_mut().intern(name),
+            other => return Err(KutError::InvalidOperand { operation: "global name".to_owned(), value_type: other.get_type_string() }),
+        };
+        cache.set(Some(slot));
+        Ok(slot)
+    }
+
+    /// Limits execution to `fuel` units, or removes the limit with `None`.
+    pub fn set_fuel(&self, fuel: Option<u64>) {
+        self.fuel.set(fuel);
+    }
+
+    /// Adds fuel to a limited VM, typically before resuming after `KutError::OutOfFuel`.
+    pub fn add_fuel(&self, fuel: u64) {
+        if let Some(remaining) = self.fuel.get() {
+            self.fuel.set(Some(remaining.saturating_add(fuel)));
+        }
+    }
+
+    pub fn remaining_fuel(&self) -> Option<u64> {
+        self.fuel.get()
+    }
+
+    pub fn fuel_consumed(&self) -> u64 {
+        self.fuel_consumed.get()
+    }
+
+    pub fn reset_fuel_consumed(&self) {
+        self.fuel_consumed.set(0);
+    }
+
+    /// Charges the cost of `instruction`, leaving the fuel untouched when there is not enough.
+    pub fn consume_fuel(&self, instruction: &KutInstruction) -> Result<(), KutError> {
+        let cost = instruction.fuel_cost(&self.fuel_costs);
+        if let Some(remaining) = self.fuel.get() {
+            if remaining < cost {
+                return Err(KutError::OutOfFuel { needed: cost, remaining });
+            }
+            self.fuel.set(Some(remaining - cost));
+        }
+        self.fuel_consumed.set(self.fuel_consumed.get() + cost);
+        Ok(())
     }
 }